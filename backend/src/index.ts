@@ -9,6 +9,7 @@ import rateLimit from 'express-rate-limit'
 
 import { connectDatabases } from './utils/database'
 import { setupWebSocket } from './utils/websocket'
+import { connectToOrderEngine } from './utils/orderEngine'
 import { errorHandler } from './middleware/errorHandler'
 import { authMiddleware } from './middleware/auth'
 
@@ -76,6 +77,9 @@ async function startServer() {
     // Setup WebSocket handlers
     setupWebSocket(io)
 
+    // Market data and orders go through the matching engine; it keeps retrying until the engine is up
+    connectToOrderEngine().catch((error) => console.error('Order engine not reachable yet:', error.message))
+
     server.listen(PORT, () => {
       console.log(`🚀 Server running on port ${PORT}`)
       console.log(`📡 WebSocket server ready`)
@@ -2,7 +2,7 @@ import express from 'express'
 import { body, validationResult } from 'express-validator'
 import { createOrder, getOrderBook, getUserOrders } from '../models/Order'
 import { updateUserBalance } from '../models/User'
-import { sendToOrderEngine } from '../utils/orderEngine'
+import { getTickersFromEngine, sendToOrderEngine } from '../utils/orderEngine'
 
 const router = express.Router()
 
@@ -89,13 +89,22 @@ router.delete('/order/:orderId', async (req, res) => {
 // Get market data
 router.get('/markets', async (req, res) => {
   try {
-    // Mock market data - in production, this would come from real price feeds
-    const markets = [
-      { symbol: 'BTC/USDT', price: 65000, change24h: 2.5, volume: 1200000000 },
-      { symbol: 'ETH/USDT', price: 3200, change24h: -1.8, volume: 800000000 },
-      { symbol: 'SOL/USDT', price: 145, change24h: 5.2, volume: 300000000 },
-      { symbol: 'ADA/USDT', price: 0.58, change24h: 1.9, volume: 150000000 },
-    ]
+    // Rolling 24h statistics from the order engine's trades; volume is in the quote asset
+    const tickers = await getTickersFromEngine()
+    const markets = tickers.map((ticker) => ({
+      symbol: ticker.pair,
+      price: ticker.lastPrice,
+      change24h: ticker.priceChangePercent ?? 0,
+      volume: ticker.quoteVolume,
+      baseVolume: ticker.volume,
+      open24h: ticker.open,
+      high24h: ticker.high,
+      low24h: ticker.low,
+      weightedAvgPrice: ticker.weightedAvgPrice,
+      trades24h: ticker.trades,
+      bestBid: ticker.bestBid,
+      bestAsk: ticker.bestAsk,
+    }))
     res.json(markets)
   } catch (error) {
     console.error('Get markets error:', error)
@@ -3,6 +3,7 @@ import { body, validationResult } from 'express-validator'
 import { getUserById, updateUserBalance } from '../models/User'
 import { processEthereumDeposit, processEthereumWithdrawal } from '../utils/ethereum'
 import { processSolanaDeposit, processSolanaWithdrawal } from '../utils/solana'
+import { depositToEngine, withdrawFromEngine } from '../utils/orderEngine'
 
 const router = express.Router()
 
@@ -46,6 +47,9 @@ router.post('/deposit', [
       txHash = await processSolanaDeposit(userId, token, amount)
     }
 
+    // Make the funds tradable
+    await depositToEngine(userId, token, amount)
+
     // Update user balance
     const user = await getUserById(userId)
     if (user) {
@@ -86,6 +90,9 @@ router.post('/withdraw', [
       return res.status(400).json({ message: 'Insufficient balance' })
     }
 
+    // Take the funds out of the engine first so they cannot back orders while they are paid out
+    await withdrawFromEngine(userId, token, amount)
+
     let txHash: string
 
     if (network === 'ethereum') {
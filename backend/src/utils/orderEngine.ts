@@ -1,13 +1,59 @@
 import WebSocket from 'ws'
+import jwt from 'jsonwebtoken'
 import { Order } from '../models/Order'
 
 interface OrderEngineMessage {
-  type: 'new_order' | 'cancel_order' | 'order_update'
+  type: 'authenticate' | 'new_order' | 'cancel_order' | 'order_update' | 'get_tickers' | 'deposit' | 'withdraw'
   data: any
 }
 
+// Rolling 24h statistics and top of book for one pair, as sent by the engine
+export interface EngineTicker {
+  pair: string
+  lastPrice: number | null
+  bestBid: number | null
+  bestBidSize: number
+  bestAsk: number | null
+  bestAskSize: number
+  open: number | null
+  high: number | null
+  low: number | null
+  volume: number
+  quoteVolume: number
+  priceChangePercent: number | null
+  weightedAvgPrice: number | null
+  trades: number
+  timestamp: number
+}
+
+// One asset of a user's engine account, as sent after their funds move
+export interface EngineBalance {
+  asset: string
+  available: number
+  locked: number
+}
+
+interface PendingTransfer {
+  userId: string
+  resolve: (balances: EngineBalance[]) => void
+  reject: (error: Error) => void
+}
+
+const ENGINE_REQUEST_TIMEOUT_MS = 5000
+
+// The engine trusts this connection to act for every user once it proves it is the backend
+function serviceToken(): string {
+  return jwt.sign({ role: 'service' }, process.env.JWT_SECRET!, { expiresIn: '5m' })
+}
+
 let engineSocket: WebSocket | null = null
 
+// Callers waiting for a tickers reply; the engine answers requests in the order they were sent
+const pendingTickerRequests: Array<(tickers: EngineTicker[]) => void> = []
+
+// Deposits and withdrawals waiting for the engine to confirm them, oldest first
+const pendingTransfers: PendingTransfer[] = []
+
 export async function connectToOrderEngine(): Promise<void> {
   const engineUrl = `ws://${process.env.ENGINE_HOST || '127.0.0.1'}:${process.env.ENGINE_PORT || '9090'}`
 
@@ -21,6 +67,11 @@ export async function connectToOrderEngine(): Promise<void> {
 
     engineSocket.on('open', () => {
       console.log('✅ Connected to Rust order matching engine')
+      const message: OrderEngineMessage = {
+        type: 'authenticate',
+        data: { token: serviceToken() }
+      }
+      engineSocket?.send(JSON.stringify(message))
       resolve()
     })
 
@@ -41,7 +92,7 @@ export async function connectToOrderEngine(): Promise<void> {
     engineSocket.on('close', () => {
       console.log('Order engine connection closed')
       // Attempt to reconnect after 5 seconds
-      setTimeout(connectToOrderEngine, 5000)
+      setTimeout(() => connectToOrderEngine().catch((error) => console.error('Order engine reconnect failed:', error.message)), 5000)
     })
   })
 }
@@ -81,6 +132,88 @@ export async function cancelOrderInEngine(orderId: string): Promise<void> {
   engineSocket.send(JSON.stringify(message))
 }
 
+export async function getTickersFromEngine(): Promise<EngineTicker[]> {
+  const socket = engineSocket
+  if (!socket || socket.readyState !== WebSocket.OPEN) {
+    throw new Error('Order engine not connected')
+  }
+
+  const message: OrderEngineMessage = {
+    type: 'get_tickers',
+    data: {}
+  }
+
+  return new Promise((resolve, reject) => {
+    const onReply = (tickers: EngineTicker[]) => {
+      clearTimeout(timeout)
+      resolve(tickers)
+    }
+    const timeout = setTimeout(() => {
+      const index = pendingTickerRequests.indexOf(onReply)
+      if (index !== -1) {
+        pendingTickerRequests.splice(index, 1)
+      }
+      reject(new Error('Order engine did not return tickers in time'))
+    }, ENGINE_REQUEST_TIMEOUT_MS)
+
+    pendingTickerRequests.push(onReply)
+    socket.send(JSON.stringify(message))
+  })
+}
+
+// Credits funds custody has received to the user's engine account
+export async function depositToEngine(userId: string, asset: string, amount: number | string): Promise<EngineBalance[]> {
+  return transferInEngine('deposit', userId, asset, amount)
+}
+
+// Debits funds from the user's engine account before custody pays them out. Fails if they are
+// not available, for instance because open orders have them locked.
+export async function withdrawFromEngine(userId: string, asset: string, amount: number | string): Promise<EngineBalance[]> {
+  return transferInEngine('withdraw', userId, asset, amount)
+}
+
+async function transferInEngine(
+  type: 'deposit' | 'withdraw',
+  userId: string,
+  asset: string,
+  amount: number | string
+): Promise<EngineBalance[]> {
+  const socket = engineSocket
+  if (!socket || socket.readyState !== WebSocket.OPEN) {
+    throw new Error('Order engine not connected')
+  }
+
+  const message: OrderEngineMessage = {
+    type,
+    data: { userId, asset, amount: String(amount) }
+  }
+
+  return new Promise((resolve, reject) => {
+    const settle = () => {
+      clearTimeout(timeout)
+      const index = pendingTransfers.indexOf(pending)
+      if (index !== -1) {
+        pendingTransfers.splice(index, 1)
+      }
+    }
+    const pending: PendingTransfer = {
+      userId,
+      resolve: (balances) => {
+        settle()
+        resolve(balances)
+      },
+      reject: (error) => {
+        settle()
+        reject(error)
+      }
+    }
+    const timeout = setTimeout(() => pending.reject(new Error(`Order engine did not confirm the ${type} in time`)), ENGINE_REQUEST_TIMEOUT_MS)
+
+    pendingTransfers.push(pending)
+    socket.send(JSON.stringify(message))
+  })
+}
+
 function handleEngineMessage(message: any) {
   switch (message.type) {
     case 'order_filled':
@@ -92,6 +225,24 @@ function handleEngineMessage(message: any) {
     case 'order_cancelled':
       handleOrderCancelled(message.data)
       break
+    case 'order_rejected':
+      handleOrderRejected(message.data)
+      break
+    case 'tickers':
+      pendingTickerRequests.shift()?.(message.data.tickers)
+      break
+    case 'balances':
+      pendingTransfers.find((pending) => pending.userId === message.data.userId)?.resolve(message.data.balances)
+      break
+    case 'authenticated':
+      console.log('Authenticated with the order engine as a service')
+      break
+    case 'error':
+      console.error('Order engine error:', message.message)
+      if (message.message?.startsWith('Failed to transfer')) {
+        pendingTransfers[0]?.reject(new Error(message.message))
+      }
+      break
     default:
       console.log('Unknown engine message type:', message.type)
   }
@@ -135,5 +286,17 @@ async function handleOrderCancelled(data: any) {
   }
 }
 
+async function handleOrderRejected(data: any) {
+  const { orderId, reason } = data
+
+  try {
+    // Update order status to rejected
+    // Release reserved balances
+    console.log(`Order ${orderId} rejected: ${reason}`)
+  } catch (error) {
+    console.error('Error handling order rejection:', error)
+  }
+}
+
 // Initialize connection when the module is loaded
 // connectToOrderEngine().catch(console.error)
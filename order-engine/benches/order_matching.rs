@@ -0,0 +1,51 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use order_engine::order::{Order, OrderKind, OrderType};
+use order_engine::orderbook::OrderBook;
+use rust_decimal::Decimal;
+use uuid::Uuid;
+
+fn limit_order(order_type: OrderType, amount: i64, price: i64) -> Order {
+    Order::new(
+        Uuid::new_v4(),
+        "BTC/USDT".to_string(),
+        order_type,
+        OrderKind::Limit,
+        Decimal::from(amount),
+        Some(Decimal::from(price)),
+    )
+}
+
+fn seeded_book(levels: i64, orders_per_level: usize) -> OrderBook {
+    let mut book = OrderBook::new("BTC/USDT".to_string());
+    for level in 0..levels {
+        for _ in 0..orders_per_level {
+            book.add_order(limit_order(OrderType::Sell, 1, 50_000 + level));
+            book.add_order(limit_order(OrderType::Buy, 1, 49_999 - level));
+        }
+    }
+    book
+}
+
+fn bench_add_resting(c: &mut Criterion) {
+    let mut book = OrderBook::new("BTC/USDT".to_string());
+    let mut price = 0;
+    c.bench_function("add resting limit order", |b| {
+        b.iter(|| {
+            price = (price + 1) % 1_000;
+            book.add_order(black_box(limit_order(OrderType::Buy, 1, 40_000 + price)))
+        })
+    });
+}
+
+fn bench_sweep(c: &mut Criterion) {
+    c.bench_function("sweep 10 levels", |b| {
+        b.iter_batched(
+            || seeded_book(100, 10),
+            |mut book| book.add_order(black_box(limit_order(OrderType::Buy, 100, 50_009))),
+            criterion::BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_add_resting, bench_sweep);
+criterion_main!(benches);
@@ -0,0 +1,359 @@
+use crate::engine::{EngineError, EngineResult};
+use crate::fees::FEE_ACCOUNT_ID;
+use crate::market::MarketSpec;
+use crate::order::{OrderType, SelfTradePrevention, Trade};
+use dashmap::DashMap;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use uuid::Uuid;
+
+/// One asset held in one account.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub struct Balance {
+    /// Free to trade or withdraw.
+    #[serde(with = "rust_decimal::serde::str")]
+    pub available: Decimal,
+    /// Held for live orders until they trade, are cancelled or expire.
+    #[serde(with = "rust_decimal::serde::str")]
+    pub locked: Decimal,
+}
+
+impl Balance {
+    pub fn total(&self) -> Decimal {
+        self.available + self.locked
+    }
+}
+
+/// Part of a balance a posting moves.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BalanceKind {
+    Available,
+    Locked,
+}
+
+/// One leg of a trade's settlement: `amount` of `asset` in or (when negative) out of an account.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Posting {
+    pub account_id: Uuid,
+    pub asset: String,
+    pub amount: Decimal,
+    pub balance: BalanceKind,
+}
+
+impl Posting {
+    fn new(account_id: Uuid, asset: &str, amount: Decimal, balance: BalanceKind) -> Self {
+        Self {
+            account_id,
+            asset: asset.to_string(),
+            amount,
+            balance,
+        }
+    }
+
+    /// The double-entry postings that settle `trade`, which net to zero in every asset.
+    ///
+    /// The buyer pays quote and the seller pays base out of what their orders have locked; each
+    /// then pays its fee to [`FEE_ACCOUNT_ID`] out of what it received.
+    pub fn for_trade(trade: &Trade, spec: &MarketSpec) -> Vec<Posting> {
+        let cost = trade.amount * trade.price;
+        let mut postings = vec![
+            Posting::new(trade.buyer_id, &spec.quote_asset, -cost, BalanceKind::Locked),
+            Posting::new(trade.seller_id, &spec.quote_asset, cost, BalanceKind::Available),
+            Posting::new(trade.seller_id, &spec.base_asset, -trade.amount, BalanceKind::Locked),
+            Posting::new(trade.buyer_id, &spec.base_asset, trade.amount, BalanceKind::Available),
+        ];
+
+        let (maker_id, taker_id) = match trade.maker_side {
+            OrderType::Buy => (trade.buyer_id, trade.seller_id),
+            OrderType::Sell => (trade.seller_id, trade.buyer_id),
+        };
+        for (account_id, fee, asset) in [
+            (maker_id, trade.maker_fee, &trade.maker_fee_asset),
+            (taker_id, trade.taker_fee, &trade.taker_fee_asset),
+        ] {
+            if !fee.is_zero() {
+                postings.push(Posting::new(account_id, asset, -fee, BalanceKind::Available));
+                postings.push(Posting::new(FEE_ACCOUNT_ID, asset, fee, BalanceKind::Available));
+            }
+        }
+        postings
+    }
+}
+
+/// Every account's balances, as written to a snapshot.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AccountsState {
+    /// Journal sequence of the last deposit, withdrawal or account setting change applied, 0 if none.
+    pub last_input_seq: u64,
+    pub balances: BTreeMap<Uuid, BTreeMap<String, Balance>>,
+    /// Accounts not in tier 0.
+    #[serde(default)]
+    pub fee_tiers: BTreeMap<Uuid, u8>,
+    /// Accounts with a self-trade prevention mode of their own.
+    #[serde(default)]
+    pub self_trade_modes: BTreeMap<Uuid, SelfTradePrevention>,
+}
+
+/// Available and locked balances per user and asset, and each user's fee tier and self-trade
+/// prevention mode.
+///
+/// Shared by every shard, since one asset (the quote currency, say) backs orders on many pairs.
+/// Each call updates a single account atomically, so two shards can never both spend the same
+/// available funds.
+#[derive(Debug, Default)]
+pub struct Accounts {
+    balances: DashMap<Uuid, HashMap<String, Balance>>,
+    fee_tiers: DashMap<Uuid, u8>,
+    self_trade_modes: DashMap<Uuid, SelfTradePrevention>,
+    /// Journal sequence of the last deposit, withdrawal or account setting change applied, 0 if none.
+    last_input_seq: AtomicU64,
+}
+
+impl Accounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn balance(&self, user_id: Uuid, asset: &str) -> Balance {
+        self.balances
+            .get(&user_id)
+            .and_then(|assets| assets.get(asset).copied())
+            .unwrap_or_default()
+    }
+
+    /// Every asset the user has ever held.
+    pub fn balances(&self, user_id: Uuid) -> HashMap<String, Balance> {
+        self.balances.get(&user_id).map(|assets| assets.clone()).unwrap_or_default()
+    }
+
+    pub fn deposit(&self, user_id: Uuid, asset: &str, amount: Decimal) -> Balance {
+        self.update(user_id, asset, |balance| balance.available += amount)
+    }
+
+    /// Takes `amount` out of the available balance, failing if there is not that much.
+    pub fn withdraw(&self, user_id: Uuid, asset: &str, amount: Decimal) -> EngineResult<Balance> {
+        self.debit(user_id, asset, amount, false)
+    }
+
+    /// Moves `amount` from available to locked, failing if there is not that much available.
+    pub fn lock(&self, user_id: Uuid, asset: &str, amount: Decimal) -> EngineResult<Balance> {
+        self.debit(user_id, asset, amount, true)
+    }
+
+    /// Moves `amount` from locked back to available.
+    pub fn unlock(&self, user_id: Uuid, asset: &str, amount: Decimal) -> Balance {
+        self.update(user_id, asset, |balance| {
+            balance.locked -= amount;
+            balance.available += amount;
+        })
+    }
+
+    /// Applies each posting to its account. Settlement postings are not checked against the
+    /// balance: the funds they take were locked for the order, or just received by it.
+    pub fn post(&self, postings: &[Posting]) {
+        for posting in postings {
+            self.update(posting.account_id, &posting.asset, |balance| match posting.balance {
+                BalanceKind::Available => balance.available += posting.amount,
+                BalanceKind::Locked => balance.locked += posting.amount,
+            });
+        }
+    }
+
+    /// Same as [`Accounts::withdraw`], for a replayed withdrawal that passed the funds check
+    /// when it was first accepted.
+    pub(crate) fn withdraw_replayed(&self, user_id: Uuid, asset: &str, amount: Decimal) -> Balance {
+        self.update(user_id, asset, |balance| balance.available -= amount)
+    }
+
+    /// Same as [`Accounts::lock`], for a replayed order that passed the funds check when it was
+    /// first accepted. Shards replay side by side, so funds another pair's trades provided may
+    /// not be back yet.
+    pub(crate) fn lock_replayed(&self, user_id: Uuid, asset: &str, amount: Decimal) -> Balance {
+        self.update(user_id, asset, |balance| {
+            balance.available -= amount;
+            balance.locked += amount;
+        })
+    }
+
+    /// Tier the user's new orders pay fees at, 0 unless set.
+    pub fn fee_tier(&self, user_id: Uuid) -> u8 {
+        self.fee_tiers.get(&user_id).map_or(0, |tier| *tier)
+    }
+
+    /// Every user not in tier 0.
+    pub fn fee_tier_users(&self) -> Vec<Uuid> {
+        self.fee_tiers.iter().map(|entry| *entry.key()).collect()
+    }
+
+    pub fn set_fee_tier(&self, user_id: Uuid, tier: u8) {
+        if tier == 0 {
+            self.fee_tiers.remove(&user_id);
+        } else {
+            self.fee_tiers.insert(user_id, tier);
+        }
+    }
+
+    /// Mode the user's orders that do not set one use, if the user chose one.
+    pub fn self_trade_prevention(&self, user_id: Uuid) -> Option<SelfTradePrevention> {
+        self.self_trade_modes.get(&user_id).map(|mode| *mode)
+    }
+
+    pub fn set_self_trade_prevention(&self, user_id: Uuid, mode: SelfTradePrevention) {
+        self.self_trade_modes.insert(user_id, mode);
+    }
+
+    pub fn last_input_seq(&self) -> u64 {
+        self.last_input_seq.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_last_input_seq(&self, seq: u64) {
+        self.last_input_seq.store(seq, Ordering::SeqCst);
+    }
+
+    /// Copies out every balance.
+    pub fn state(&self) -> AccountsState {
+        AccountsState {
+            last_input_seq: self.last_input_seq(),
+            balances: self
+                .balances
+                .iter()
+                .map(|entry| (*entry.key(), entry.value().iter().map(|(asset, balance)| (asset.clone(), *balance)).collect()))
+                .collect(),
+            fee_tiers: self.fee_tiers.iter().map(|entry| (*entry.key(), *entry.value())).collect(),
+            self_trade_modes: self.self_trade_modes.iter().map(|entry| (*entry.key(), *entry.value())).collect(),
+        }
+    }
+
+    /// Replaces every balance with those in `state`.
+    pub fn restore_state(&self, state: AccountsState) {
+        self.balances.clear();
+        for (user_id, assets) in state.balances {
+            self.balances.insert(user_id, assets.into_iter().collect());
+        }
+        self.fee_tiers.clear();
+        for (user_id, tier) in state.fee_tiers {
+            self.fee_tiers.insert(user_id, tier);
+        }
+        self.self_trade_modes.clear();
+        for (user_id, mode) in state.self_trade_modes {
+            self.self_trade_modes.insert(user_id, mode);
+        }
+        self.set_last_input_seq(state.last_input_seq);
+    }
+
+    fn debit(&self, user_id: Uuid, asset: &str, amount: Decimal, into_locked: bool) -> EngineResult<Balance> {
+        let mut assets = self.balances.entry(user_id).or_default();
+        let balance = assets.entry(asset.to_string()).or_default();
+        if balance.available < amount {
+            return Err(EngineError::InsufficientFunds {
+                asset: asset.to_string(),
+                required: amount,
+                available: balance.available,
+            });
+        }
+
+        balance.available -= amount;
+        if into_locked {
+            balance.locked += amount;
+        }
+        Ok(*balance)
+    }
+
+    fn update(&self, user_id: Uuid, asset: &str, apply: impl FnOnce(&mut Balance)) -> Balance {
+        let mut assets = self.balances.entry(user_id).or_default();
+        let balance = assets.entry(asset.to_string()).or_default();
+        apply(balance);
+        *balance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fees::FeeRates;
+    use crate::order::{Order, OrderKind};
+    use chrono::Utc;
+
+    fn trade(spec: &MarketSpec, maker_side: OrderType, maker_tier: u8, taker_tier: u8) -> Trade {
+        let order = |order_type| {
+            Order::new(Uuid::new_v4(), spec.pair.clone(), order_type, OrderKind::Limit, Decimal::new(15, 1), Some(Decimal::new(10_001, 2)))
+        };
+        let mut trade = Trade::new(
+            &order(OrderType::Buy),
+            &order(OrderType::Sell),
+            Decimal::new(15, 1),
+            Decimal::new(10_001, 2),
+            maker_side,
+            Utc::now(),
+        );
+        spec.fee_schedule().charge(&mut trade, maker_tier, taker_tier);
+        trade
+    }
+
+    #[test]
+    fn trade_postings_net_to_zero_in_every_asset() {
+        let spec = MarketSpec::new("BTC", "USDT");
+        let rebate_tier = (FeeRates::default_tiers().len() - 1) as u8;
+
+        for maker_side in [OrderType::Buy, OrderType::Sell] {
+            for (maker_tier, taker_tier) in [(0, 0), (rebate_tier, 3)] {
+                let postings = Posting::for_trade(&trade(&spec, maker_side.clone(), maker_tier, taker_tier), &spec);
+
+                for asset in [&spec.base_asset, &spec.quote_asset] {
+                    let net: Decimal = postings.iter().filter(|p| &p.asset == asset).map(|p| p.amount).sum();
+                    assert_eq!(net, Decimal::ZERO, "{} does not net to zero", asset);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn posting_a_trade_keeps_total_holdings() {
+        let spec = MarketSpec::new("BTC", "USDT");
+        let trade = trade(&spec, OrderType::Sell, 0, 0);
+        let accounts = Accounts::new();
+        accounts.deposit(trade.buyer_id, &spec.quote_asset, Decimal::from(1_000));
+        accounts.lock(trade.buyer_id, &spec.quote_asset, trade.amount * trade.price).unwrap();
+        accounts.deposit(trade.seller_id, &spec.base_asset, Decimal::from(10));
+        accounts.lock(trade.seller_id, &spec.base_asset, trade.amount).unwrap();
+
+        accounts.post(&Posting::for_trade(&trade, &spec));
+
+        let total = |asset: &str| -> Decimal {
+            [trade.buyer_id, trade.seller_id, FEE_ACCOUNT_ID]
+                .into_iter()
+                .map(|account_id| accounts.balance(account_id, asset).total())
+                .sum()
+        };
+        assert_eq!(total(&spec.base_asset), Decimal::from(10));
+        assert_eq!(total(&spec.quote_asset), Decimal::from(1_000));
+        assert_eq!(accounts.balance(trade.buyer_id, &spec.quote_asset).locked, Decimal::ZERO);
+        assert_eq!(accounts.balance(trade.seller_id, &spec.base_asset).locked, Decimal::ZERO);
+    }
+
+    #[test]
+    fn rounded_fees_leave_no_dust_in_any_balance() {
+        let spec = MarketSpec { base_precision: 5, quote_precision: 3, ..MarketSpec::new("BTC", "USDT") };
+        let trade = trade(&spec, OrderType::Buy, 0, 0);
+        let accounts = Accounts::new();
+        accounts.deposit(trade.buyer_id, &spec.quote_asset, Decimal::from(1_000));
+        accounts.lock(trade.buyer_id, &spec.quote_asset, trade.amount * trade.price).unwrap();
+        accounts.deposit(trade.seller_id, &spec.base_asset, Decimal::from(10));
+        accounts.lock(trade.seller_id, &spec.base_asset, trade.amount).unwrap();
+
+        accounts.post(&Posting::for_trade(&trade, &spec));
+
+        // 1.5 * 0.001 = 0.0015 BTC and 1.5 * 100.01 * 0.001 = 0.150015 USDT, the latter rounded up
+        assert_eq!(accounts.balance(FEE_ACCOUNT_ID, &spec.base_asset).available, Decimal::new(15, 4));
+        assert_eq!(accounts.balance(FEE_ACCOUNT_ID, &spec.quote_asset).available, Decimal::new(151, 3));
+        for account_id in [trade.buyer_id, trade.seller_id, FEE_ACCOUNT_ID] {
+            for (asset, precision) in [(&spec.base_asset, spec.base_precision), (&spec.quote_asset, spec.quote_precision)] {
+                let balance = accounts.balance(account_id, asset).total();
+                assert!(balance.normalize().scale() <= precision, "{} {} has dust: {}", account_id, asset, balance);
+            }
+        }
+    }
+}
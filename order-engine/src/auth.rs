@@ -0,0 +1,152 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// Claim value that marks a token as issued to a backend service rather than a user.
+pub const SERVICE_ROLE: &str = "service";
+
+/// Who a connection has proven it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Identity {
+    /// A user, as named by the `userId` claim of a token the backend issued at login.
+    User(Uuid),
+    /// A trusted backend service, from a token whose `role` claim is [`SERVICE_ROLE`]. It acts on
+    /// behalf of every user: it moves funds, reads any account and may follow any user's orders.
+    Service,
+}
+
+impl Identity {
+    /// Whether this identity may see and act on `user_id`'s account and orders.
+    pub fn may_act_for(&self, user_id: Uuid) -> bool {
+        match self {
+            Identity::User(id) => *id == user_id,
+            Identity::Service => true,
+        }
+    }
+}
+
+/// Checks the HS256 JSON Web Tokens the backend signs with its `JWT_SECRET`.
+pub struct TokenVerifier {
+    secret: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Header {
+    alg: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    #[serde(rename = "userId")]
+    user_id: Option<String>,
+    role: Option<String>,
+    exp: Option<i64>,
+    nbf: Option<i64>,
+}
+
+impl TokenVerifier {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self { secret: secret.into() }
+    }
+
+    /// The identity `token` proves as of `now`. Fails unless the token is signed with the shared
+    /// secret, carries an expiry and is inside its validity window.
+    pub fn verify(&self, token: &str, now: DateTime<Utc>) -> Result<Identity, String> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err("Malformed token".to_string());
+        };
+
+        let alg = decode_part::<Header>(header)?.alg;
+        if alg != "HS256" {
+            return Err(format!("Unsupported token algorithm: {}", alg));
+        }
+
+        // The signature covers the header and payload exactly as sent
+        let signed = &token[..header.len() + 1 + payload.len()];
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| "Malformed token signature".to_string())?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).map_err(|e| e.to_string())?;
+        mac.update(signed.as_bytes());
+        mac.verify_slice(&signature).map_err(|_| "Invalid token signature".to_string())?;
+
+        let claims: Claims = decode_part(payload)?;
+        let now = now.timestamp();
+        match claims.exp {
+            Some(exp) if exp > now => {}
+            Some(_) => return Err("Token has expired".to_string()),
+            None => return Err("Token has no expiry".to_string()),
+        }
+        if claims.nbf.is_some_and(|nbf| nbf > now) {
+            return Err("Token is not valid yet".to_string());
+        }
+
+        match (claims.role.as_deref(), claims.user_id) {
+            (Some(SERVICE_ROLE), _) => Ok(Identity::Service),
+            (_, Some(user_id)) => Uuid::from_str(&user_id).map(Identity::User).map_err(|_| format!("Invalid userId claim: {}", user_id)),
+            (_, None) => Err("Token names no user".to_string()),
+        }
+    }
+}
+
+/// Decodes one base64url JSON part of a token.
+fn decode_part<T: for<'de> Deserialize<'de>>(part: &str) -> Result<T, String> {
+    let bytes = URL_SAFE_NO_PAD.decode(part).map_err(|_| "Malformed token".to_string())?;
+    serde_json::from_slice(&bytes).map_err(|e| format!("Malformed token: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use serde_json::json;
+
+    const SECRET: &str = "test-secret";
+
+    fn sign(claims: serde_json::Value, secret: &str) -> String {
+        let header = URL_SAFE_NO_PAD.encode(json!({ "alg": "HS256", "typ": "JWT" }).to_string());
+        let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("{}.{}", header, payload).as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{}.{}.{}", header, payload, signature)
+    }
+
+    fn expiry() -> i64 {
+        (Utc::now() + Duration::hours(1)).timestamp()
+    }
+
+    #[test]
+    fn accepts_user_and_service_tokens() {
+        let verifier = TokenVerifier::new(SECRET);
+        let user_id = Uuid::new_v4();
+
+        let token = sign(json!({ "userId": user_id.to_string(), "exp": expiry() }), SECRET);
+        assert_eq!(verifier.verify(&token, Utc::now()), Ok(Identity::User(user_id)));
+
+        let token = sign(json!({ "role": SERVICE_ROLE, "exp": expiry() }), SECRET);
+        assert_eq!(verifier.verify(&token, Utc::now()), Ok(Identity::Service));
+    }
+
+    #[test]
+    fn rejects_forged_expired_and_unbounded_tokens() {
+        let verifier = TokenVerifier::new(SECRET);
+        let user_id = Uuid::new_v4().to_string();
+
+        let forged = sign(json!({ "userId": user_id, "exp": expiry() }), "other-secret");
+        assert!(verifier.verify(&forged, Utc::now()).is_err());
+
+        let expired = sign(json!({ "userId": user_id, "exp": Utc::now().timestamp() - 1 }), SECRET);
+        assert!(verifier.verify(&expired, Utc::now()).is_err());
+
+        let unbounded = sign(json!({ "userId": user_id }), SECRET);
+        assert!(verifier.verify(&unbounded, Utc::now()).is_err());
+
+        assert!(verifier.verify(&user_id, Utc::now()).is_err());
+    }
+}
@@ -0,0 +1,548 @@
+use crate::order::Trade;
+use chrono::{DateTime, Duration, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::{info, warn};
+
+/// Closed candles kept in memory per pair and interval; older ones are only on disk.
+pub const CANDLE_HISTORY_LEN: usize = 1000;
+
+/// Width of a candle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum CandleInterval {
+    #[serde(rename = "1m")]
+    OneMinute,
+    #[serde(rename = "5m")]
+    FiveMinutes,
+    #[serde(rename = "15m")]
+    FifteenMinutes,
+    #[serde(rename = "1h")]
+    OneHour,
+    #[serde(rename = "4h")]
+    FourHours,
+    #[serde(rename = "1d")]
+    OneDay,
+}
+
+impl CandleInterval {
+    pub const ALL: [CandleInterval; 6] = [
+        CandleInterval::OneMinute,
+        CandleInterval::FiveMinutes,
+        CandleInterval::FifteenMinutes,
+        CandleInterval::OneHour,
+        CandleInterval::FourHours,
+        CandleInterval::OneDay,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CandleInterval::OneMinute => "1m",
+            CandleInterval::FiveMinutes => "5m",
+            CandleInterval::FifteenMinutes => "15m",
+            CandleInterval::OneHour => "1h",
+            CandleInterval::FourHours => "4h",
+            CandleInterval::OneDay => "1d",
+        }
+    }
+
+    pub fn duration(self) -> Duration {
+        match self {
+            CandleInterval::OneMinute => Duration::minutes(1),
+            CandleInterval::FiveMinutes => Duration::minutes(5),
+            CandleInterval::FifteenMinutes => Duration::minutes(15),
+            CandleInterval::OneHour => Duration::hours(1),
+            CandleInterval::FourHours => Duration::hours(4),
+            CandleInterval::OneDay => Duration::days(1),
+        }
+    }
+
+    /// Start of the candle `timestamp` falls in. Candles are aligned to the Unix epoch, so days
+    /// start at midnight UTC.
+    pub fn open_time(self, timestamp: DateTime<Utc>) -> DateTime<Utc> {
+        let width = self.duration().num_milliseconds();
+        let millis = timestamp.timestamp_millis();
+        DateTime::from_timestamp_millis(millis - millis.rem_euclid(width)).unwrap_or(timestamp)
+    }
+}
+
+impl fmt::Display for CandleInterval {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for CandleInterval {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CandleInterval::ALL
+            .into_iter()
+            .find(|interval| interval.as_str() == s)
+            .ok_or_else(|| format!("Invalid candle interval: {}", s))
+    }
+}
+
+/// Open, high, low, close and volume of a pair's trades over one interval.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Candle {
+    pub pair: String,
+    pub interval: CandleInterval,
+    pub open_time: DateTime<Utc>,
+    /// End of the interval, exclusive.
+    pub close_time: DateTime<Utc>,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub open: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub high: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub low: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub close: Decimal,
+    /// Base asset traded.
+    #[serde(with = "rust_decimal::serde::str")]
+    pub volume: Decimal,
+    /// Quote asset traded, the sum of price * amount.
+    #[serde(with = "rust_decimal::serde::str")]
+    pub quote_volume: Decimal,
+    pub trade_count: u64,
+    /// Set once the interval is over; the candle never changes after that.
+    pub closed: bool,
+}
+
+impl Candle {
+    fn open(trade: &Trade, interval: CandleInterval, open_time: DateTime<Utc>) -> Self {
+        Self {
+            pair: trade.pair.clone(),
+            interval,
+            open_time,
+            close_time: open_time + interval.duration(),
+            open: trade.price,
+            high: trade.price,
+            low: trade.price,
+            close: trade.price,
+            volume: trade.amount,
+            quote_volume: trade.amount * trade.price,
+            trade_count: 1,
+            closed: false,
+        }
+    }
+
+    /// Whether `other` is this candle, possibly at a different point in its interval.
+    fn is_same(&self, other: &Candle) -> bool {
+        self.pair == other.pair && self.interval == other.interval && self.open_time == other.open_time
+    }
+
+    fn add(&mut self, trade: &Trade) {
+        self.high = self.high.max(trade.price);
+        self.low = self.low.min(trade.price);
+        self.close = trade.price;
+        self.volume += trade.amount;
+        self.quote_volume += trade.amount * trade.price;
+        self.trade_count += 1;
+    }
+}
+
+/// One pair's candles at one interval.
+#[derive(Debug, Default)]
+struct Series {
+    open: Option<Candle>,
+    closed: VecDeque<Candle>, // Oldest first, at most CANDLE_HISTORY_LEN
+}
+
+impl Series {
+    /// Start of the newest closed candle; trades before its end are already counted.
+    fn last_closed(&self) -> Option<DateTime<Utc>> {
+        self.closed.back().map(|candle| candle.open_time)
+    }
+
+    /// Adds a closed candle unless one at or after its time is already closed, as happens when
+    /// inputs are replayed.
+    fn push_closed(&mut self, mut candle: Candle) -> Option<Candle> {
+        if self.last_closed().is_some_and(|last| candle.open_time <= last) {
+            return None;
+        }
+
+        candle.closed = true;
+        if self.closed.len() == CANDLE_HISTORY_LEN {
+            self.closed.pop_front();
+        }
+        self.closed.push_back(candle.clone());
+        Some(candle)
+    }
+}
+
+/// Builds candles at every interval from one shard's trades.
+///
+/// Empty intervals get no candle. A candle closes when a trade lands in a later interval or
+/// [`CandleAggregator::close_due`] is called after it ends, whichever comes first.
+#[derive(Debug, Default)]
+pub struct CandleAggregator {
+    series: HashMap<(String, CandleInterval), Series>,
+    unflushed: Vec<Candle>, // Closed candles not yet written to disk
+}
+
+impl CandleAggregator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Folds `trades` into their pair's candles and returns every candle they changed, as it now
+    /// stands, closed ones first.
+    pub fn record(&mut self, trades: &[Trade]) -> Vec<Candle> {
+        let mut closed = Vec::new();
+        let mut updated: Vec<Candle> = Vec::new();
+
+        for trade in trades {
+            for interval in CandleInterval::ALL {
+                let open_time = interval.open_time(trade.timestamp);
+                let series = self.series.entry((trade.pair.clone(), interval)).or_default();
+                if series.last_closed().is_some_and(|last| open_time <= last) {
+                    continue;
+                }
+
+                let finished = match &mut series.open {
+                    Some(candle) if candle.open_time == open_time => {
+                        candle.add(trade);
+                        None
+                    }
+                    // Never reopen an interval a later trade has already moved past
+                    Some(candle) if candle.open_time > open_time => continue,
+                    open => open.replace(Candle::open(trade, interval, open_time)),
+                };
+                if let Some(finished) = finished.and_then(|candle| series.push_closed(candle)) {
+                    updated.retain(|c| !c.is_same(&finished));
+                    closed.push(finished);
+                }
+
+                let candle = series.open.as_ref().expect("a trade leaves an open candle");
+                match updated.iter_mut().find(|c| c.is_same(candle)) {
+                    Some(previous) => *previous = candle.clone(),
+                    None => updated.push(candle.clone()),
+                }
+            }
+        }
+
+        self.unflushed.extend(closed.iter().cloned());
+        closed.extend(updated);
+        closed
+    }
+
+    /// Closes every open candle whose interval ended by `now` and returns them.
+    pub fn close_due(&mut self, now: DateTime<Utc>) -> Vec<Candle> {
+        let mut closed = Vec::new();
+        for series in self.series.values_mut() {
+            if series.open.as_ref().is_some_and(|candle| candle.close_time <= now) {
+                let finished = series.open.take().expect("checked above");
+                closed.extend(series.push_closed(finished));
+            }
+        }
+
+        closed.sort_by(|a, b| (&a.pair, a.interval).cmp(&(&b.pair, b.interval)));
+        self.unflushed.extend(closed.iter().cloned());
+        closed
+    }
+
+    /// Up to `limit` of the newest candles that open before `before`, the open one included,
+    /// oldest first. Only what is held in memory; see [`CandleStore::load`] for the rest.
+    pub fn history(&self, pair: &str, interval: CandleInterval, before: Option<DateTime<Utc>>, limit: usize) -> Vec<Candle> {
+        let Some(series) = self.series.get(&(pair.to_string(), interval)) else {
+            return Vec::new();
+        };
+
+        let mut candles: Vec<Candle> = series
+            .closed
+            .iter()
+            .chain(series.open.as_ref())
+            .rev()
+            .filter(|candle| before.is_none_or(|before| candle.open_time < before))
+            .take(limit)
+            .cloned()
+            .collect();
+        candles.reverse();
+        candles
+    }
+
+    /// Closed candles since the last call, for writing to disk.
+    pub fn take_unflushed(&mut self) -> Vec<Candle> {
+        std::mem::take(&mut self.unflushed)
+    }
+
+    /// Open candles and closed ones not yet on disk, as written to a snapshot.
+    pub fn state(&self) -> Vec<Candle> {
+        let open = self.series.values().filter_map(|series| series.open.clone());
+        self.unflushed.iter().cloned().chain(open).collect()
+    }
+
+    /// Takes back candles from a snapshot, see [`CandleAggregator::state`]. Closed ones are
+    /// written out again with the next flush, in case they never reached disk.
+    pub fn restore(&mut self, candles: Vec<Candle>) {
+        for candle in candles {
+            if let Some(closed) = self.insert(candle) {
+                self.unflushed.push(closed);
+            }
+        }
+    }
+
+    /// Takes closed candles read back from disk, oldest first, into the history.
+    pub fn load_history(&mut self, candles: Vec<Candle>) {
+        for candle in candles {
+            self.insert(candle);
+        }
+    }
+
+    /// Adds a stored candle: closed ones join the history, open ones replace the current candle.
+    /// Returns the candle if it was closed and taken.
+    fn insert(&mut self, candle: Candle) -> Option<Candle> {
+        let series = self.series.entry((candle.pair.clone(), candle.interval)).or_default();
+        if candle.closed {
+            if series.open.as_ref().is_some_and(|open| open.open_time <= candle.open_time) {
+                series.open = None;
+            }
+            series.push_closed(candle)
+        } else {
+            if series.last_closed().is_none_or(|last| candle.open_time > last) {
+                series.open = Some(candle);
+            }
+            None
+        }
+    }
+}
+
+/// Closed candles on disk, one JSON line per candle in a file per pair and interval.
+///
+/// Appends skip candles at or before the newest one already in the file, so candles rebuilt
+/// by a journal replay are not written twice.
+#[derive(Debug)]
+pub struct CandleStore {
+    dir: PathBuf,
+    last_written: HashMap<(String, CandleInterval), DateTime<Utc>>,
+}
+
+impl CandleStore {
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            last_written: HashMap::new(),
+        })
+    }
+
+    fn path(&self, pair: &str, interval: CandleInterval) -> PathBuf {
+        self.dir.join(pair.replace('/', "-")).join(format!("{}.jsonl", interval))
+    }
+
+    /// Up to `limit` of the newest stored candles that open before `before`, oldest first.
+    pub fn load(&self, pair: &str, interval: CandleInterval, before: Option<DateTime<Utc>>, limit: usize) -> io::Result<Vec<Candle>> {
+        let file = match File::open(self.path(pair, interval)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut candles = VecDeque::with_capacity(limit);
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            // A crash mid-append can leave a torn last line
+            let candle: Candle = match serde_json::from_str(&line) {
+                Ok(candle) => candle,
+                Err(e) => {
+                    warn!("Skipping unreadable {} {} candle: {}", pair, interval, e);
+                    continue;
+                }
+            };
+            if before.is_some_and(|before| candle.open_time >= before) {
+                break;
+            }
+            if candles.len() == limit {
+                candles.pop_front();
+            }
+            candles.push_back(candle);
+        }
+
+        Ok(candles.into())
+    }
+
+    /// Appends closed candles to their files and syncs them.
+    pub fn append(&mut self, candles: &[Candle]) -> io::Result<()> {
+        let mut files: HashMap<(String, CandleInterval), File> = HashMap::new();
+        let mut written = 0;
+
+        for candle in candles {
+            let key = (candle.pair.clone(), candle.interval);
+            if !self.last_written.contains_key(&key) {
+                let last = self.load(&candle.pair, candle.interval, None, 1)?.pop().map(|c| c.open_time);
+                self.last_written.insert(key.clone(), last.unwrap_or(DateTime::<Utc>::MIN_UTC));
+            }
+            if candle.open_time <= self.last_written[&key] {
+                continue;
+            }
+
+            let file = match files.entry(key.clone()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let path = self.path(&candle.pair, candle.interval);
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    entry.insert(OpenOptions::new().create(true).append(true).open(path)?)
+                }
+            };
+            let mut line = serde_json::to_vec(candle).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+            self.last_written.insert(key, candle.open_time);
+            written += 1;
+        }
+
+        for file in files.values() {
+            file.sync_data()?;
+        }
+        if written > 0 {
+            info!("Wrote {} closed candles to {}", written, self.dir.display());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order::{Order, OrderKind, OrderType};
+    use chrono::TimeZone;
+    use uuid::Uuid;
+
+    const PAIR: &str = "BTC/USDT";
+
+    fn at(hour: u32, min: u32, sec: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 3, 10, hour, min, sec).unwrap()
+    }
+
+    fn trade(price: i64, amount: i64, timestamp: DateTime<Utc>) -> Trade {
+        let order = |order_type| Order::new(Uuid::new_v4(), PAIR.to_string(), order_type, OrderKind::Limit, amount.into(), Some(price.into()));
+        Trade::new(&order(OrderType::Buy), &order(OrderType::Sell), amount.into(), price.into(), OrderType::Sell, timestamp)
+    }
+
+    fn minute_candles(candles: &[Candle]) -> Vec<&Candle> {
+        candles.iter().filter(|candle| candle.interval == CandleInterval::OneMinute).collect()
+    }
+
+    #[test]
+    fn candles_are_aligned_to_their_interval() {
+        let timestamp = at(13, 34, 56);
+        let open_times: Vec<DateTime<Utc>> = CandleInterval::ALL.iter().map(|interval| interval.open_time(timestamp)).collect();
+        assert_eq!(open_times, [at(13, 34, 0), at(13, 30, 0), at(13, 30, 0), at(13, 0, 0), at(12, 0, 0), at(0, 0, 0)]);
+
+        for interval in CandleInterval::ALL {
+            assert_eq!(interval.as_str().parse::<CandleInterval>(), Ok(interval));
+        }
+        assert!("2m".parse::<CandleInterval>().is_err());
+    }
+
+    #[test]
+    fn trades_in_one_interval_build_one_candle() {
+        let mut candles = CandleAggregator::new();
+        candles.record(&[trade(100, 1, at(12, 0, 5)), trade(104, 2, at(12, 0, 20))]);
+        let updated = candles.record(&[trade(98, 1, at(12, 0, 40)), trade(101, 3, at(12, 0, 59))]);
+
+        // One update per interval, each as it now stands
+        assert_eq!(updated.len(), CandleInterval::ALL.len());
+        let candle = minute_candles(&updated)[0];
+        assert_eq!((candle.open_time, candle.close_time), (at(12, 0, 0), at(12, 1, 0)));
+        assert_eq!((candle.open, candle.high, candle.low, candle.close), (100.into(), 104.into(), 98.into(), 101.into()));
+        assert_eq!(candle.volume, Decimal::from(7));
+        assert_eq!(candle.quote_volume, Decimal::from(100 + 208 + 98 + 303));
+        assert_eq!(candle.trade_count, 4);
+        assert!(!candle.closed);
+        assert!(updated.iter().all(|c| c.trade_count == 4 && c.close == Decimal::from(101)));
+    }
+
+    #[test]
+    fn a_trade_in_a_later_interval_closes_the_candle_before() {
+        let mut candles = CandleAggregator::new();
+        candles.record(&[trade(100, 1, at(12, 0, 30))]);
+
+        let updated = candles.record(&[trade(102, 1, at(12, 2, 0))]);
+        let minutes = minute_candles(&updated);
+        assert_eq!(minutes.len(), 2);
+        assert!(minutes[0].closed && minutes[0].open_time == at(12, 0, 0));
+        assert!(!minutes[1].closed && minutes[1].open_time == at(12, 2, 0));
+        assert_eq!(candles.take_unflushed().len(), 1);
+
+        // Trades for an interval already moved past change nothing
+        assert!(minute_candles(&candles.record(&[trade(1, 1, at(12, 1, 0))])).is_empty());
+        assert!(minute_candles(&candles.record(&[trade(1, 1, at(12, 0, 59))])).is_empty());
+    }
+
+    #[test]
+    fn due_candles_close_without_a_trade() {
+        let mut candles = CandleAggregator::new();
+        candles.record(&[trade(100, 1, at(12, 0, 30))]);
+
+        assert!(candles.close_due(at(12, 0, 59)).is_empty());
+        let closed = candles.close_due(at(12, 1, 0));
+        assert_eq!(closed.iter().map(|c| c.interval).collect::<Vec<_>>(), [CandleInterval::OneMinute]);
+        assert!(closed[0].closed);
+        let closed = candles.close_due(at(16, 0, 0));
+        assert_eq!(
+            closed.iter().map(|c| c.interval).collect::<Vec<_>>(),
+            [CandleInterval::FiveMinutes, CandleInterval::FifteenMinutes, CandleInterval::OneHour, CandleInterval::FourHours]
+        );
+        assert!(candles.close_due(at(16, 0, 0)).is_empty());
+    }
+
+    #[test]
+    fn history_is_oldest_first_and_includes_the_open_candle() {
+        let mut candles = CandleAggregator::new();
+        for minute in 0..5 {
+            candles.record(&[trade(100 + minute as i64, 1, at(12, minute, 0))]);
+        }
+
+        let opens = |history: Vec<Candle>| history.iter().map(|candle| candle.open).collect::<Vec<_>>();
+        assert_eq!(opens(candles.history(PAIR, CandleInterval::OneMinute, None, 10)), [100, 101, 102, 103, 104].map(Decimal::from));
+        assert_eq!(opens(candles.history(PAIR, CandleInterval::OneMinute, None, 2)), [103, 104].map(Decimal::from));
+        assert_eq!(opens(candles.history(PAIR, CandleInterval::OneMinute, Some(at(12, 3, 0)), 2)), [101, 102].map(Decimal::from));
+        assert!(candles.history("ETH/USDT", CandleInterval::OneMinute, None, 10).is_empty());
+    }
+
+    #[test]
+    fn stored_candles_survive_reopening_and_are_not_written_twice() {
+        let dir = std::env::temp_dir().join(format!("order-engine-candles-{}", Uuid::new_v4()));
+        let mut candles = CandleAggregator::new();
+        for minute in 0..4 {
+            candles.record(&[trade(100 + minute as i64, 1, at(12, minute, 0))]);
+        }
+        let closed = candles.take_unflushed();
+        assert_eq!(minute_candles(&closed).len(), 3);
+
+        let mut store = CandleStore::open(&dir).unwrap();
+        store.append(&closed).unwrap();
+        // A replay rebuilding the same candles, into a store that has to find what is on disk
+        let mut reopened = CandleStore::open(&dir).unwrap();
+        reopened.append(&closed).unwrap();
+
+        let stored = reopened.load(PAIR, CandleInterval::OneMinute, None, 10).unwrap();
+        assert_eq!(stored, minute_candles(&closed).into_iter().cloned().collect::<Vec<_>>());
+        assert_eq!(reopened.load(PAIR, CandleInterval::OneMinute, Some(at(12, 2, 0)), 1).unwrap()[0].open_time, at(12, 1, 0));
+
+        // A torn last line from a crash mid-append is skipped
+        let path = reopened.path(PAIR, CandleInterval::OneMinute);
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"pair\":\"BTC").unwrap();
+        assert_eq!(CandleStore::open(&dir).unwrap().load(PAIR, CandleInterval::OneMinute, None, 10).unwrap().len(), 3);
+
+        // Loaded history goes back in behind the open candle
+        let mut restarted = CandleAggregator::new();
+        restarted.load_history(stored);
+        restarted.record(&[trade(110, 1, at(12, 3, 30))]);
+        assert_eq!(restarted.history(PAIR, CandleInterval::OneMinute, None, 10).len(), 4);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,9 +1,24 @@
-use crate::orderbook::OrderBook;
-use crate::order::{Order, Trade};
+use crate::accounts::Balance;
+use crate::candles::{Candle, CandleInterval, CandleStore, CANDLE_HISTORY_LEN};
+use crate::events::EngineEvent;
+use crate::journal::{Journal, JournalInput, JournalRecord, JournalWriter};
+use crate::market::MarketSpec;
+use crate::matching::MatchingPolicy;
+use crate::orderbook::{AmendResult, MatchResult, OrderBook};
+use crate::order::{Fill, Order, OrderKind, OrderType, SelfTradePrevented, SelfTradePrevention, TimeInForce, Trade};
+use crate::shard::{Shard, ShardTask, SharedState};
+use crate::snapshot::EngineSnapshot;
+use crate::ticker::Ticker;
+use crate::volume::FeeTierSchedule;
+use chrono::{DateTime, Utc};
 use dashmap::DashMap;
-use std::sync::Arc;
-use tokio::sync::mpsc;
-use tracing::{info, warn, error};
+use futures_util::future::join_all;
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Barrier, Mutex, MutexGuard, PoisonError, RwLock};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::{info, warn};
 use uuid::Uuid;
 
 pub type EngineResult<T> = Result<T, EngineError>;
@@ -11,172 +26,898 @@ pub type EngineResult<T> = Result<T, EngineError>;
 #[derive(Debug)]
 pub enum EngineError {
     OrderBookNotFound(String),
+    OrderNotFound(Uuid),
     InvalidOrder(String),
+    /// A deposit or withdrawal the engine cannot take, e.g. of an asset no pair trades.
+    InvalidRequest(String),
+    /// The account does not have `required` of `asset` available to lock or withdraw.
+    InsufficientFunds {
+        asset: String,
+        required: Decimal,
+        available: Decimal,
+    },
     ProcessingError(String),
+    /// A worker's input queue is full; the request was not accepted.
+    Overloaded,
 }
 
 impl std::fmt::Display for EngineError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             EngineError::OrderBookNotFound(pair) => write!(f, "Order book not found for pair: {}", pair),
+            EngineError::OrderNotFound(order_id) => write!(f, "Order not found: {}", order_id),
             EngineError::InvalidOrder(msg) => write!(f, "Invalid order: {}", msg),
+            EngineError::InvalidRequest(msg) => write!(f, "Invalid request: {}", msg),
+            EngineError::InsufficientFunds { asset, required, available } => {
+                write!(f, "Insufficient {} balance: {} required, {} available", asset, required, available)
+            }
             EngineError::ProcessingError(msg) => write!(f, "Processing error: {}", msg),
+            EngineError::Overloaded => write!(f, "Engine overloaded, try again later"),
         }
     }
 }
 
 impl std::error::Error for EngineError {}
 
+/// A request to the engine, see [`OrderEngine::process`].
 #[derive(Debug, Clone)]
 pub enum EngineMessage {
     NewOrder(Order),
     CancelOrder(Uuid),
+    AmendOrder {
+        order_id: Uuid,
+        new_amount: Option<Decimal>,
+        new_price: Option<Decimal>,
+    },
+    GetOrder(Uuid),
     GetOrderBook(String),
+    Deposit {
+        user_id: Uuid,
+        asset: String,
+        amount: Decimal,
+    },
+    Withdraw {
+        user_id: Uuid,
+        asset: String,
+        amount: Decimal,
+    },
+    GetBalances(Uuid),
+}
+
+/// The engine's answer to an [`EngineMessage`], one variant per message.
+#[derive(Debug, Clone)]
+pub enum EngineReply {
+    NewOrder(EngineResponse),
+    /// The cancelled order, or `None` if it was no longer live.
+    CancelOrder(Option<Order>),
+    AmendOrder(AmendResult),
+    GetOrder(Option<Order>),
+    GetOrderBook(Option<OrderBookSnapshot>),
+    /// The asset's balance after the deposit.
+    Deposit(Balance),
+    /// The asset's balance after the withdrawal.
+    Withdraw(Balance),
+    GetBalances(HashMap<String, Balance>),
 }
 
 #[derive(Debug, Clone)]
 pub struct EngineResponse {
     pub trades: Vec<Trade>,
     pub updated_order: Option<Order>,
+    pub cancelled_orders: Vec<Order>,
+    pub triggered_orders: Vec<Order>,
+    pub repriced_from: Option<Decimal>,
+    pub self_trades_prevented: Vec<SelfTradePrevented>,
+    pub fills: Vec<Fill>,
 }
 
+impl From<MatchResult> for EngineResponse {
+    fn from(result: MatchResult) -> Self {
+        Self {
+            trades: result.trades,
+            updated_order: Some(result.order),
+            cancelled_orders: result.cancelled,
+            triggered_orders: result.triggered,
+            repriced_from: result.repriced_from,
+            self_trades_prevented: result.self_trades_prevented,
+            fills: result.fills,
+        }
+    }
+}
+
+/// Default capacity of each shard's input queue.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+/// Front end of the matching engine.
+///
+/// Pairs are partitioned across worker threads ("shards"), each owning its books outright. Calls
+/// validate their input here, then queue it to the owning shard and wait for its reply.
 pub struct OrderEngine {
-    orderbooks: Arc<DashMap<String, OrderBook>>,
-    tx: mpsc::UnboundedSender<EngineMessage>,
+    shards: Vec<mpsc::Sender<ShardTask>>,
+    pair_shards: DashMap<String, usize>, // Listed pairs (pair -> shard)
+    fee_tier_schedule: RwLock<FeeTierSchedule>,
+    candle_store: Mutex<Option<CandleStore>>,
+    shared: Arc<SharedState>,
 }
 
 impl OrderEngine {
     pub fn new(workers: usize) -> Self {
-        let orderbooks = Arc::new(DashMap::new());
-        let (tx, rx) = mpsc::unbounded_channel();
-
-        // Start worker tasks
-        for i in 0..workers {
-            let orderbooks_clone = Arc::clone(&orderbooks);
-            let mut rx_clone = rx.clone();
+        Self::with_queue_capacity(workers, DEFAULT_QUEUE_CAPACITY)
+    }
 
-            tokio::spawn(async move {
-                info!("Starting worker thread {}", i);
-                // Worker implementation would go here
-                // For now, we'll handle messages directly
-            });
-        }
+    /// Starts an engine whose workers each accept at most `queue_capacity` pending requests.
+    /// Requests beyond that fail with [`EngineError::Overloaded`].
+    pub fn with_queue_capacity(workers: usize, queue_capacity: usize) -> Self {
+        let workers = workers.max(1);
+        let shared = Arc::new(SharedState::default());
+        let pair_shards = DashMap::new();
 
-        // Initialize common trading pairs
-        let pairs = vec![
-            "BTC/USDT".to_string(),
-            "ETH/USDT".to_string(),
-            "SOL/USDT".to_string(),
-            "ADA/USDT".to_string(),
-        ];
+        // Initialize common trading pairs, dealt round-robin across the shards
+        let mut shard_books: Vec<HashMap<String, OrderBook>> = vec![HashMap::new(); workers];
+        for (i, spec) in MarketSpec::defaults().into_iter().enumerate() {
+            if let Err(e) = spec.validate() {
+                warn!("Skipping default market {}: {}", spec.pair, e);
+                continue;
+            }
 
-        for pair in pairs {
-            orderbooks.insert(pair.clone(), OrderBook::new(pair));
+            let shard = i % workers;
+            shard_books[shard].insert(spec.pair.clone(), new_book(&spec));
+            pair_shards.insert(spec.pair.clone(), shard);
+            shared.markets.insert(spec.pair.clone(), spec);
         }
 
+        // Start worker threads
+        let shards = shard_books
+            .into_iter()
+            .enumerate()
+            .map(|(i, books)| {
+                Shard::spawn(i, books, Arc::clone(&shared), queue_capacity.max(1)).expect("failed to start worker thread")
+            })
+            .collect();
+
         info!("Order engine initialized with {} workers", workers);
 
         Self {
-            orderbooks,
-            tx,
+            shards,
+            pair_shards,
+            fee_tier_schedule: RwLock::default(),
+            candle_store: Mutex::default(),
+            shared,
         }
     }
 
-    pub fn add_order(&self, order: Order) -> EngineResult<EngineResponse> {
-        let pair = order.pair.clone();
+    /// Streams every event from every pair, starting with the next one published.
+    ///
+    /// Events for a pair arrive in `seq` order. A receiver that falls too far behind is told how
+    /// many it missed (`RecvError::Lagged`) rather than slowing the matching threads down.
+    pub fn subscribe(&self) -> broadcast::Receiver<EngineEvent> {
+        self.shared.events.subscribe()
+    }
 
-        if let Some(mut orderbook_ref) = self.orderbooks.get_mut(&pair) {
-            let trades = orderbook_ref.add_order(order.clone());
+    /// Runs `task` on shard `shard` and waits for its result.
+    ///
+    /// Fails with [`EngineError::Overloaded`] instead of queueing when the shard is full.
+    async fn run_on<T: Send + 'static>(
+        &self,
+        shard: usize,
+        task: impl FnOnce(&mut Shard) -> T + Send + 'static,
+    ) -> EngineResult<T> {
+        let (task, reply_rx) = reply_task(task);
+        self.shards[shard].try_send(task).map_err(|e| match e {
+            TrySendError::Full(_) => EngineError::Overloaded,
+            TrySendError::Closed(_) => EngineError::ProcessingError(format!("Worker {} has stopped", shard)),
+        })?;
 
-            info!(
-                "Order {} processed for pair {}, generated {} trades",
-                order.id,
-                pair,
-                trades.len()
-            );
+        reply_rx
+            .await
+            .map_err(|_| EngineError::ProcessingError(format!("Worker {} dropped the request", shard)))
+    }
 
-            Ok(EngineResponse {
-                trades,
-                updated_order: Some(order),
-            })
-        } else {
-            // Create new orderbook for the pair
-            let mut new_orderbook = OrderBook::new(pair.clone());
-            let trades = new_orderbook.add_order(order.clone());
-            self.orderbooks.insert(pair.clone(), new_orderbook);
+    /// Runs `task` on every shard at once and collects the results in shard order.
+    ///
+    /// Used for housekeeping, so it waits for room in a full queue rather than giving up.
+    async fn run_on_all<T: Send + 'static>(
+        &self,
+        task: impl Fn(&mut Shard) -> T + Send + Sync + 'static,
+    ) -> EngineResult<Vec<T>> {
+        let task = Arc::new(task);
+        let runs = self.shards.iter().enumerate().map(|(shard, queue)| {
+            let task = Arc::clone(&task);
+            async move {
+                let (task, reply_rx) = reply_task(move |s| task(s));
+                queue
+                    .send(task)
+                    .await
+                    .map_err(|_| EngineError::ProcessingError(format!("Worker {} has stopped", shard)))?;
+                reply_rx
+                    .await
+                    .map_err(|_| EngineError::ProcessingError(format!("Worker {} dropped the request", shard)))
+            }
+        });
+        join_all(runs).await.into_iter().collect()
+    }
+
+    fn shard_of(&self, pair: &str) -> EngineResult<usize> {
+        self.pair_shards.get(pair).map(|shard| *shard).ok_or_else(|| EngineError::OrderBookNotFound(pair.to_string()))
+    }
+
+    /// Lists a pair, or updates the rules of one that is already listed.
+    pub async fn add_market(&self, spec: MarketSpec) -> EngineResult<()> {
+        spec.validate().map_err(EngineError::InvalidOrder)?;
+
+        let pair = spec.pair.clone();
+        let shard = match self.shard_of(&pair) {
+            Ok(shard) => shard,
+            Err(_) => self.pair_shards.len() % self.shards.len(),
+        };
+
+        let listed = spec.clone();
+        self.run_on(shard, move |s| {
+            let orderbook = s.books.entry(listed.pair.clone()).or_insert_with(|| new_book(&listed));
+            orderbook.tick_size = listed.tick_size;
+            orderbook.lot_size = listed.quantity_step;
+            orderbook.fees = Arc::new(listed.fee_schedule());
+            orderbook.matching_policy = listed.matching_policy.clone();
+        })
+        .await?;
+
+        info!("Market {} listed on worker {} with tick size {} and quantity step {}", pair, shard, spec.tick_size, spec.quantity_step);
+        self.pair_shards.insert(pair.clone(), shard);
+        self.shared.markets.insert(pair, spec);
+        Ok(())
+    }
+
+    pub fn get_market(&self, pair: &str) -> Option<MarketSpec> {
+        self.shared.markets.get(pair).map(|spec| spec.clone())
+    }
+
+    /// Handles one request and returns the matching reply.
+    ///
+    /// This is the entry point for services embedding the engine. Requests are never queued
+    /// without bound: when the owning worker is saturated the call fails fast with
+    /// [`EngineError::Overloaded`].
+    pub async fn process(&self, message: EngineMessage) -> EngineResult<EngineReply> {
+        match message {
+            EngineMessage::NewOrder(order) => self.add_order(order).await.map(EngineReply::NewOrder),
+            EngineMessage::CancelOrder(order_id) => self.cancel_order(order_id).await.map(EngineReply::CancelOrder),
+            EngineMessage::AmendOrder { order_id, new_amount, new_price } => {
+                self.amend_order(order_id, new_amount, new_price).await.map(EngineReply::AmendOrder)
+            }
+            EngineMessage::GetOrder(order_id) => self.get_order(order_id).await.map(EngineReply::GetOrder),
+            EngineMessage::GetOrderBook(pair) => self.get_orderbook_snapshot(&pair).await.map(EngineReply::GetOrderBook),
+            EngineMessage::Deposit { user_id, asset, amount } => self.deposit(user_id, &asset, amount).map(EngineReply::Deposit),
+            EngineMessage::Withdraw { user_id, asset, amount } => self.withdraw(user_id, &asset, amount).map(EngineReply::Withdraw),
+            EngineMessage::GetBalances(user_id) => Ok(EngineReply::GetBalances(self.get_balances(user_id))),
+        }
+    }
+
+    pub async fn add_order(&self, mut order: Order) -> EngineResult<EngineResponse> {
+        Self::validate_order(&order)?;
+
+        // Orders without their own self-trade prevention mode use the account's
+        if order.self_trade_prevention.is_none() {
+            order.self_trade_prevention = self.shared.accounts.self_trade_prevention(order.user_id);
+        }
+
+        // Unknown pairs are rejected rather than given a fresh book
+        let spec = self.get_market(&order.pair).ok_or_else(|| EngineError::OrderBookNotFound(order.pair.clone()))?;
+        spec.validate_order(&order).map_err(EngineError::InvalidOrder)?;
+
+        let shard = self.shard_of(&order.pair)?;
+        self.run_on(shard, move |s| s.add_order(order)).await?
+    }
+
+    /// Starts recording every accepted input to `journal`. Replay any existing records first.
+    pub async fn attach_journal(&self, mut journal: Journal) -> EngineResult<()> {
+        // Never reuse sequence numbers a restored snapshot has already seen
+        let restored_seq = self
+            .run_on_all(|s| s.books.values().map(|book| book.last_input_seq).max().unwrap_or(0))
+            .await?
+            .into_iter()
+            .max()
+            .unwrap_or(0)
+            .max(self.shared.accounts.last_input_seq());
+        journal.skip_to(restored_seq);
+
+        let writer = JournalWriter::start(journal)
+            .map_err(|e| EngineError::ProcessingError(format!("Failed to start journal writer: {}", e)))?;
+        info!("Journaling engine inputs to {}", writer.path().display());
+        self.shared
+            .journal
+            .set(writer)
+            .map_err(|_| EngineError::ProcessingError("A journal is already attached".to_string()))
+    }
+
+    /// Forces journaled inputs to disk regardless of the fsync policy.
+    pub fn sync_journal(&self) -> EngineResult<()> {
+        let Some(journal) = self.shared.journal.get() else {
+            return Ok(());
+        };
+
+        journal.sync().map_err(|e| EngineError::ProcessingError(format!("Failed to sync journal: {}", e)))
+    }
+
+    /// Drops the journal records numbered `through_seq` or lower, see [`Journal::compact`].
+    /// Returns how many went; 0 without a journal.
+    pub fn compact_journal(&self, through_seq: u64) -> EngineResult<usize> {
+        let Some(journal) = self.shared.journal.get() else {
+            return Ok(0);
+        };
+
+        journal
+            .compact(through_seq)
+            .map_err(|e| EngineError::ProcessingError(format!("Failed to compact journal: {}", e)))
+    }
 
-            info!("Created new orderbook for pair {} and processed order {}", pair, order.id);
+    /// Re-applies journaled inputs in order, rebuilding the books they produced.
+    ///
+    /// Inputs are applied with their recorded time and are not journaled again. Records a book
+    /// already reflects (because it was restored from a later snapshot) are skipped. Returns how
+    /// many records were applied.
+    pub async fn replay_journal(&self, records: Vec<JournalRecord>) -> EngineResult<usize> {
+        // Route every record to the shard owning its pair, keeping journal order within each shard
+        let mut replayed_pairs: HashMap<Uuid, String> = HashMap::new();
+        let mut shard_records: Vec<Vec<(String, JournalRecord)>> = vec![Vec::new(); self.shards.len()];
+        let mut account_inputs = 0;
 
-            Ok(EngineResponse {
-                trades,
-                updated_order: Some(order),
+        for record in records {
+            let pair = match &record.input {
+                JournalInput::NewOrder { order } => {
+                    replayed_pairs.insert(order.id, order.pair.clone());
+                    Some(order.pair.clone())
+                }
+                JournalInput::CancelOrder { order_id } | JournalInput::AmendOrder { order_id, .. } => {
+                    replayed_pairs.get(order_id).cloned().or_else(|| self.order_pair(*order_id))
+                }
+                JournalInput::ExpireOrders { pair } => Some(pair.clone()),
+                // Balances are not owned by any shard, so these are applied right away. Orders
+                // replay their locks unchecked, so it does not matter that they run later.
+                JournalInput::Deposit { user_id, asset, amount } => {
+                    if record.seq > self.shared.accounts.last_input_seq() {
+                        self.shared.accounts.deposit(*user_id, asset, *amount);
+                        self.shared.accounts.set_last_input_seq(record.seq);
+                        account_inputs += 1;
+                    }
+                    continue;
+                }
+                JournalInput::Withdraw { user_id, asset, amount } => {
+                    if record.seq > self.shared.accounts.last_input_seq() {
+                        self.shared.accounts.withdraw_replayed(*user_id, asset, *amount);
+                        self.shared.accounts.set_last_input_seq(record.seq);
+                        account_inputs += 1;
+                    }
+                    continue;
+                }
+                // Orders carry the tier they were placed in, so only new orders need this
+                JournalInput::SetFeeTier { user_id, tier } => {
+                    if record.seq > self.shared.accounts.last_input_seq() {
+                        self.shared.accounts.set_fee_tier(*user_id, *tier);
+                        self.shared.accounts.set_last_input_seq(record.seq);
+                        account_inputs += 1;
+                    }
+                    continue;
+                }
+                // Orders carry the mode they were placed with, the same as fee tiers
+                JournalInput::SetSelfTradePrevention { user_id, mode } => {
+                    if record.seq > self.shared.accounts.last_input_seq() {
+                        self.shared.accounts.set_self_trade_prevention(*user_id, *mode);
+                        self.shared.accounts.set_last_input_seq(record.seq);
+                        account_inputs += 1;
+                    }
+                    continue;
+                }
+            };
+
+            // Cancels and amends of orders that are already gone have nothing left to do
+            let Some(pair) = pair else {
+                continue;
+            };
+            match self.shard_of(&pair) {
+                Ok(shard) => shard_records[shard].push((pair, record)),
+                Err(_) => warn!("Skipping journal record {}: unknown pair {}", record.seq, pair),
+            }
+        }
+
+        let replays = shard_records
+            .into_iter()
+            .enumerate()
+            .map(|(shard, records)| self.run_on(shard, move |s| s.replay(records)));
+        let applied: usize = account_inputs + join_all(replays).await.into_iter().collect::<EngineResult<Vec<_>>>()?.into_iter().sum::<usize>();
+
+        info!("Replayed {} journal records", applied);
+        Ok(applied)
+    }
+
+    /// Copies every book's orders, every account balance, every user's recent volume and the
+    /// candles not yet on disk into a snapshot.
+    ///
+    /// Balances change on every shard, so all shards pause together while they are copied, and
+    /// the account input lock keeps deposits, withdrawals and tier changes out for that moment too.
+    pub async fn snapshot(&self) -> EngineResult<EngineSnapshot> {
+        let barrier = Arc::new(Barrier::new(self.shards.len()));
+        let shared = Arc::clone(&self.shared);
+        let copies = self
+            .run_on_all(move |s| {
+                let books: Vec<_> = s.books.values().map(|book| book.state()).collect();
+                let candles = s.candles.state();
+                let accounts = barrier.wait().is_leader().then(|| {
+                    shared.lock_account_inputs().map(|_inputs| (shared.accounts.state(), shared.volumes.state()))
+                });
+                barrier.wait();
+                (books, candles, accounts)
             })
+            .await?;
+
+        let mut books = Vec::new();
+        let mut candles = Vec::new();
+        let mut accounts = None;
+        for (shard_books, shard_candles, shard_accounts) in copies {
+            books.extend(shard_books);
+            candles.extend(shard_candles);
+            accounts = accounts.or(shard_accounts);
         }
+        let (accounts, volumes) = accounts.transpose()?.unwrap_or_default();
+        Ok(EngineSnapshot::new(books, accounts, volumes, candles))
     }
 
-    pub fn cancel_order(&self, pair: &str, order_id: Uuid) -> EngineResult<Option<Order>> {
-        if let Some(mut orderbook_ref) = self.orderbooks.get_mut(pair) {
-            let cancelled_order = orderbook_ref.cancel_order(order_id);
+    /// Replaces the orders of every listed pair with those in `snapshot`.
+    ///
+    /// Books for pairs that are no longer listed are dropped with a warning.
+    pub async fn restore_snapshot(&self, snapshot: EngineSnapshot) -> EngineResult<()> {
+        self.shared.order_pairs.clear();
+        self.shared.accounts.restore_state(snapshot.accounts);
+        self.shared.volumes.restore_state(snapshot.volumes);
+
+        for state in snapshot.books {
+            let pair = state.pair.clone();
+            let Ok(shard) = self.shard_of(&pair) else {
+                warn!("Snapshot contains unknown pair {}, dropping its orders", pair);
+                continue;
+            };
 
-            if cancelled_order.is_some() {
-                info!("Order {} cancelled in pair {}", order_id, pair);
-            } else {
-                warn!("Order {} not found for cancellation in pair {}", order_id, pair);
+            self.run_on(shard, move |s| s.restore_book(state)).await?;
+        }
+
+        let mut shard_candles: Vec<Vec<Candle>> = vec![Vec::new(); self.shards.len()];
+        for candle in snapshot.candles {
+            if let Ok(shard) = self.shard_of(&candle.pair) {
+                shard_candles[shard].push(candle);
             }
+        }
+        for (shard, candles) in shard_candles.into_iter().enumerate() {
+            self.run_on(shard, move |s| s.candles.restore(candles)).await?;
+        }
+
+        info!("Restored snapshot taken at {}", snapshot.taken_at);
+        Ok(())
+    }
 
-            Ok(cancelled_order)
-        } else {
-            Err(EngineError::OrderBookNotFound(pair.to_string()))
+    /// Keeps closed candles in `store` from now on, and reads the latest of them back in.
+    ///
+    /// Call this before restoring a snapshot or replaying the journal: candles they rebuild are
+    /// then only kept, and written, if they are newer than what is already stored.
+    pub async fn attach_candle_store(&self, store: CandleStore) -> EngineResult<()> {
+        for entry in self.pair_shards.iter() {
+            let (pair, shard) = (entry.key().clone(), *entry.value());
+            let mut history = Vec::new();
+            for interval in CandleInterval::ALL {
+                history.extend(
+                    store
+                        .load(&pair, interval, None, CANDLE_HISTORY_LEN)
+                        .map_err(|e| EngineError::ProcessingError(format!("Failed to read {} candles: {}", pair, e)))?,
+                );
+            }
+            self.run_on(shard, move |s| s.candles.load_history(history)).await?;
         }
+
+        let mut attached = self.lock_candle_store()?;
+        *attached = Some(store);
+        Ok(())
     }
 
-    pub fn get_orderbook(&self, pair: &str) -> Option<OrderBook> {
-        self.orderbooks.get(pair).map(|book_ref| book_ref.clone())
+    /// Closes every candle whose interval ended by `now`, including those of pairs that have
+    /// stopped trading.
+    pub async fn close_candles(&self, now: DateTime<Utc>) -> EngineResult<()> {
+        self.run_on_all(move |s| s.close_candles(now)).await?;
+        Ok(())
     }
 
-    pub fn get_orderbook_snapshot(&self, pair: &str) -> Option<OrderBookSnapshot> {
-        if let Some(orderbook_ref) = self.orderbooks.get(pair) {
-            let orderbook = orderbook_ref.value();
+    /// Writes candles closed since the last flush to the candle store. A no-op until one is
+    /// attached.
+    pub async fn flush_candles(&self) -> EngineResult<()> {
+        if self.lock_candle_store()?.is_none() {
+            return Ok(());
+        }
+
+        let closed: Vec<Candle> = self.run_on_all(|s| s.candles.take_unflushed()).await?.into_iter().flatten().collect();
+        let mut store = self.lock_candle_store()?;
+        let Some(store) = store.as_mut() else {
+            return Ok(());
+        };
+        store
+            .append(&closed)
+            .map_err(|e| EngineError::ProcessingError(format!("Failed to write candles: {}", e)))
+    }
 
-            let bids: Vec<(rust_decimal::Decimal, rust_decimal::Decimal)> = orderbook
-                .bids
-                .iter()
-                .map(|(price, orders)| {
-                    let total_amount: rust_decimal::Decimal = orders
-                        .iter()
-                        .map(|o| o.remaining_amount())
-                        .sum();
-                    (*price, total_amount)
-                })
-                .collect();
+    /// Up to `limit` of a pair's newest candles opening before `before`, the open one included,
+    /// oldest first. Candles too old to be held in memory are read from the candle store.
+    pub async fn get_candles(
+        &self,
+        pair: &str,
+        interval: CandleInterval,
+        before: Option<DateTime<Utc>>,
+        limit: usize,
+    ) -> EngineResult<Vec<Candle>> {
+        let shard = self.shard_of(pair)?;
+        let owned_pair = pair.to_string();
+        let mut candles = self.run_on(shard, move |s| s.candles.history(&owned_pair, interval, before, limit)).await?;
+        if candles.len() >= limit {
+            return Ok(candles);
+        }
 
-            let asks: Vec<(rust_decimal::Decimal, rust_decimal::Decimal)> = orderbook
-                .asks
-                .iter()
-                .map(|(price, orders)| {
-                    let total_amount: rust_decimal::Decimal = orders
-                        .iter()
-                        .map(|o| o.remaining_amount())
-                        .sum();
-                    (*price, total_amount)
-                })
-                .collect();
+        let store = self.lock_candle_store()?;
+        let Some(store) = store.as_ref() else {
+            return Ok(candles);
+        };
+        let older_than = candles.first().map(|candle| candle.open_time).or(before);
+        let mut older = store
+            .load(pair, interval, older_than, limit - candles.len())
+            .map_err(|e| EngineError::ProcessingError(format!("Failed to read {} candles: {}", pair, e)))?;
+        older.append(&mut candles);
+        Ok(older)
+    }
 
-            Some(OrderBookSnapshot {
-                pair: pair.to_string(),
-                bids,
-                asks,
-                best_bid: orderbook.get_best_bid(),
-                best_ask: orderbook.get_best_ask(),
-                spread: orderbook.get_spread(),
-            })
-        } else {
-            None
+    fn lock_candle_store(&self) -> EngineResult<MutexGuard<'_, Option<CandleStore>>> {
+        self.candle_store.lock().map_err(|_| EngineError::ProcessingError("Candle store lock poisoned".to_string()))
+    }
+
+    /// Pair a live order is on, `None` once it has left the book.
+    pub fn order_pair(&self, order_id: Uuid) -> Option<String> {
+        self.shared.order_pairs.get(&order_id).map(|pair| pair.value().clone())
+    }
+
+    /// Credits `amount` of `asset` to the user's available balance.
+    pub fn deposit(&self, user_id: Uuid, asset: &str, amount: Decimal) -> EngineResult<Balance> {
+        self.validate_transfer(asset, amount)?;
+
+        // Held until the deposit is applied, so a snapshot sees both or neither
+        let _inputs = self.shared.lock_account_inputs()?;
+        let seq = self.journal_account_input(JournalInput::Deposit { user_id, asset: asset.to_string(), amount })?;
+        let balance = self.shared.accounts.deposit(user_id, asset, amount);
+        if let Some(seq) = seq {
+            self.shared.accounts.set_last_input_seq(seq);
+        }
+
+        info!("Deposited {} {} for user {}", amount, asset, user_id);
+        Ok(balance)
+    }
+
+    /// Takes `amount` of `asset` out of the user's available balance.
+    ///
+    /// Fails with [`EngineError::InsufficientFunds`] if less than that is available; funds locked
+    /// for live orders cannot be withdrawn.
+    pub fn withdraw(&self, user_id: Uuid, asset: &str, amount: Decimal) -> EngineResult<Balance> {
+        self.validate_transfer(asset, amount)?;
+
+        let _inputs = self.shared.lock_account_inputs()?;
+        let balance = self.shared.accounts.withdraw(user_id, asset, amount)?;
+        let seq = match self.journal_account_input(JournalInput::Withdraw { user_id, asset: asset.to_string(), amount }) {
+            Ok(seq) => seq,
+            Err(e) => {
+                self.shared.accounts.deposit(user_id, asset, amount);
+                return Err(e);
+            }
+        };
+        if let Some(seq) = seq {
+            self.shared.accounts.set_last_input_seq(seq);
+        }
+
+        info!("Withdrew {} {} for user {}", amount, asset, user_id);
+        Ok(balance)
+    }
+
+    /// Every asset the user holds, with what is available and what is locked for live orders.
+    pub fn get_balances(&self, user_id: Uuid) -> HashMap<String, Balance> {
+        self.shared.accounts.balances(user_id)
+    }
+
+    /// Moves the user to fee `tier` for orders placed from now on. Orders already placed keep
+    /// paying the rates of the tier they were placed in.
+    pub fn set_fee_tier(&self, user_id: Uuid, tier: u8) -> EngineResult<()> {
+        let _inputs = self.shared.lock_account_inputs()?;
+        let seq = self.journal_account_input(JournalInput::SetFeeTier { user_id, tier })?;
+        self.shared.accounts.set_fee_tier(user_id, tier);
+        if let Some(seq) = seq {
+            self.shared.accounts.set_last_input_seq(seq);
         }
+
+        info!("Moved user {} to fee tier {}", user_id, tier);
+        Ok(())
+    }
+
+    pub fn get_fee_tier(&self, user_id: Uuid) -> u8 {
+        self.shared.accounts.fee_tier(user_id)
+    }
+
+    /// Notional the user traded over the volume window as of `now`, see [`crate::volume::VolumeTracker`].
+    pub fn get_rolling_volume(&self, user_id: Uuid, now: DateTime<Utc>) -> Decimal {
+        self.shared.volumes.rolling_volume(user_id, now)
+    }
+
+    pub fn fee_tier_schedule(&self) -> FeeTierSchedule {
+        self.fee_tier_schedule.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Replaces the volume each tier needs and the time of day tiers are recalculated.
+    pub fn set_fee_tier_schedule(&self, schedule: FeeTierSchedule) -> EngineResult<()> {
+        schedule.validate().map_err(EngineError::InvalidRequest)?;
+        *self.fee_tier_schedule.write().unwrap_or_else(PoisonError::into_inner) = schedule;
+        Ok(())
+    }
+
+    /// Moves every user to the tier their rolling volume as of `now` earns, through
+    /// [`OrderEngine::set_fee_tier`]. Meant to run once a day at the schedule's recalculation
+    /// time; returns how many users changed tier.
+    pub fn recalculate_fee_tiers(&self, now: DateTime<Utc>) -> EngineResult<usize> {
+        let schedule = self.fee_tier_schedule();
+        let mut users: HashSet<Uuid> = self.shared.volumes.users().into_iter().collect();
+        users.extend(self.shared.accounts.fee_tier_users());
+
+        let mut changed = 0;
+        for user_id in users {
+            let tier = schedule.tier_for(self.shared.volumes.rolling_volume(user_id, now));
+            if tier != self.shared.accounts.fee_tier(user_id) {
+                self.set_fee_tier(user_id, tier)?;
+                changed += 1;
+            }
+        }
+        self.shared.volumes.prune(now);
+
+        info!("Recalculated fee tiers, {} users changed tier", changed);
+        Ok(changed)
+    }
+
+    fn validate_transfer(&self, asset: &str, amount: Decimal) -> EngineResult<()> {
+        if amount <= Decimal::ZERO {
+            return Err(EngineError::InvalidRequest(format!("Amount must be positive: {}", amount)));
+        }
+        if !self.shared.markets.iter().any(|spec| spec.trades_asset(asset)) {
+            return Err(EngineError::InvalidRequest(format!("No listed pair trades {}", asset)));
+        }
+        Ok(())
+    }
+
+    /// Records an input applied to the accounts rather than a book, a no-op until a journal is
+    /// attached.
+    fn journal_account_input(&self, input: JournalInput) -> EngineResult<Option<u64>> {
+        self.shared.write_journal(Utc::now(), || input)
+    }
+
+    fn validate_order(order: &Order) -> EngineResult<()> {
+        match order.order_kind {
+            OrderKind::Limit | OrderKind::StopLimit if order.price.is_none() => {
+                return Err(EngineError::InvalidOrder("Limit orders require a price".to_string()));
+            }
+            OrderKind::Stop | OrderKind::StopLimit if order.trigger_price.is_none() => {
+                return Err(EngineError::InvalidOrder("Stop orders require a trigger price".to_string()));
+            }
+            OrderKind::Market | OrderKind::Limit if order.trigger_price.is_some() => {
+                return Err(EngineError::InvalidOrder("Trigger price is only valid for stop orders".to_string()));
+            }
+            _ => {}
+        }
+
+        if order.post_only && (!order.is_limit() || matches!(order.time_in_force, TimeInForce::Ioc | TimeInForce::Fok)) {
+            return Err(EngineError::InvalidOrder("Post-only requires a GTC or GTD limit order".to_string()));
+        }
+
+        if let Some(display_amount) = order.display_amount {
+            if !order.is_limit() || matches!(order.time_in_force, TimeInForce::Ioc | TimeInForce::Fok) {
+                return Err(EngineError::InvalidOrder("Iceberg orders must be GTC or GTD limit orders".to_string()));
+            }
+            if display_amount <= Decimal::ZERO || display_amount >= order.amount {
+                return Err(EngineError::InvalidOrder(
+                    "Display amount must be positive and less than the order amount".to_string(),
+                ));
+            }
+        }
+
+        match (order.time_in_force, order.expires_at) {
+            (TimeInForce::Gtd, None) => Err(EngineError::InvalidOrder(
+                "GTD orders require an expiry time".to_string(),
+            )),
+            // A stop expires out of the trigger book, so only a plain market order has nothing to expire
+            (TimeInForce::Gtd, Some(_)) if order.is_market() => Err(EngineError::InvalidOrder(
+                "GTD is not valid for market orders".to_string(),
+            )),
+            (TimeInForce::Gtc | TimeInForce::Ioc | TimeInForce::Fok, Some(_)) => Err(EngineError::InvalidOrder(
+                "Expiry time is only valid for GTD orders".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Sets the self-trade prevention mode used for a user's orders that do not specify one.
+    pub fn set_self_trade_prevention(&self, user_id: Uuid, mode: SelfTradePrevention) -> EngineResult<()> {
+        let _inputs = self.shared.lock_account_inputs()?;
+        let seq = self.journal_account_input(JournalInput::SetSelfTradePrevention { user_id, mode })?;
+        self.shared.accounts.set_self_trade_prevention(user_id, mode);
+        if let Some(seq) = seq {
+            self.shared.accounts.set_last_input_seq(seq);
+        }
+
+        info!("Self-trade prevention for user {} set to {:?}", user_id, mode);
+        Ok(())
+    }
+
+    pub fn get_self_trade_prevention(&self, user_id: Uuid) -> Option<SelfTradePrevention> {
+        self.shared.accounts.self_trade_prevention(user_id)
+    }
+
+    /// Sets the max fraction a market order on `pair` may trade away from the best price at arrival.
+    pub async fn set_market_collar(&self, pair: &str, collar: Decimal) -> EngineResult<()> {
+        if collar < Decimal::ZERO || collar >= Decimal::ONE {
+            return Err(EngineError::InvalidOrder(format!("Market collar must be in [0, 1): {}", collar)));
+        }
+
+        let pair = pair.to_string();
+        self.run_on(self.shard_of(&pair)?, move |s| {
+            if let Some(orderbook) = s.books.get_mut(&pair) {
+                orderbook.market_collar = collar;
+            }
+        })
+        .await
+    }
+
+    /// Selects how fills at a single price level are shared between resting orders on `pair`.
+    ///
+    /// Shorthand for relisting the pair with its spec's `matching_policy` changed.
+    pub async fn set_matching_policy(&self, pair: &str, policy: MatchingPolicy) -> EngineResult<()> {
+        let spec = self.get_market(pair).ok_or_else(|| EngineError::OrderBookNotFound(pair.to_string()))?;
+        self.add_market(MarketSpec { matching_policy: policy, ..spec }).await
+    }
+
+    /// Sweeps expired GTD orders off every book and returns them. Each one is published as an
+    /// [`crate::events::EventKind::OrderCancelled`] with [`crate::order::CancelReason::Expired`],
+    /// which reaches its owner's private stream.
+    pub async fn expire_orders(&self) -> EngineResult<Vec<Order>> {
+        let now = Utc::now();
+        let expired = self.run_on_all(move |s| s.expire_orders(now)).await?;
+        Ok(expired.into_iter().flatten().collect())
+    }
+
+    pub async fn cancel_order(&self, order_id: Uuid) -> EngineResult<Option<Order>> {
+        let Some(pair) = self.order_pair(order_id) else {
+            warn!("Order {} not found for cancellation", order_id);
+            return Ok(None);
+        };
+
+        self.run_on(self.shard_of(&pair)?, move |s| s.cancel_order(&pair, order_id)).await?
+    }
+
+    pub async fn amend_order(
+        &self,
+        order_id: Uuid,
+        new_amount: Option<Decimal>,
+        new_price: Option<Decimal>,
+    ) -> EngineResult<AmendResult> {
+        let pair = self.order_pair(order_id).ok_or(EngineError::OrderNotFound(order_id))?;
+        let spec = self.get_market(&pair).ok_or_else(|| EngineError::OrderBookNotFound(pair.clone()))?;
+
+        self.run_on(self.shard_of(&pair)?, move |s| s.amend_order(&spec, order_id, new_amount, new_price)).await?
+    }
+
+    /// Current state of a live order, found by id alone.
+    pub async fn get_order(&self, order_id: Uuid) -> EngineResult<Option<Order>> {
+        let Some(pair) = self.order_pair(order_id) else {
+            return Ok(None);
+        };
+        self.run_on(self.shard_of(&pair)?, move |s| s.books.get(&pair).and_then(|book| book.get_order(order_id).cloned()))
+            .await
+    }
+
+    pub async fn get_orderbook(&self, pair: &str) -> EngineResult<Option<OrderBook>> {
+        let Ok(shard) = self.shard_of(pair) else {
+            return Ok(None);
+        };
+        let pair = pair.to_string();
+        self.run_on(shard, move |s| s.books.get(&pair).cloned()).await
+    }
+
+    pub async fn get_orderbook_snapshot(&self, pair: &str) -> EngineResult<Option<OrderBookSnapshot>> {
+        let Ok(shard) = self.shard_of(pair) else {
+            return Ok(None);
+        };
+        let pair = pair.to_string();
+        self.run_on(shard, move |s| s.books.get(&pair).map(book_snapshot)).await
+    }
+
+    pub async fn get_l3_snapshot(&self, pair: &str) -> EngineResult<Option<L3Snapshot>> {
+        let Ok(shard) = self.shard_of(pair) else {
+            return Ok(None);
+        };
+        let pair = pair.to_string();
+        self.run_on(shard, move |s| s.books.get(&pair).map(book_l3_snapshot)).await
+    }
+
+    pub async fn get_ticker(&self, pair: &str) -> EngineResult<Option<Ticker>> {
+        let Ok(shard) = self.shard_of(pair) else {
+            return Ok(None);
+        };
+        let pair = pair.to_string();
+        let now = Utc::now();
+        self.run_on(shard, move |s| s.books.get(&pair).map(|book| Ticker::from_book(book, now))).await
+    }
+
+    /// Tickers for every listed pair, sorted by pair.
+    pub async fn get_tickers(&self) -> EngineResult<Vec<Ticker>> {
+        let now = Utc::now();
+        let mut tickers: Vec<Ticker> = self
+            .run_on_all(move |s| s.books.values().map(|book| Ticker::from_book(book, now)).collect::<Vec<_>>())
+            .await?
+            .into_iter()
+            .flatten()
+            .collect();
+        tickers.sort_by(|a, b| a.pair.cmp(&b.pair));
+        Ok(tickers)
     }
 
     pub fn get_pairs(&self) -> Vec<String> {
-        self.orderbooks.iter().map(|entry| entry.key().clone()).collect()
+        self.shared.markets.iter().map(|entry| entry.key().clone()).collect()
+    }
+}
+
+/// Wraps `task` so its result is sent back over a oneshot channel.
+fn reply_task<T: Send + 'static>(
+    task: impl FnOnce(&mut Shard) -> T + Send + 'static,
+) -> (ShardTask, oneshot::Receiver<T>) {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let task: ShardTask = Box::new(move |shard| {
+        let _ = reply_tx.send(task(shard));
+    });
+    (task, reply_rx)
+}
+
+/// An empty book set up with a market's rules.
+fn new_book(spec: &MarketSpec) -> OrderBook {
+    let mut orderbook = OrderBook::new(spec.pair.clone());
+    orderbook.tick_size = spec.tick_size;
+    orderbook.lot_size = spec.quantity_step;
+    orderbook.fees = Arc::new(spec.fee_schedule());
+    orderbook.matching_policy = spec.matching_policy.clone();
+    orderbook
+}
+
+/// Aggregated price levels of a book, as shown to clients.
+fn book_snapshot(orderbook: &OrderBook) -> OrderBookSnapshot {
+    OrderBookSnapshot {
+        pair: orderbook.pair.clone(),
+        bids: orderbook.depth(&OrderType::Buy),
+        asks: orderbook.depth(&OrderType::Sell),
+        best_bid: orderbook.get_best_bid(),
+        best_ask: orderbook.get_best_ask(),
+        spread: orderbook.get_spread(),
+        seq: orderbook.depth_seq,
+    }
+}
+
+/// Every resting order of a book in queue order, as shown to clients.
+fn book_l3_snapshot(orderbook: &OrderBook) -> L3Snapshot {
+    let levels = |levels: &BTreeMap<Decimal, VecDeque<Order>>| {
+        levels
+            .iter()
+            .map(|(price, orders)| L3Level {
+                price: *price,
+                orders: orders.iter().map(|o| (o.queue_seq, o.visible_remaining())).collect(),
+            })
+            .collect()
+    };
+
+    L3Snapshot {
+        pair: orderbook.pair.clone(),
+        bids: levels(&orderbook.bids),
+        asks: levels(&orderbook.asks),
+        seq: orderbook.l3_seq,
     }
 }
 
@@ -188,4 +929,102 @@ pub struct OrderBookSnapshot {
     pub best_bid: Option<rust_decimal::Decimal>,
     pub best_ask: Option<rust_decimal::Decimal>,
     pub spread: Option<rust_decimal::Decimal>,
+    /// Last level change the snapshot reflects; depth updates carry on from `seq + 1`.
+    pub seq: u64,
+}
+
+/// Order-by-order view of a book.
+#[derive(Debug, Clone)]
+pub struct L3Snapshot {
+    pub pair: String,
+    /// Levels from the lowest price up.
+    pub bids: Vec<L3Level>,
+    pub asks: Vec<L3Level>,
+    /// Last order change the snapshot reflects; L3 updates carry on from `seq + 1`.
+    pub seq: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct L3Level {
+    pub price: Decimal,
+    /// (handle, visible size) of each order, front of the queue first.
+    pub orders: Vec<(u64, Decimal)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+
+    const PAIR: &str = "BTC/USDT";
+
+    fn limit(user_id: Uuid, order_type: OrderType, price: i64) -> Order {
+        Order::new(user_id, PAIR.to_string(), order_type, OrderKind::Limit, Decimal::ONE, Some(price.into()))
+    }
+
+    #[tokio::test]
+    async fn process_answers_each_message_with_its_reply() {
+        let engine = OrderEngine::new(1);
+        let user = Uuid::new_v4();
+
+        let deposit = EngineMessage::Deposit { user_id: user, asset: "USDT".to_string(), amount: Decimal::from(1_000) };
+        let Ok(EngineReply::Deposit(balance)) = engine.process(deposit).await else { panic!("expected a deposit reply") };
+        assert_eq!(balance.available, Decimal::from(1_000));
+
+        let order = limit(user, OrderType::Buy, 100);
+        let Ok(EngineReply::NewOrder(response)) = engine.process(EngineMessage::NewOrder(order.clone())).await else {
+            panic!("expected a new order reply")
+        };
+        assert!(response.trades.is_empty());
+        assert_eq!(response.updated_order.map(|o| o.id), Some(order.id));
+
+        let Ok(EngineReply::GetOrderBook(Some(book))) = engine.process(EngineMessage::GetOrderBook(PAIR.to_string())).await else {
+            panic!("expected the book")
+        };
+        assert_eq!(book.bids, vec![(Decimal::from(100), Decimal::ONE)]);
+        assert_eq!(book.best_bid, Some(Decimal::from(100)));
+
+        let Ok(EngineReply::CancelOrder(Some(cancelled))) = engine.process(EngineMessage::CancelOrder(order.id)).await else {
+            panic!("expected the cancelled order")
+        };
+        assert_eq!(cancelled.id, order.id);
+        let Ok(EngineReply::GetBalances(balances)) = engine.process(EngineMessage::GetBalances(user)).await else {
+            panic!("expected balances")
+        };
+        assert_eq!(balances["USDT"].available, Decimal::from(1_000));
+        assert_eq!(balances["USDT"].locked, Decimal::ZERO);
+    }
+
+    #[tokio::test]
+    async fn full_worker_queue_fails_fast_with_overloaded() {
+        let engine = Arc::new(OrderEngine::with_queue_capacity(1, 1));
+
+        // Park the worker inside a task, then fill its one queue slot behind it
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let parked = tokio::spawn({
+            let engine = Arc::clone(&engine);
+            async move {
+                engine
+                    .run_on(0, move |_| {
+                        started_tx.send(()).unwrap();
+                        release_rx.recv().unwrap();
+                    })
+                    .await
+            }
+        });
+        tokio::task::spawn_blocking(move || started_rx.recv().unwrap()).await.unwrap();
+        assert!(engine.run_on(0, |_| ()).now_or_never().is_none());
+
+        let rejected = engine.process(EngineMessage::GetOrderBook(PAIR.to_string())).await;
+        assert!(matches!(rejected, Err(EngineError::Overloaded)));
+
+        // Once the worker catches up it takes requests again
+        release_tx.send(()).unwrap();
+        parked.await.unwrap().unwrap();
+        assert!(matches!(
+            engine.process(EngineMessage::GetOrderBook(PAIR.to_string())).await,
+            Ok(EngineReply::GetOrderBook(Some(_)))
+        ));
+    }
 }
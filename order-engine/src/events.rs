@@ -0,0 +1,430 @@
+use crate::candles::Candle;
+use crate::order::{Fill, Order, OrderStatus, OrderType, SelfTradePrevented, Trade};
+use crate::orderbook::{AmendResult, MatchResult, OrderBook, OrderChange};
+use crate::ticker::Ticker;
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+/// Events a subscriber can fall behind by before it starts missing them.
+pub const DEFAULT_EVENT_CAPACITY: usize = 16384;
+
+/// Something that happened on a pair's book.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EngineEvent {
+    pub pair: String,
+    /// Increases by one with every event for `pair`, with no gaps.
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    OrderAccepted {
+        order: Order,
+    },
+    /// The book turned the order down, e.g. a post-only order that would have crossed.
+    OrderRejected {
+        order: Order,
+    },
+    OrderPartiallyFilled {
+        fill: Fill,
+    },
+    OrderFilled {
+        fill: Fill,
+    },
+    OrderCancelled {
+        order: Order,
+    },
+    /// A resting order's amount or price was changed. `order` already reflects whatever an
+    /// amend that crossed the book traded.
+    OrderAmended {
+        order: Order,
+        old_amount: Decimal,
+        old_price: Decimal,
+        new_amount: Decimal,
+        new_price: Decimal,
+        /// Whether the order kept its place in the queue, see [`crate::orderbook::AmendResult`].
+        kept_priority: bool,
+    },
+    TradeExecuted {
+        trade: Trade,
+    },
+    /// Self-trade prevention stopped an order from trading with another order of the same user.
+    SelfTradePrevented {
+        prevented: SelfTradePrevented,
+    },
+    /// New aggregate visible size at a price level; zero when the level is gone.
+    BookLevelChanged {
+        side: OrderType,
+        price: Decimal,
+        amount: Decimal,
+        /// The book's depth sequence after this change, see [`OrderBook::depth_seq`].
+        depth_seq: u64,
+    },
+    /// A resting order was added, reduced or removed.
+    BookOrderChanged {
+        change: OrderChange,
+        /// The book's L3 sequence after this change, see [`OrderBook::l3_seq`].
+        l3_seq: u64,
+    },
+    /// The last price or the top of the book moved.
+    TickerChanged {
+        ticker: Ticker,
+    },
+    /// A candle took in trades or closed.
+    CandleUpdated {
+        candle: Candle,
+    },
+}
+
+/// Fans engine events out to any number of subscribers.
+///
+/// Publishing never waits: a subscriber that falls more than the bus capacity behind gets
+/// `RecvError::Lagged` and resumes from the oldest event still held, so a slow consumer can
+/// never hold up matching.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<EngineEvent>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity.max(1));
+        Self { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<EngineEvent> {
+        self.sender.subscribe()
+    }
+
+    pub fn publish(&self, events: Vec<EngineEvent>) {
+        for event in events {
+            // Nobody listening is not an error
+            let _ = self.sender.send(event);
+        }
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(DEFAULT_EVENT_CAPACITY)
+    }
+}
+
+/// Numbers events for one book as they are produced.
+struct EventSeq<'a> {
+    orderbook: &'a mut OrderBook,
+    timestamp: DateTime<Utc>,
+    events: Vec<EngineEvent>,
+    traded: bool,
+}
+
+impl<'a> EventSeq<'a> {
+    fn new(orderbook: &'a mut OrderBook, timestamp: DateTime<Utc>) -> Self {
+        Self {
+            orderbook,
+            timestamp,
+            events: Vec::new(),
+            traded: false,
+        }
+    }
+
+    fn push(&mut self, kind: EventKind) {
+        self.orderbook.event_seq += 1;
+        self.events.push(EngineEvent {
+            pair: self.orderbook.pair.clone(),
+            seq: self.orderbook.event_seq,
+            timestamp: self.timestamp,
+            kind,
+        });
+    }
+
+    fn push_match(&mut self, result: &MatchResult) {
+        self.traded |= !result.trades.is_empty();
+        for (trade, fills) in result.trades.iter().zip(result.fills.chunks(2)) {
+            self.push(EventKind::TradeExecuted { trade: trade.clone() });
+            for fill in fills {
+                self.push(fill_event(fill));
+            }
+        }
+
+        for prevented in &result.self_trades_prevented {
+            self.push(EventKind::SelfTradePrevented { prevented: prevented.clone() });
+        }
+
+        for order in &result.cancelled {
+            self.push(EventKind::OrderCancelled { order: order.clone() });
+        }
+
+        for order in &result.triggered {
+            self.push_final_status(order);
+        }
+    }
+
+    fn push_final_status(&mut self, order: &Order) {
+        match order.status {
+            OrderStatus::Cancelled => self.push(EventKind::OrderCancelled { order: order.clone() }),
+            OrderStatus::Rejected => self.push(EventKind::OrderRejected { order: order.clone() }),
+            _ => {}
+        }
+    }
+
+    /// Closes off with the orders and price levels the operation changed, and the ticker if it
+    /// moved.
+    fn finish(mut self) -> Vec<EngineEvent> {
+        for change in self.orderbook.take_order_changes() {
+            self.orderbook.l3_seq += 1;
+            let l3_seq = self.orderbook.l3_seq;
+            self.push(EventKind::BookOrderChanged { change, l3_seq });
+        }
+
+        let best_bid = self.orderbook.get_best_bid();
+        let best_ask = self.orderbook.get_best_ask();
+        let mut top_changed = false;
+
+        for (side, price, amount) in self.orderbook.take_changed_levels() {
+            // A level at or inside the current best is either the best itself or one that just went
+            top_changed |= match side {
+                OrderType::Buy => best_bid.is_none_or(|bid| price >= bid),
+                OrderType::Sell => best_ask.is_none_or(|ask| price <= ask),
+            };
+            self.orderbook.depth_seq += 1;
+            let depth_seq = self.orderbook.depth_seq;
+            self.push(EventKind::BookLevelChanged { side, price, amount, depth_seq });
+        }
+
+        if self.traded || top_changed {
+            let ticker = Ticker::from_book(self.orderbook, self.timestamp);
+            self.push(EventKind::TickerChanged { ticker });
+        }
+
+        self.events
+    }
+}
+
+fn fill_event(fill: &Fill) -> EventKind {
+    if fill.remaining.is_zero() {
+        EventKind::OrderFilled { fill: fill.clone() }
+    } else {
+        EventKind::OrderPartiallyFilled { fill: fill.clone() }
+    }
+}
+
+/// Events for a new order: accepted or rejected, then its trades and fills, any self-trades it
+/// was kept from, anything it took off the book, its own cancellation if it did not rest, and
+/// finally the level changes.
+pub(crate) fn new_order_events(orderbook: &mut OrderBook, result: &MatchResult, timestamp: DateTime<Utc>) -> Vec<EngineEvent> {
+    let mut events = EventSeq::new(orderbook, timestamp);
+
+    if result.order.status == OrderStatus::Rejected {
+        events.push(EventKind::OrderRejected { order: result.order.clone() });
+    } else {
+        events.push(EventKind::OrderAccepted { order: result.order.clone() });
+    }
+    events.push_match(result);
+    if result.order.status == OrderStatus::Cancelled {
+        events.push(EventKind::OrderCancelled { order: result.order.clone() });
+    }
+
+    events.finish()
+}
+
+/// Events for an amend: the amend itself, trades if the amended order crossed, then the level
+/// changes.
+pub(crate) fn amend_events(orderbook: &mut OrderBook, amend: &AmendResult, timestamp: DateTime<Utc>) -> Vec<EngineEvent> {
+    let mut events = EventSeq::new(orderbook, timestamp);
+    let result = &amend.result;
+    events.push(EventKind::OrderAmended {
+        order: result.order.clone(),
+        old_amount: amend.old_amount,
+        old_price: amend.old_price,
+        new_amount: result.order.amount,
+        new_price: result.order.price.unwrap_or(amend.old_price),
+        kept_priority: amend.kept_priority,
+    });
+    events.push_match(result);
+    events.push_final_status(&result.order);
+    events.finish()
+}
+
+/// Events for orders taken off the book by a cancel or an expiry.
+pub(crate) fn cancel_events(orderbook: &mut OrderBook, orders: &[Order], timestamp: DateTime<Utc>) -> Vec<EngineEvent> {
+    let mut events = EventSeq::new(orderbook, timestamp);
+    for order in orders {
+        events.push(EventKind::OrderCancelled { order: order.clone() });
+    }
+    events.finish()
+}
+
+/// Events for candles that trades changed or time closed, see [`crate::candles::CandleAggregator`].
+pub(crate) fn candle_events(orderbook: &mut OrderBook, candles: &[Candle], timestamp: DateTime<Utc>) -> Vec<EngineEvent> {
+    let mut events = EventSeq::new(orderbook, timestamp);
+    for candle in candles {
+        events.push(EventKind::CandleUpdated { candle: candle.clone() });
+    }
+    events.events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order::{OrderKind, SelfTradePrevention};
+    use tokio::sync::broadcast::error::TryRecvError;
+    use uuid::Uuid;
+
+    fn limit(user_id: Uuid, order_type: OrderType, price: i64) -> Order {
+        Order::new(user_id, "BTC/USDT".to_string(), order_type, OrderKind::Limit, Decimal::ONE, Some(price.into()))
+    }
+
+    fn kinds(events: &[EngineEvent]) -> Vec<&'static str> {
+        events
+            .iter()
+            .map(|event| match event.kind {
+                EventKind::OrderAccepted { .. } => "accepted",
+                EventKind::OrderRejected { .. } => "rejected",
+                EventKind::OrderPartiallyFilled { .. } => "partially_filled",
+                EventKind::OrderFilled { .. } => "filled",
+                EventKind::OrderCancelled { .. } => "cancelled",
+                EventKind::OrderAmended { .. } => "amended",
+                EventKind::TradeExecuted { .. } => "trade",
+                EventKind::SelfTradePrevented { .. } => "self_trade_prevented",
+                EventKind::BookLevelChanged { .. } => "level",
+                EventKind::BookOrderChanged { .. } => "order_change",
+                EventKind::TickerChanged { .. } => "ticker",
+                EventKind::CandleUpdated { .. } => "candle",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn prevented_self_trades_are_published() {
+        let user = Uuid::new_v4();
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        let maker = book.add_order(limit(user, OrderType::Sell, 100)).order;
+        book.take_changed_levels();
+        book.take_order_changes();
+
+        let mut taker = limit(user, OrderType::Buy, 100);
+        taker.self_trade_prevention = Some(SelfTradePrevention::CancelNewest);
+        let result = book.add_order(taker);
+        let events = new_order_events(&mut book, &result, Utc::now());
+
+        assert_eq!(kinds(&events)[..3], ["accepted", "self_trade_prevented", "cancelled"]);
+        let EventKind::SelfTradePrevented { prevented } = &events[1].kind else {
+            unreachable!()
+        };
+        assert_eq!((prevented.taker_order_id, prevented.maker_order_id), (result.order.id, maker.id));
+        assert_eq!(prevented.mode, SelfTradePrevention::CancelNewest);
+    }
+
+    #[test]
+    fn amends_publish_the_old_and_new_terms() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        let mut order = limit(Uuid::new_v4(), OrderType::Buy, 100);
+        order.amount = Decimal::from(3);
+        let order = book.add_order(order).order;
+
+        // A size reduction keeps priority and trades nothing, but still gets an event
+        let amend = book.amend_order(order.id, Some(Decimal::from(2)), None).unwrap();
+        let events = amend_events(&mut book, &amend, Utc::now());
+        let EventKind::OrderAmended { old_amount, old_price, new_amount, new_price, kept_priority, .. } = &events[0].kind else {
+            panic!("expected an order_amended event first, got {:?}", kinds(&events));
+        };
+        assert_eq!((*old_amount, *old_price), (Decimal::from(3), Decimal::from(100)));
+        assert_eq!((*new_amount, *new_price), (Decimal::from(2), Decimal::from(100)));
+        assert!(*kept_priority);
+
+        let amend = book.amend_order(order.id, None, Some(Decimal::from(101))).unwrap();
+        let events = amend_events(&mut book, &amend, Utc::now());
+        let EventKind::OrderAmended { old_price, new_price, kept_priority, .. } = &events[0].kind else {
+            panic!("expected an order_amended event first, got {:?}", kinds(&events));
+        };
+        assert_eq!((*old_price, *new_price), (Decimal::from(100), Decimal::from(101)));
+        assert!(!*kept_priority);
+    }
+
+    #[test]
+    fn a_crossing_order_publishes_its_trades_fills_and_levels_in_sequence() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        let maker = book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 100));
+        let resting = new_order_events(&mut book, &maker, Utc::now());
+        assert_eq!(kinds(&resting), ["accepted", "order_change", "level", "ticker"]);
+
+        let mut taker = limit(Uuid::new_v4(), OrderType::Buy, 100);
+        taker.amount = Decimal::from(2);
+        let result = book.add_order(taker);
+        let events = new_order_events(&mut book, &result, Utc::now());
+
+        assert_eq!(kinds(&events)[..2], ["accepted", "trade"]);
+        let mut fills = kinds(&events)[2..4].to_vec();
+        fills.sort();
+        assert_eq!(fills, ["filled", "partially_filled"]);
+        assert!(events.iter().any(|event| matches!(
+            event.kind,
+            EventKind::BookLevelChanged { side: OrderType::Sell, amount, .. } if amount.is_zero()
+        )));
+        assert!(events.iter().any(|event| matches!(event.kind, EventKind::BookLevelChanged { side: OrderType::Buy, .. })));
+
+        // One gapless sequence per pair, carried across operations
+        let seqs: Vec<u64> = resting.iter().chain(&events).map(|event| event.seq).collect();
+        assert_eq!(seqs, (1..=seqs.len() as u64).collect::<Vec<_>>());
+        assert_eq!(book.event_seq, seqs.len() as u64);
+    }
+
+    #[test]
+    fn an_order_the_book_turns_down_is_published_as_rejected() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 100));
+        book.take_changed_levels();
+        book.take_order_changes();
+
+        let mut order = limit(Uuid::new_v4(), OrderType::Buy, 100);
+        order.post_only = true;
+        let result = book.add_order(order);
+        let events = new_order_events(&mut book, &result, Utc::now());
+
+        assert_eq!(kinds(&events), ["rejected"]);
+    }
+
+    #[test]
+    fn cancels_publish_the_order_and_the_emptied_level() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        let order = book.add_order(limit(Uuid::new_v4(), OrderType::Buy, 100)).order;
+        book.take_changed_levels();
+        book.take_order_changes();
+
+        let cancelled = book.cancel_order(order.id).unwrap();
+        let events = cancel_events(&mut book, &[cancelled], Utc::now());
+
+        assert_eq!(kinds(&events), ["cancelled", "order_change", "level", "ticker"]);
+        assert!(matches!(events[2].kind, EventKind::BookLevelChanged { amount, .. } if amount.is_zero()));
+    }
+
+    #[test]
+    fn lagging_subscribers_skip_ahead_instead_of_blocking_the_bus() {
+        let bus = EventBus::new(2);
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        let mut numbered = |count: usize| {
+            let mut events = EventSeq::new(&mut book, Utc::now());
+            for _ in 0..count {
+                events.push(EventKind::OrderCancelled { order: limit(Uuid::new_v4(), OrderType::Buy, 100) });
+            }
+            events.events
+        };
+
+        // Nobody listening yet is fine
+        bus.publish(numbered(1));
+
+        let mut subscriber = bus.subscribe();
+        bus.publish(numbered(3));
+        assert!(matches!(subscriber.try_recv(), Err(TryRecvError::Lagged(1))));
+        assert_eq!(subscriber.try_recv().unwrap().seq, 3);
+        assert_eq!(subscriber.try_recv().unwrap().seq, 4);
+        assert!(matches!(subscriber.try_recv(), Err(TryRecvError::Empty)));
+    }
+}
@@ -0,0 +1,180 @@
+use crate::order::{OrderType, Trade};
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Account every trading fee is paid into and every maker rebate is paid out of.
+///
+/// Rebates and fees on one trade can be in different assets, so its balance in a single asset
+/// may go negative.
+pub const FEE_ACCOUNT_ID: Uuid = Uuid::nil();
+
+/// What one fee tier pays on a pair, as fractions of what each side receives.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct FeeRates {
+    /// Rate for the order that was resting on the book. Negative for a rebate.
+    pub maker: Decimal,
+    /// Rate for the order that took liquidity.
+    pub taker: Decimal,
+}
+
+impl FeeRates {
+    pub fn new(maker: Decimal, taker: Decimal) -> Self {
+        Self { maker, taker }
+    }
+
+    /// The rates pairs are listed with for VIP0 to VIP9, see
+    /// [`crate::volume::FeeTierSchedule`].
+    pub fn default_tiers() -> Vec<FeeRates> {
+        vec![
+            FeeRates::new(Decimal::new(10, 4), Decimal::new(10, 4)),
+            FeeRates::new(Decimal::new(9, 4), Decimal::new(10, 4)),
+            FeeRates::new(Decimal::new(8, 4), Decimal::new(9, 4)),
+            FeeRates::new(Decimal::new(7, 4), Decimal::new(8, 4)),
+            FeeRates::new(Decimal::new(6, 4), Decimal::new(7, 4)),
+            FeeRates::new(Decimal::new(4, 4), Decimal::new(6, 4)),
+            FeeRates::new(Decimal::new(2, 4), Decimal::new(5, 4)),
+            FeeRates::new(Decimal::ZERO, Decimal::new(4, 4)),
+            FeeRates::new(Decimal::new(-5, 5), Decimal::new(35, 5)),
+            FeeRates::new(Decimal::new(-1, 4), Decimal::new(3, 4)),
+        ]
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.taker < Decimal::ZERO || self.taker >= Decimal::ONE {
+            return Err(format!("Taker fee rate {} must be in [0, 1)", self.taker));
+        }
+        if self.maker <= -Decimal::ONE || self.maker >= Decimal::ONE {
+            return Err(format!("Maker fee rate {} must be in (-1, 1)", self.maker));
+        }
+        if self.maker + self.taker < Decimal::ZERO {
+            return Err(format!("Maker rebate {} is larger than the taker fee {}", -self.maker, self.taker));
+        }
+        Ok(())
+    }
+}
+
+/// Decimal places balances of an asset are kept to unless its market says otherwise.
+pub const DEFAULT_ASSET_PRECISION: u32 = 8;
+
+/// Fees a book charges on its trades.
+///
+/// Each side pays out of the asset it receives, base for the buyer and quote for the seller, so
+/// an order never needs more locked than the trade itself costs.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeeSchedule {
+    pub base_asset: String,
+    pub quote_asset: String,
+    /// Decimal places fees in each asset are rounded to.
+    pub base_precision: u32,
+    pub quote_precision: u32,
+    /// Rates by fee tier. Tiers past the end pay the last rates; no tiers means no fees.
+    pub tiers: Vec<FeeRates>,
+}
+
+impl FeeSchedule {
+    pub fn new(base_asset: &str, quote_asset: &str, tiers: Vec<FeeRates>) -> Self {
+        Self {
+            base_asset: base_asset.to_string(),
+            quote_asset: quote_asset.to_string(),
+            base_precision: DEFAULT_ASSET_PRECISION,
+            quote_precision: DEFAULT_ASSET_PRECISION,
+            tiers,
+        }
+    }
+
+    pub fn rates(&self, tier: u8) -> FeeRates {
+        self.tiers
+            .get(tier as usize)
+            .or(self.tiers.last())
+            .copied()
+            .unwrap_or(FeeRates::new(Decimal::ZERO, Decimal::ZERO))
+    }
+
+    /// Records on `trade` what its maker and taker pay, at the rates of the tiers their orders
+    /// were placed in.
+    ///
+    /// Each fee is rounded to its asset's precision towards positive infinity: a fee up, a rebate
+    /// towards zero. Whatever rounding adds is paid into [`FEE_ACCOUNT_ID`] with the fee, so no
+    /// balance ever holds dust finer than its asset allows.
+    pub fn charge(&self, trade: &mut Trade, maker_tier: u8, taker_tier: u8) {
+        let maker_rate = self.rates(maker_tier).maker;
+        let taker_rate = self.rates(taker_tier).taker;
+        let (buyer_rate, seller_rate) = match trade.maker_side {
+            OrderType::Buy => (maker_rate, taker_rate),
+            OrderType::Sell => (taker_rate, maker_rate),
+        };
+        let buyer_fee = (round_fee(trade.amount * buyer_rate, self.base_precision), self.base_asset.clone());
+        let seller_fee = (round_fee(trade.amount * trade.price * seller_rate, self.quote_precision), self.quote_asset.clone());
+
+        let ((maker_fee, maker_fee_asset), (taker_fee, taker_fee_asset)) = match trade.maker_side {
+            OrderType::Buy => (buyer_fee, seller_fee),
+            OrderType::Sell => (seller_fee, buyer_fee),
+        };
+        trade.maker_rate = maker_rate;
+        trade.taker_rate = taker_rate;
+        trade.maker_fee = maker_fee;
+        trade.maker_fee_asset = maker_fee_asset;
+        trade.taker_fee = taker_fee;
+        trade.taker_fee_asset = taker_fee_asset;
+    }
+}
+
+/// `fee` to `precision` decimal places, rounded in the fee account's favour.
+fn round_fee(fee: Decimal, precision: u32) -> Decimal {
+    fee.round_dp_with_strategy(precision, RoundingStrategy::ToPositiveInfinity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order::{Order, OrderKind};
+    use chrono::Utc;
+
+    fn trade(amount: Decimal, price: Decimal, maker_side: OrderType) -> Trade {
+        let order = |order_type| Order::new(Uuid::new_v4(), "BTC/USDT".to_string(), order_type, OrderKind::Limit, amount, Some(price));
+        Trade::new(&order(OrderType::Buy), &order(OrderType::Sell), amount, price, maker_side, Utc::now())
+    }
+
+    fn schedule(maker: Decimal, taker: Decimal) -> FeeSchedule {
+        FeeSchedule {
+            base_precision: 8,
+            quote_precision: 2,
+            ..FeeSchedule::new("BTC", "USDT", vec![FeeRates::new(maker, taker)])
+        }
+    }
+
+    #[test]
+    fn fees_are_rounded_up_to_the_asset_precision() {
+        // 0.123 * 100.01 * 0.001 = 0.01230123 USDT, and 0.123 * 0.001 = 0.000123 BTC
+        let mut trade = trade(Decimal::new(123, 3), Decimal::new(10_001, 2), OrderType::Sell);
+        schedule(Decimal::new(1, 3), Decimal::new(1, 3)).charge(&mut trade, 0, 0);
+
+        assert_eq!((trade.maker_fee, trade.maker_fee_asset.as_str()), (Decimal::new(2, 2), "USDT"));
+        assert_eq!((trade.taker_fee, trade.taker_fee_asset.as_str()), (Decimal::new(123, 6), "BTC"));
+        assert_eq!(trade.maker_rate, Decimal::new(1, 3));
+    }
+
+    #[test]
+    fn rebates_are_rounded_towards_zero() {
+        // The buyer makes and is owed 0.123 * -0.0005 = -0.0000615 BTC; the seller takes and pays 0.01230123 USDT
+        let mut trade = trade(Decimal::new(123, 3), Decimal::new(10_001, 2), OrderType::Buy);
+        FeeSchedule {
+            base_precision: 6,
+            ..schedule(Decimal::new(-5, 4), Decimal::new(1, 3))
+        }
+        .charge(&mut trade, 0, 0);
+
+        assert_eq!(trade.maker_fee, Decimal::new(-61, 6));
+        assert_eq!(trade.taker_fee, Decimal::new(2, 2));
+    }
+
+    #[test]
+    fn fees_already_at_the_precision_are_left_alone() {
+        let mut trade = trade(Decimal::ONE, Decimal::from(100), OrderType::Sell);
+        schedule(Decimal::new(1, 3), Decimal::new(2, 3)).charge(&mut trade, 0, 0);
+
+        assert_eq!(trade.maker_fee, Decimal::new(1, 1));
+        assert_eq!(trade.taker_fee, Decimal::new(2, 3));
+    }
+}
@@ -0,0 +1,704 @@
+use crate::order::{Order, SelfTradePrevention};
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+/// Bytes in front of every record: payload length then CRC-32 of the payload, both little endian.
+const RECORD_HEADER_LEN: usize = 8;
+
+/// Upper bound on a single payload, anything larger is treated as a corrupt length.
+const MAX_RECORD_LEN: usize = 16 * 1024 * 1024;
+
+/// An input the engine accepted, recorded before it is applied to a book.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JournalInput {
+    NewOrder {
+        order: Order,
+    },
+    CancelOrder {
+        order_id: Uuid,
+    },
+    AmendOrder {
+        order_id: Uuid,
+        // Only written as strings: `str_option` cannot read the null a tagged enum buffers, and
+        // `Decimal` reads strings exactly anyway
+        #[serde(serialize_with = "rust_decimal::serde::str_option::serialize")]
+        new_amount: Option<Decimal>,
+        #[serde(serialize_with = "rust_decimal::serde::str_option::serialize")]
+        new_price: Option<Decimal>,
+    },
+    Deposit {
+        user_id: Uuid,
+        asset: String,
+        #[serde(with = "rust_decimal::serde::str")]
+        amount: Decimal,
+    },
+    Withdraw {
+        user_id: Uuid,
+        asset: String,
+        #[serde(with = "rust_decimal::serde::str")]
+        amount: Decimal,
+    },
+    SetFeeTier {
+        user_id: Uuid,
+        tier: u8,
+    },
+    SetSelfTradePrevention {
+        user_id: Uuid,
+        mode: SelfTradePrevention,
+    },
+    /// A sweep of the GTD orders in `pair` that had expired by the record's timestamp.
+    ExpireOrders {
+        pair: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalRecord {
+    pub seq: u64,
+    /// Time the input was applied, used again on replay so expiries line up.
+    pub timestamp: DateTime<Utc>,
+    pub input: JournalInput,
+}
+
+/// When appended records are forced to disk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    /// fsync after every record. Nothing acknowledged is ever lost.
+    Always,
+    /// fsync once every `n` records. A crash can lose up to `n - 1` acknowledged inputs.
+    Batch(u32),
+    /// Leave flushing to the OS. Survives a process crash but not a power loss.
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => match s.strip_prefix("batch:").map(str::parse::<u32>) {
+                Some(Ok(n)) if n > 0 => Ok(FsyncPolicy::Batch(n)),
+                _ => Err(format!("Invalid fsync policy: {} (expected always, never or batch:<n>)", s)),
+            },
+        }
+    }
+}
+
+/// Append-only log of accepted engine inputs.
+///
+/// Each record is framed as `[len: u32][crc32: u32][json payload]`. A record that is cut short or
+/// fails its checksum marks the end of the usable log, and everything from it on is truncated
+/// when the journal is opened.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    policy: FsyncPolicy,
+    next_seq: u64,
+    unsynced: u32,
+}
+
+impl Journal {
+    /// Opens (or creates) the journal at `path` and returns it with every intact record.
+    pub fn open(path: impl AsRef<Path>, policy: FsyncPolicy) -> io::Result<(Self, Vec<JournalRecord>)> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+        let file_len = file.metadata()?.len();
+        let (records, valid_len) = read_records(&mut file)?;
+
+        if valid_len < file_len {
+            warn!(
+                "Truncating {} bytes of torn or corrupt records from the end of journal {}",
+                file_len - valid_len,
+                path.display()
+            );
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+
+        let next_seq = records.last().map_or(1, |record| record.seq + 1);
+        info!("Opened journal {} with {} records", path.display(), records.len());
+
+        Ok((
+            Self {
+                path,
+                file,
+                policy,
+                next_seq,
+                unsynced: 0,
+            },
+            records,
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Sequence number of the last record written, 0 when the journal is empty.
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    /// Makes sure the next record is numbered after `seq`.
+    pub fn skip_to(&mut self, seq: u64) {
+        self.next_seq = self.next_seq.max(seq + 1);
+    }
+
+    /// Appends one input and syncs it according to the fsync policy.
+    pub fn append(&mut self, timestamp: DateTime<Utc>, input: JournalInput) -> io::Result<u64> {
+        let record = JournalRecord {
+            seq: self.next_seq,
+            timestamp,
+            input,
+        };
+        self.append_batch(std::slice::from_ref(&record))?;
+        Ok(record.seq)
+    }
+
+    /// Appends already numbered records with a single write, then syncs according to the fsync
+    /// policy. If the write fails, whatever part of it reached the file is cut off again so the
+    /// records after it are not lost behind a torn one.
+    fn append_batch(&mut self, records: &[JournalRecord]) -> io::Result<()> {
+        let frames = encode_records(records)?;
+        let len_before = self.file.metadata()?.len();
+        if let Err(e) = self.file.write_all(&frames) {
+            let _ = self.file.set_len(len_before);
+            return Err(e);
+        }
+
+        self.unsynced += records.len() as u32;
+        let sync = match self.policy {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Batch(n) => self.unsynced >= n,
+            FsyncPolicy::Never => false,
+        };
+        if sync {
+            self.sync()?;
+        }
+
+        if let Some(last) = records.last() {
+            self.next_seq = self.next_seq.max(last.seq + 1);
+        }
+        Ok(())
+    }
+
+    /// Forces everything appended so far to disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced > 0 {
+            self.file.sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    /// Drops every record numbered `through_seq` or lower and returns how many went.
+    ///
+    /// Meant for records a snapshot already reflects. The records kept are written to a new
+    /// file that then replaces the journal, the same way a snapshot is written, so a crash part
+    /// way leaves either the old journal or the new one. Numbering carries on where it was.
+    pub fn compact(&mut self, through_seq: u64) -> io::Result<usize> {
+        self.sync()?;
+        self.file.seek(SeekFrom::Start(0))?;
+        let (records, _) = read_records(&mut self.file)?;
+        let total = records.len();
+        let kept: Vec<JournalRecord> = records.into_iter().filter(|record| record.seq > through_seq).collect();
+        let dropped = total - kept.len();
+        if dropped == 0 {
+            return Ok(0);
+        }
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut tmp_file = File::create(&tmp_path)?;
+        tmp_file.write_all(&encode_records(&kept)?)?;
+        tmp_file.sync_all()?;
+        drop(tmp_file);
+
+        std::fs::rename(&tmp_path, &self.path)?;
+        if let Some(dir) = self.path.parent().and_then(|dir| File::open(dir).ok()) {
+            // Persist the rename itself; not every platform can sync a directory
+            let _ = dir.sync_all();
+        }
+        self.file = OpenOptions::new().read(true).append(true).open(&self.path)?;
+
+        info!(
+            "Compacted journal {}: dropped {} records through seq {}, kept {}",
+            self.path.display(),
+            dropped,
+            through_seq,
+            kept.len()
+        );
+        Ok(dropped)
+    }
+}
+
+/// Frames `records` for the journal file, one after another.
+fn encode_records(records: &[JournalRecord]) -> io::Result<Vec<u8>> {
+    let mut frames = Vec::new();
+    for record in records {
+        let payload = serde_json::to_vec(record).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        frames.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frames.extend_from_slice(&crc32(&payload).to_le_bytes());
+        frames.extend_from_slice(&payload);
+    }
+    Ok(frames)
+}
+
+/// Group commit front end to a [`Journal`], shared by every shard.
+///
+/// Callers hold a lock only long enough to number a record and queue it, so shards never wait
+/// on each other's writes or fsyncs. A writer thread appends whatever has queued up in a single
+/// write and syncs the batch once, as the fsync policy asks. Each caller still waits for its
+/// own record to be written (and synced, under [`FsyncPolicy::Always`]) before it applies the
+/// input, so nothing is acknowledged that the journal does not hold.
+pub struct JournalWriter {
+    path: PathBuf,
+    queue: Mutex<WriteQueue>,
+}
+
+/// Next sequence number and the writer thread's inbox, locked together so records are queued
+/// in sequence order.
+struct WriteQueue {
+    next_seq: u64,
+    requests: mpsc::Sender<WriteRequest>,
+}
+
+enum WriteRequest {
+    Append(Box<JournalRecord>, mpsc::SyncSender<io::Result<()>>),
+    Sync(mpsc::SyncSender<io::Result<()>>),
+    Compact(u64, mpsc::SyncSender<io::Result<usize>>),
+}
+
+impl JournalWriter {
+    /// Moves `journal` onto its own writer thread.
+    pub fn start(journal: Journal) -> io::Result<Self> {
+        let path = journal.path().to_path_buf();
+        let next_seq = journal.last_seq() + 1;
+        let (requests, inbox) = mpsc::channel();
+        thread::Builder::new().name("journal-writer".to_string()).spawn(move || write_loop(journal, inbox))?;
+
+        Ok(Self {
+            path,
+            queue: Mutex::new(WriteQueue { next_seq, requests }),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends one input and returns its sequence number once the writer has stored it.
+    pub fn append(&self, timestamp: DateTime<Utc>, input: JournalInput) -> io::Result<u64> {
+        let (done, written) = mpsc::sync_channel(1);
+        let seq = {
+            let mut queue = self.queue.lock().map_err(|_| io::Error::other("Journal queue lock poisoned"))?;
+            let seq = queue.next_seq;
+            let record = JournalRecord { seq, timestamp, input };
+            queue.requests.send(WriteRequest::Append(Box::new(record), done)).map_err(|_| writer_gone())?;
+            queue.next_seq += 1;
+            seq
+        };
+
+        written.recv().map_err(|_| writer_gone())??;
+        Ok(seq)
+    }
+
+    /// Forces everything appended so far to disk.
+    pub fn sync(&self) -> io::Result<()> {
+        let (done, synced) = mpsc::sync_channel(1);
+        self.queue
+            .lock()
+            .map_err(|_| io::Error::other("Journal queue lock poisoned"))?
+            .requests
+            .send(WriteRequest::Sync(done))
+            .map_err(|_| writer_gone())?;
+        synced.recv().map_err(|_| writer_gone())?
+    }
+
+    /// Drops every record numbered `through_seq` or lower once everything queued before has been
+    /// written, see [`Journal::compact`].
+    pub fn compact(&self, through_seq: u64) -> io::Result<usize> {
+        let (done, compacted) = mpsc::sync_channel(1);
+        self.queue
+            .lock()
+            .map_err(|_| io::Error::other("Journal queue lock poisoned"))?
+            .requests
+            .send(WriteRequest::Compact(through_seq, done))
+            .map_err(|_| writer_gone())?;
+        compacted.recv().map_err(|_| writer_gone())?
+    }
+}
+
+/// Writer thread: drains everything queued, writes it as one batch and tells each caller how
+/// it went. Exits once every [`JournalWriter`] handle is gone.
+fn write_loop(mut journal: Journal, inbox: mpsc::Receiver<WriteRequest>) {
+    while let Ok(first) = inbox.recv() {
+        let mut records = Vec::new();
+        let mut appended = Vec::new();
+        let mut synced = Vec::new();
+        let mut compactions = Vec::new();
+        for request in std::iter::once(first).chain(inbox.try_iter()) {
+            match request {
+                WriteRequest::Append(record, done) => {
+                    records.push(*record);
+                    appended.push(done);
+                }
+                WriteRequest::Sync(done) => synced.push(done),
+                WriteRequest::Compact(through_seq, done) => compactions.push((through_seq, done)),
+            }
+        }
+
+        let result = if records.is_empty() { Ok(()) } else { journal.append_batch(&records) };
+        if let Err(e) = &result {
+            error!("Failed to write {} journal records: {}", records.len(), e);
+        }
+        for done in appended {
+            let _ = done.send(clone_result(&result));
+        }
+
+        if !synced.is_empty() {
+            let result = journal.sync();
+            for done in synced {
+                let _ = done.send(clone_result(&result));
+            }
+        }
+
+        for (through_seq, done) in compactions {
+            let result = journal.compact(through_seq);
+            if let Err(e) = &result {
+                error!("Failed to compact journal through seq {}: {}", through_seq, e);
+            }
+            let _ = done.send(result);
+        }
+    }
+}
+
+fn clone_result<T: Copy>(result: &io::Result<T>) -> io::Result<T> {
+    match result {
+        Ok(value) => Ok(*value),
+        Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+    }
+}
+
+fn writer_gone() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "Journal writer thread has stopped")
+}
+
+/// Reads records from the start of `file` until the end or the first bad record.
+///
+/// Returns the intact records and the length of the file they cover.
+fn read_records(file: &mut File) -> io::Result<(Vec<JournalRecord>, u64)> {
+    let mut reader = BufReader::new(&*file);
+    let mut records = Vec::new();
+    let mut valid_len = 0u64;
+
+    loop {
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        if !read_full(&mut reader, &mut header)? {
+            break;
+        }
+
+        let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let checksum = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if len > MAX_RECORD_LEN {
+            break;
+        }
+
+        let mut payload = vec![0u8; len];
+        if !read_full(&mut reader, &mut payload)? || crc32(&payload) != checksum {
+            break;
+        }
+
+        let Ok(record) = serde_json::from_slice::<JournalRecord>(&payload) else {
+            break;
+        };
+
+        // Sequence numbers only ever grow, a record that goes backwards is garbage
+        if records.last().is_some_and(|last: &JournalRecord| record.seq <= last.seq) {
+            break;
+        }
+
+        valid_len += (RECORD_HEADER_LEN + len) as u64;
+        records.push(record);
+    }
+
+    Ok((records, valid_len))
+}
+
+/// Fills `buf`, returning false if the reader ran out first.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => return Ok(false),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// CRC-32 (IEEE 802.3) lookup table.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, byte| CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::OrderEngine;
+    use crate::events::{EngineEvent, EventKind};
+    use crate::order::{CancelReason, OrderKind, OrderType, SelfTradePrevention, TimeInForce};
+    use tokio::sync::broadcast;
+
+    /// A journal path in a fresh directory under the system temp dir.
+    fn journal_path() -> PathBuf {
+        std::env::temp_dir().join(format!("order-engine-journal-{}", Uuid::new_v4())).join("journal.log")
+    }
+
+    fn deposit(amount: i64) -> JournalInput {
+        JournalInput::Deposit {
+            user_id: Uuid::new_v4(),
+            asset: "USDT".to_string(),
+            amount: Decimal::from(amount),
+        }
+    }
+
+    fn write_records(path: &Path, count: i64) -> u64 {
+        let (mut journal, records) = Journal::open(path, FsyncPolicy::Always).unwrap();
+        assert!(records.is_empty());
+        for amount in 1..=count {
+            journal.append(Utc::now(), deposit(amount)).unwrap();
+        }
+        std::fs::metadata(path).unwrap().len()
+    }
+
+    #[test]
+    fn torn_tail_is_truncated_on_open() {
+        let path = journal_path();
+        let intact_len = write_records(&path, 3);
+
+        // A crash part way through a record: a full header but only some of its payload
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&100u32.to_le_bytes()).unwrap();
+        file.write_all(&0u32.to_le_bytes()).unwrap();
+        file.write_all(b"{\"seq\":4").unwrap();
+        drop(file);
+
+        let (mut journal, records) = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(records.iter().map(|record| record.seq).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), intact_len);
+
+        // Appends carry on from the last intact record, and survive the next open
+        assert_eq!(journal.append(Utc::now(), deposit(4)).unwrap(), 4);
+        drop(journal);
+        let (_, records) = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(records.len(), 4);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn corrupt_record_ends_the_log() {
+        let path = journal_path();
+        write_records(&path, 3);
+
+        // Flip a byte inside the last record's payload so its checksum no longer matches
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 2;
+        bytes[last] ^= 0xFF;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let (journal, records) = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(journal.last_seq(), 2);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn writer_numbers_and_persists_records() {
+        let path = journal_path();
+        let (journal, _) = Journal::open(&path, FsyncPolicy::Batch(16)).unwrap();
+        let writer = JournalWriter::start(journal).unwrap();
+
+        let seqs: Vec<u64> = (1..=5).map(|amount| writer.append(Utc::now(), deposit(amount)).unwrap()).collect();
+        writer.sync().unwrap();
+        drop(writer);
+
+        assert_eq!(seqs, vec![1, 2, 3, 4, 5]);
+        let (_, records) = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(records.iter().map(|record| record.seq).collect::<Vec<_>>(), seqs);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    /// Every event published so far on `events`, oldest first.
+    fn drain(events: &mut broadcast::Receiver<EngineEvent>) -> Vec<EngineEvent> {
+        std::iter::from_fn(|| events.try_recv().ok()).collect()
+    }
+
+    #[tokio::test]
+    async fn expiry_sweeps_replay_at_their_recorded_time() {
+        let path = journal_path();
+        let user = Uuid::new_v4();
+
+        let engine = OrderEngine::new(1);
+        let mut live_events = engine.subscribe();
+        engine.attach_journal(Journal::open(&path, FsyncPolicy::Always).unwrap().0).await.unwrap();
+        engine.deposit(user, "USDT", Decimal::from(1_000)).unwrap();
+        let mut order = Order::new(user, "BTC/USDT".to_string(), OrderType::Buy, OrderKind::Limit, Decimal::ONE, Some(Decimal::from(100)));
+        order.time_in_force = TimeInForce::Gtd;
+        order.expires_at = Some(Utc::now() + chrono::Duration::milliseconds(50));
+        engine.add_order(order.clone()).await.unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(engine.expire_orders().await.unwrap().len(), 1);
+
+        let restored = OrderEngine::new(1);
+        let mut replayed_events = restored.subscribe();
+        let (_, records) = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        assert!(matches!(records.last().unwrap().input, JournalInput::ExpireOrders { .. }));
+        restored.replay_journal(records).await.unwrap();
+
+        // The replayed cancellation carries the sweep's time and sequence, not the replay's
+        let (live, replayed) = (drain(&mut live_events), drain(&mut replayed_events));
+        let cancelled = |events: &[EngineEvent]| {
+            events
+                .iter()
+                .find_map(|event| match &event.kind {
+                    EventKind::OrderCancelled { order } => Some((event.seq, event.timestamp, order.cancel_reason.clone())),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        assert_eq!(cancelled(&replayed), cancelled(&live));
+        assert_eq!(cancelled(&live).2, Some(CancelReason::Expired));
+        assert_eq!(restored.get_balances(user), engine.get_balances(user));
+
+        let (book, restored_book) = (
+            engine.get_orderbook("BTC/USDT").await.unwrap().unwrap().state(),
+            restored.get_orderbook("BTC/USDT").await.unwrap().unwrap().state(),
+        );
+        assert!(restored_book.bids.is_empty());
+        assert_eq!(restored_book.last_input_seq, book.last_input_seq);
+        assert_eq!(restored_book.event_seq, book.event_seq);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn self_trade_prevention_modes_survive_replay_and_snapshots() {
+        let path = journal_path();
+        let user = Uuid::new_v4();
+
+        let engine = OrderEngine::new(1);
+        engine.attach_journal(Journal::open(&path, FsyncPolicy::Always).unwrap().0).await.unwrap();
+        engine.set_self_trade_prevention(user, SelfTradePrevention::CancelBoth).unwrap();
+
+        let replayed = OrderEngine::new(1);
+        let (_, records) = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(replayed.replay_journal(records).await.unwrap(), 1);
+        assert_eq!(replayed.get_self_trade_prevention(user), Some(SelfTradePrevention::CancelBoth));
+
+        let restored = OrderEngine::new(1);
+        let snapshot = serde_json::to_vec(&engine.snapshot().await.unwrap()).unwrap();
+        restored.restore_snapshot(serde_json::from_slice(&snapshot).unwrap()).await.unwrap();
+        assert_eq!(restored.get_self_trade_prevention(user), Some(SelfTradePrevention::CancelBoth));
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn compaction_keeps_only_records_past_the_given_seq() {
+        let path = journal_path();
+        write_records(&path, 5);
+
+        let (mut journal, _) = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(journal.compact(3).unwrap(), 3);
+        assert_eq!(journal.compact(3).unwrap(), 0);
+
+        // Numbering carries on past the records dropped, in the compacted file
+        assert_eq!(journal.append(Utc::now(), deposit(6)).unwrap(), 6);
+        drop(journal);
+        let (journal, records) = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(records.iter().map(|record| record.seq).collect::<Vec<_>>(), vec![4, 5, 6]);
+        assert_eq!(journal.last_seq(), 6);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn snapshot_plus_compacted_journal_restores_the_engine() {
+        let path = journal_path();
+        let user = Uuid::new_v4();
+        let order = |price: i64| Order::new(user, "BTC/USDT".to_string(), OrderType::Buy, OrderKind::Limit, Decimal::ONE, Some(Decimal::from(price)));
+
+        let engine = OrderEngine::new(1);
+        engine.attach_journal(Journal::open(&path, FsyncPolicy::Always).unwrap().0).await.unwrap();
+        engine.deposit(user, "USDT", Decimal::from(1_000)).unwrap();
+        engine.add_order(order(100)).await.unwrap();
+
+        let snapshot = engine.snapshot().await.unwrap();
+        assert_eq!(snapshot.last_input_seq(), 2);
+        assert_eq!(engine.compact_journal(snapshot.last_input_seq()).unwrap(), 2);
+        engine.add_order(order(99)).await.unwrap();
+        engine.sync_journal().unwrap();
+
+        let restored = OrderEngine::new(1);
+        restored.restore_snapshot(serde_json::from_slice(&serde_json::to_vec(&snapshot).unwrap()).unwrap()).await.unwrap();
+        let (journal, records) = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(records.iter().map(|record| record.seq).collect::<Vec<_>>(), vec![3]);
+        assert_eq!(restored.replay_journal(records).await.unwrap(), 1);
+        restored.attach_journal(journal).await.unwrap();
+
+        assert_eq!(restored.get_balances(user), engine.get_balances(user));
+        let (book, restored_book) = (
+            engine.get_orderbook("BTC/USDT").await.unwrap().unwrap().state(),
+            restored.get_orderbook("BTC/USDT").await.unwrap().unwrap().state(),
+        );
+        assert_eq!(restored_book.bids.len(), 2);
+        assert_eq!(restored_book.last_input_seq, book.last_input_seq);
+
+        // New input is numbered after everything the snapshot and journal held
+        restored.deposit(user, "USDT", Decimal::ONE).unwrap();
+        restored.sync_journal().unwrap();
+        let (_, records) = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(records.last().unwrap().seq, 4);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,18 @@
+pub mod accounts;
+pub mod auth;
+pub mod candles;
+pub mod engine;
+pub mod fees;
+pub mod events;
+pub mod journal;
+pub mod market;
+pub mod matching;
+pub mod order;
+pub mod orderbook;
+pub mod shard;
+pub mod snapshot;
+pub mod ticker;
+pub mod trigger_book;
+pub mod volume;
+pub mod websocket;
+pub mod wire;
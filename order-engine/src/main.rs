@@ -1,17 +1,20 @@
 use anyhow::Result;
+use chrono::{NaiveTime, Utc};
 use clap::Parser;
+use rust_decimal::Decimal;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
-use tracing::{info, warn, error};
-use tracing_subscriber;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tracing::{info, error};
 
-mod engine;
-mod order;
-mod orderbook;
-mod websocket;
-
-use engine::OrderEngine;
-use websocket::handle_connection;
+use order_engine::auth::TokenVerifier;
+use order_engine::candles::CandleStore;
+use order_engine::engine::{OrderEngine, DEFAULT_QUEUE_CAPACITY};
+use order_engine::journal::{FsyncPolicy, Journal};
+use order_engine::snapshot::{latest_snapshot, prune_snapshots, read_snapshot, write_snapshot};
+use order_engine::volume::FeeTierSchedule;
+use order_engine::websocket::handle_connection;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -20,9 +23,57 @@ struct Args {
     #[arg(short, long, default_value_t = 9090)]
     port: u16,
 
-    /// Number of worker threads
+    /// Number of matching threads; listed pairs are partitioned across them
     #[arg(short, long, default_value_t = 4)]
     workers: usize,
+
+    /// Max pending requests per matching thread before new ones are turned away as overloaded
+    #[arg(long, default_value_t = DEFAULT_QUEUE_CAPACITY)]
+    queue_capacity: usize,
+
+    /// Max deviation of a market order from the best price at arrival, in basis points
+    #[arg(long, default_value_t = 500)]
+    market_collar_bps: u32,
+
+    /// Write-ahead journal of accepted inputs, replayed on startup
+    #[arg(long, default_value = "data/engine.journal")]
+    journal_path: PathBuf,
+
+    /// When journal records are fsynced: always, never or batch:<n>
+    #[arg(long, default_value = "always")]
+    journal_fsync: FsyncPolicy,
+
+    /// Directory order book snapshots are written to and restored from
+    #[arg(long, default_value = "data/snapshots")]
+    snapshot_dir: PathBuf,
+
+    /// Seconds between periodic snapshots, 0 to disable
+    #[arg(long, default_value_t = 60)]
+    snapshot_interval_secs: u64,
+
+    /// Number of snapshots kept on disk
+    #[arg(long, default_value_t = 5)]
+    snapshot_retain: usize,
+
+    /// Restore from this snapshot file instead of the newest one in the snapshot directory
+    #[arg(long)]
+    restore_snapshot: Option<PathBuf>,
+
+    /// Directory closed candles are kept in, one file per pair and interval
+    #[arg(long, default_value = "data/candles")]
+    candle_dir: PathBuf,
+
+    /// Time of day (UTC, HH:MM:SS) every user's fee tier is recalculated from their rolling volume
+    #[arg(long, default_value = "00:00:00")]
+    fee_tier_recalculation_time: NaiveTime,
+
+    /// Secret the backend signs its JSON Web Tokens with; connections authenticate with those tokens
+    #[arg(long, env = "JWT_SECRET", hide_env_values = true)]
+    jwt_secret: Option<String>,
+
+    /// Write a snapshot of the recovered books and exit
+    #[arg(long)]
+    force_snapshot: bool,
 }
 
 #[tokio::main]
@@ -33,12 +84,140 @@ async fn main() -> Result<()> {
     let args = Args::parse();
 
     info!("Starting Rust Order Matching Engine");
-    info!("Port: {}, Workers: {}", args.port, args.workers);
+    info!("Port: {}, Workers: {}, Market collar: {}bps", args.port, args.workers, args.market_collar_bps);
 
     // Create the order engine
-    let engine = Arc::new(OrderEngine::new(args.workers));
+    let engine = Arc::new(OrderEngine::with_queue_capacity(args.workers, args.queue_capacity));
+
+    let market_collar = Decimal::new(args.market_collar_bps as i64, 4);
+    for pair in engine.get_pairs() {
+        engine.set_market_collar(&pair, market_collar).await?;
+    }
+
+    engine.set_fee_tier_schedule(FeeTierSchedule {
+        recalculation_time: args.fee_tier_recalculation_time,
+        ..FeeTierSchedule::default()
+    })?;
+
+    // Stored candles go in first, so candles rebuilt below never duplicate them
+    engine.attach_candle_store(CandleStore::open(&args.candle_dir)?).await?;
+
+    // Rebuild the books from the newest snapshot plus the journal before taking new input
+    let snapshot = match &args.restore_snapshot {
+        Some(path) => Some((path.clone(), read_snapshot(path)?)),
+        None => latest_snapshot(&args.snapshot_dir)?,
+    };
+    let mut restored_seq = 0;
+    if let Some((path, snapshot)) = snapshot {
+        info!("Restoring order books from {}", path.display());
+        restored_seq = snapshot.last_input_seq();
+        engine.restore_snapshot(snapshot).await?;
+    }
+
+    let (journal, records) = Journal::open(&args.journal_path, args.journal_fsync)?;
+    engine.replay_journal(records).await?;
+    engine.attach_journal(journal).await?;
+
+    if args.force_snapshot {
+        write_snapshot(&args.snapshot_dir, &engine.snapshot().await?)?;
+        prune_snapshots(&args.snapshot_dir, args.snapshot_retain)?;
+        return Ok(());
+    }
+
+    // Periodically snapshot every book so startup does not replay the whole journal. Once a
+    // snapshot is on disk the journal is compacted through the one before it, so it still covers
+    // a fallback to the previous snapshot should the newest turn out unreadable
+    if args.snapshot_interval_secs > 0 {
+        let snapshot_engine = Arc::clone(&engine);
+        let snapshot_dir = args.snapshot_dir.clone();
+        let snapshot_retain = args.snapshot_retain;
+        tokio::spawn(async move {
+            let mut previous_seq = restored_seq;
+            let mut interval = tokio::time::interval(Duration::from_secs(args.snapshot_interval_secs));
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let snapshot = match snapshot_engine.snapshot().await {
+                    Ok(snapshot) => snapshot,
+                    Err(e) => {
+                        error!("Failed to take snapshot: {}", e);
+                        continue;
+                    }
+                };
+                let dir = snapshot_dir.clone();
+                let snapshot_seq = snapshot.last_input_seq();
+                let written = tokio::task::spawn_blocking(move || {
+                    write_snapshot(&dir, &snapshot)?;
+                    prune_snapshots(&dir, snapshot_retain)
+                })
+                .await;
+
+                match written {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => {
+                        error!("Failed to write snapshot: {}", e);
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("Snapshot task failed: {}", e);
+                        continue;
+                    }
+                }
+
+                let compact_engine = Arc::clone(&snapshot_engine);
+                let through_seq = previous_seq;
+                match tokio::task::spawn_blocking(move || compact_engine.compact_journal(through_seq)).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => error!("{}", e),
+                    Err(e) => error!("Journal compaction task failed: {}", e),
+                }
+                previous_seq = snapshot_seq;
+            }
+        });
+    }
+
+    // Sweep expired GTD orders off the books and close out finished candles
+    let expiry_engine = Arc::clone(&engine);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            match expiry_engine.expire_orders().await {
+                Ok(expired) => {
+                    for order in expired {
+                        info!("Order {} of user {} expired", order.id, order.user_id);
+                    }
+                }
+                Err(e) => error!("Failed to expire orders: {}", e),
+            }
+            if let Err(e) = expiry_engine.sync_journal() {
+                error!("{}", e);
+            }
+            if let Err(e) = expiry_engine.close_candles(Utc::now()).await {
+                error!("Failed to close candles: {}", e);
+            }
+            if let Err(e) = expiry_engine.flush_candles().await {
+                error!("{}", e);
+            }
+        }
+    });
+
+    // Re-tier every user from their rolling volume once a day
+    let tier_engine = Arc::clone(&engine);
+    tokio::spawn(async move {
+        let mut next = tier_engine.fee_tier_schedule().next_recalculation(Utc::now());
+        loop {
+            tokio::time::sleep((next - Utc::now()).to_std().unwrap_or_default()).await;
+            if let Err(e) = tier_engine.recalculate_fee_tiers(next) {
+                error!("Failed to recalculate fee tiers: {}", e);
+            }
+            next = tier_engine.fee_tier_schedule().next_recalculation(next);
+        }
+    });
 
     // Start the WebSocket server
+    let secret = args.jwt_secret.ok_or_else(|| anyhow::anyhow!("--jwt-secret or JWT_SECRET is required to accept connections"))?;
+    let tokens = Arc::new(TokenVerifier::new(secret));
     let addr = format!("127.0.0.1:{}", args.port);
     let listener = TcpListener::bind(&addr).await?;
     info!("Order matching engine listening on: {}", addr);
@@ -47,9 +226,10 @@ async fn main() -> Result<()> {
     while let Ok((stream, addr)) = listener.accept().await {
         info!("New connection from: {}", addr);
         let engine_clone = Arc::clone(&engine);
+        let tokens = Arc::clone(&tokens);
 
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(stream, engine_clone).await {
+            if let Err(e) = handle_connection(stream, engine_clone, tokens).await {
                 error!("Connection error: {}", e);
             }
         });
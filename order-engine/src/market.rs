@@ -0,0 +1,289 @@
+use crate::fees::{FeeRates, FeeSchedule, DEFAULT_ASSET_PRECISION};
+use crate::matching::MatchingPolicy;
+use crate::order::{Order, OrderType};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+/// Trading rules for a single pair. Orders that break them never reach the book.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MarketSpec {
+    pub pair: String,
+    pub base_asset: String,
+    pub quote_asset: String,
+    /// Prices must be a multiple of this increment.
+    pub tick_size: Decimal,
+    /// Quantities must be a multiple of this increment.
+    pub quantity_step: Decimal,
+    pub min_quantity: Decimal,
+    pub max_quantity: Decimal,
+    /// Smallest price * quantity accepted. Stop orders without a limit price are checked at their
+    /// trigger price and market orders at the best opposite price when they arrive.
+    pub min_notional: Decimal,
+    /// Max number of decimal places in a price.
+    pub price_precision: u32,
+    /// Decimal places balances of each asset are kept to; fees are rounded to them.
+    #[serde(default = "default_asset_precision")]
+    pub base_precision: u32,
+    #[serde(default = "default_asset_precision")]
+    pub quote_precision: u32,
+    /// Maker and taker rates by fee tier, lowest tier first.
+    pub fee_tiers: Vec<FeeRates>,
+    /// How fills at one price level are shared between its resting orders.
+    #[serde(default)]
+    pub matching_policy: MatchingPolicy,
+}
+
+impl MarketSpec {
+    pub fn new(base_asset: &str, quote_asset: &str) -> Self {
+        Self {
+            pair: format!("{}/{}", base_asset, quote_asset),
+            base_asset: base_asset.to_string(),
+            quote_asset: quote_asset.to_string(),
+            tick_size: Decimal::new(1, 2),
+            quantity_step: Decimal::new(1, 8),
+            min_quantity: Decimal::new(1, 8),
+            max_quantity: Decimal::from(1_000_000),
+            min_notional: Decimal::ZERO,
+            price_precision: 2,
+            base_precision: DEFAULT_ASSET_PRECISION,
+            quote_precision: DEFAULT_ASSET_PRECISION,
+            fee_tiers: FeeRates::default_tiers(),
+            matching_policy: MatchingPolicy::Fifo,
+        }
+    }
+
+    /// The pairs the engine lists out of the box.
+    pub fn defaults() -> Vec<MarketSpec> {
+        vec![
+            MarketSpec {
+                quantity_step: Decimal::new(1, 5),
+                min_quantity: Decimal::new(1, 5),
+                max_quantity: Decimal::from(1_000),
+                min_notional: Decimal::from(5),
+                ..MarketSpec::new("BTC", "USDT")
+            },
+            MarketSpec {
+                quantity_step: Decimal::new(1, 4),
+                min_quantity: Decimal::new(1, 4),
+                max_quantity: Decimal::from(10_000),
+                min_notional: Decimal::from(5),
+                ..MarketSpec::new("ETH", "USDT")
+            },
+            MarketSpec {
+                quantity_step: Decimal::new(1, 3),
+                min_quantity: Decimal::new(1, 3),
+                max_quantity: Decimal::from(100_000),
+                min_notional: Decimal::from(5),
+                ..MarketSpec::new("SOL", "USDT")
+            },
+            MarketSpec {
+                tick_size: Decimal::new(1, 4),
+                quantity_step: Decimal::new(1, 1),
+                min_quantity: Decimal::new(1, 1),
+                max_quantity: Decimal::from(10_000_000),
+                min_notional: Decimal::from(5),
+                price_precision: 4,
+                ..MarketSpec::new("ADA", "USDT")
+            },
+        ]
+    }
+
+    /// Checks that the spec itself is usable before a book is opened for it.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.pair != format!("{}/{}", self.base_asset, self.quote_asset) {
+            return Err(format!("Pair {} does not match {}/{}", self.pair, self.base_asset, self.quote_asset));
+        }
+        if self.tick_size <= Decimal::ZERO || self.quantity_step <= Decimal::ZERO {
+            return Err("Tick size and quantity step must be positive".to_string());
+        }
+        if self.tick_size.normalize().scale() > self.price_precision {
+            return Err(format!("Tick size {} is finer than the price precision", self.tick_size));
+        }
+        if self.quantity_precision() > self.base_precision || self.price_precision > self.quote_precision {
+            return Err(format!(
+                "Quantity step {} and price precision {} must fit the asset precisions {} and {}",
+                self.quantity_step, self.price_precision, self.base_precision, self.quote_precision
+            ));
+        }
+        if self.min_quantity <= Decimal::ZERO || self.min_quantity > self.max_quantity {
+            return Err("Quantity limits must satisfy 0 < min <= max".to_string());
+        }
+        if self.min_notional < Decimal::ZERO {
+            return Err("Min notional must not be negative".to_string());
+        }
+        if let MatchingPolicy::ProRataTopOrder { min_allocation } = &self.matching_policy {
+            if *min_allocation < Decimal::ZERO {
+                return Err(format!("Minimum allocation must not be negative: {}", min_allocation));
+            }
+        }
+        for (tier, rates) in self.fee_tiers.iter().enumerate() {
+            rates.validate().map_err(|e| format!("Fee tier {}: {}", tier, e))?;
+        }
+        Ok(())
+    }
+
+    /// Checks an incoming order's prices and quantities against the pair's rules.
+    pub fn validate_order(&self, order: &Order) -> Result<(), String> {
+        self.validate_quantity(order.amount)?;
+        if let Some(display_amount) = order.display_amount {
+            self.validate_quantity(display_amount)?;
+        }
+        if let Some(trigger_price) = order.trigger_price {
+            self.validate_price(trigger_price)?;
+        }
+        if let Some(price) = order.price {
+            self.validate_price(price)?;
+        }
+        // Market orders are checked by the book they go to, which knows the price they would get
+        if let Some(price) = order.price.or(order.trigger_price) {
+            self.validate_notional(order.amount, price)?;
+        }
+        Ok(())
+    }
+
+    /// Asset an order on `side` locks: quote for a buy, base for a sell.
+    pub fn locked_asset(&self, side: &OrderType) -> &str {
+        match side {
+            OrderType::Buy => &self.quote_asset,
+            OrderType::Sell => &self.base_asset,
+        }
+    }
+
+    pub fn fee_schedule(&self) -> FeeSchedule {
+        FeeSchedule {
+            base_precision: self.base_precision,
+            quote_precision: self.quote_precision,
+            ..FeeSchedule::new(&self.base_asset, &self.quote_asset, self.fee_tiers.clone())
+        }
+    }
+
+    /// Whether any side of the pair is `asset`.
+    pub fn trades_asset(&self, asset: &str) -> bool {
+        self.base_asset == asset || self.quote_asset == asset
+    }
+
+    /// Decimal places a quantity can have, as set by the quantity step.
+    pub fn quantity_precision(&self) -> u32 {
+        self.quantity_step.normalize().scale()
+    }
+
+    pub fn validate_price(&self, price: Decimal) -> Result<(), String> {
+        if price <= Decimal::ZERO {
+            return Err(format!("Price must be positive: {}", price));
+        }
+        if price.normalize().scale() > self.price_precision {
+            return Err(format!("Price {} has more than {} decimal places", price, self.price_precision));
+        }
+        if !(price % self.tick_size).is_zero() {
+            return Err(format!("Price {} is not a multiple of the tick size {}", price, self.tick_size));
+        }
+        Ok(())
+    }
+
+    pub fn validate_quantity(&self, quantity: Decimal) -> Result<(), String> {
+        if quantity < self.min_quantity {
+            return Err(format!("Quantity {} is below the minimum {}", quantity, self.min_quantity));
+        }
+        if quantity > self.max_quantity {
+            return Err(format!("Quantity {} is above the maximum {}", quantity, self.max_quantity));
+        }
+        if !(quantity % self.quantity_step).is_zero() {
+            return Err(format!("Quantity {} is not a multiple of the step {}", quantity, self.quantity_step));
+        }
+        Ok(())
+    }
+
+    pub fn validate_notional(&self, quantity: Decimal, price: Decimal) -> Result<(), String> {
+        let notional = quantity * price;
+        if notional < self.min_notional {
+            return Err(format!("Notional {} is below the minimum {}", notional, self.min_notional));
+        }
+        Ok(())
+    }
+}
+
+fn default_asset_precision() -> u32 {
+    DEFAULT_ASSET_PRECISION
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order::OrderKind;
+    use uuid::Uuid;
+
+    fn btc() -> MarketSpec {
+        MarketSpec::defaults().into_iter().find(|spec| spec.pair == "BTC/USDT").unwrap()
+    }
+
+    fn order(amount: &str, price: Option<&str>) -> Order {
+        let kind = if price.is_some() { OrderKind::Limit } else { OrderKind::Market };
+        Order::new(Uuid::new_v4(), "BTC/USDT".to_string(), OrderType::Buy, kind, amount.parse().unwrap(), price.map(|p| p.parse().unwrap()))
+    }
+
+    #[test]
+    fn default_specs_are_valid() {
+        for spec in MarketSpec::defaults() {
+            assert_eq!(spec.validate(), Ok(()), "{}", spec.pair);
+        }
+    }
+
+    #[test]
+    fn prices_must_sit_on_the_tick() {
+        let spec = btc();
+        assert!(spec.validate_order(&order("0.1", Some("100.01"))).is_ok());
+        assert!(spec.validate_order(&order("0.1", Some("100.015"))).is_err());
+        assert!(spec.validate_order(&order("0.1", Some("0"))).is_err());
+
+        let coarse = MarketSpec { tick_size: Decimal::new(5, 1), ..btc() };
+        assert!(coarse.validate_price("100.5".parse().unwrap()).is_ok());
+        assert!(coarse.validate_price("100.2".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn quantities_must_sit_on_the_lot_and_within_limits() {
+        let spec = btc();
+        assert!(spec.validate_order(&order("0.00001", Some("1000000"))).is_ok());
+        assert!(spec.validate_order(&order("0.000015", Some("1000000"))).is_err());
+        assert!(spec.validate_order(&order("0.000001", Some("1000000"))).is_err());
+        assert!(spec.validate_order(&order("1000.00001", Some("100"))).is_err());
+        assert!(spec.validate_order(&order("1000", Some("100"))).is_ok());
+
+        // The iceberg display size follows the same rules
+        let mut iceberg = order("1", Some("100"));
+        iceberg.display_amount = Some("0.000015".parse().unwrap());
+        assert!(spec.validate_order(&iceberg).is_err());
+    }
+
+    #[test]
+    fn orders_below_the_min_notional_are_refused() {
+        let spec = btc();
+        assert!(spec.validate_order(&order("0.05", Some("100"))).is_ok());
+        assert!(spec.validate_order(&order("0.04", Some("100"))).is_err());
+
+        // A stop market order is held to its trigger price, a market order is left to the book
+        let mut stop = order("0.04", None);
+        stop.order_kind = OrderKind::Stop;
+        stop.trigger_price = Some(Decimal::from(100));
+        assert!(spec.validate_order(&stop).is_err());
+        assert!(spec.validate_order(&order("0.00001", None)).is_ok());
+    }
+
+    #[test]
+    fn unusable_specs_are_refused() {
+        assert!(MarketSpec { pair: "BTC-USDT".to_string(), ..btc() }.validate().is_err());
+        assert!(MarketSpec { tick_size: Decimal::ZERO, ..btc() }.validate().is_err());
+        assert!(MarketSpec { tick_size: Decimal::new(1, 3), ..btc() }.validate().is_err());
+        assert!(MarketSpec { min_quantity: Decimal::from(2_000), ..btc() }.validate().is_err());
+        assert!(MarketSpec { min_notional: Decimal::NEGATIVE_ONE, ..btc() }.validate().is_err());
+        assert!(MarketSpec { base_precision: 4, ..btc() }.validate().is_err());
+        assert!(MarketSpec { quote_precision: 1, ..btc() }.validate().is_err());
+    }
+
+    #[test]
+    fn quantity_precision_follows_the_step() {
+        assert_eq!(btc().quantity_precision(), 5);
+        assert_eq!(MarketSpec { quantity_step: Decimal::new(10, 1), ..btc() }.quantity_precision(), 0);
+    }
+}
+
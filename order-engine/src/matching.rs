@@ -0,0 +1,135 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+/// How an incoming order's quantity is shared between the resting orders at one price level.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MatchingPolicy {
+    /// Price-time priority: the oldest order at the level fills first.
+    #[default]
+    Fifo,
+    /// Every order at the level fills in proportion to its displayed size.
+    ProRata,
+    /// The order at the front of the queue fills first, the rest is shared pro-rata.
+    /// Pro-rata shares smaller than `min_allocation` are dropped and handed out in queue order.
+    ProRataTopOrder { min_allocation: Decimal },
+}
+
+impl MatchingPolicy {
+    /// Splits `quantity` between resting orders showing `sizes`, given in queue order.
+    ///
+    /// Pro-rata shares are rounded down to `lot_size`. Whatever rounding leaves over is handed
+    /// out in queue order, so the allocations always add up to `quantity` (or the whole level
+    /// if it is smaller) and never exceed an order's size.
+    pub fn allocate(&self, sizes: &[Decimal], quantity: Decimal, lot_size: Decimal) -> Vec<Decimal> {
+        let mut allocations = vec![Decimal::ZERO; sizes.len()];
+        let total: Decimal = sizes.iter().sum();
+        let mut remaining = quantity.min(total);
+
+        match self {
+            MatchingPolicy::Fifo => {}
+            MatchingPolicy::ProRata => {
+                remaining -= allocate_pro_rata(&mut allocations, sizes, remaining, lot_size, Decimal::ZERO);
+            }
+            MatchingPolicy::ProRataTopOrder { min_allocation } => {
+                if let Some(top_size) = sizes.first() {
+                    allocations[0] = (*top_size).min(remaining);
+                    remaining -= allocations[0];
+                }
+                remaining -= allocate_pro_rata(&mut allocations, sizes, remaining, lot_size, *min_allocation);
+            }
+        }
+
+        // Hand out whatever is left in queue order
+        for (allocation, size) in allocations.iter_mut().zip(sizes) {
+            if remaining <= Decimal::ZERO {
+                break;
+            }
+            let extra = (*size - *allocation).min(remaining);
+            *allocation += extra;
+            remaining -= extra;
+        }
+
+        allocations
+    }
+}
+
+/// Adds each order's pro-rata share of `quantity` to `allocations` and returns the total handed out.
+fn allocate_pro_rata(
+    allocations: &mut [Decimal],
+    sizes: &[Decimal],
+    quantity: Decimal,
+    lot_size: Decimal,
+    min_allocation: Decimal,
+) -> Decimal {
+    let capacity: Decimal = sizes.iter().zip(allocations.iter()).map(|(size, allocated)| *size - *allocated).sum();
+    if capacity <= Decimal::ZERO || quantity <= Decimal::ZERO {
+        return Decimal::ZERO;
+    }
+
+    let mut allocated = Decimal::ZERO;
+    for (allocation, size) in allocations.iter_mut().zip(sizes) {
+        let order_capacity = *size - *allocation;
+        let share = round_down_to_lot(quantity * order_capacity / capacity, lot_size).min(order_capacity);
+        if share <= Decimal::ZERO || share < min_allocation {
+            continue;
+        }
+
+        *allocation += share;
+        allocated += share;
+    }
+
+    allocated
+}
+
+fn round_down_to_lot(amount: Decimal, lot_size: Decimal) -> Decimal {
+    if lot_size <= Decimal::ZERO {
+        return amount;
+    }
+    (amount / lot_size).floor() * lot_size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimals(values: &[&str]) -> Vec<Decimal> {
+        values.iter().map(|v| v.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn fifo_fills_in_queue_order() {
+        let allocations = MatchingPolicy::Fifo.allocate(&decimals(&["2", "3"]), Decimal::from(4), Decimal::ONE);
+        assert_eq!(allocations, decimals(&["2", "2"]));
+    }
+
+    #[test]
+    fn pro_rata_shares_by_size() {
+        let allocations = MatchingPolicy::ProRata.allocate(&decimals(&["6", "3", "1"]), Decimal::from(5), Decimal::ONE);
+        // Shares of 3, 1.5 and 0.5 round down to 3, 1 and 0; the lot left over goes to the front
+        assert_eq!(allocations, decimals(&["4", "1", "0"]));
+    }
+
+    #[test]
+    fn pro_rata_rounding_remainder_goes_in_queue_order() {
+        let sizes = decimals(&["1", "1", "1"]);
+        let allocations = MatchingPolicy::ProRata.allocate(&sizes, Decimal::ONE, Decimal::new(1, 1));
+        assert_eq!(allocations, decimals(&["0.4", "0.3", "0.3"]));
+        assert_eq!(allocations.iter().sum::<Decimal>(), Decimal::ONE);
+    }
+
+    #[test]
+    fn pro_rata_top_order_drops_small_shares() {
+        let policy = MatchingPolicy::ProRataTopOrder { min_allocation: Decimal::from(2) };
+        let allocations = policy.allocate(&decimals(&["2", "10", "5", "1"]), Decimal::from(8), Decimal::ONE);
+        // The top order fills first; of the 6 left, 3 goes pro-rata and the 1 under the minimum
+        // is handed out in queue order with the rounding remainder
+        assert_eq!(allocations, decimals(&["2", "6", "0", "0"]));
+    }
+
+    #[test]
+    fn never_allocates_more_than_the_level() {
+        let allocations = MatchingPolicy::ProRata.allocate(&decimals(&["1", "2"]), Decimal::from(10), Decimal::ONE);
+        assert_eq!(allocations, decimals(&["1", "2"]));
+    }
+}
@@ -1,6 +1,7 @@
 use chrono::{DateTime, Utc};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -15,6 +16,11 @@ pub enum OrderType {
 pub enum OrderKind {
     Market,
     Limit,
+    /// Becomes a market order once the last trade price reaches `trigger_price`.
+    Stop,
+    /// Becomes a limit order at `price` once the last trade price reaches `trigger_price`.
+    #[serde(rename = "stop_limit")]
+    StopLimit,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -24,6 +30,73 @@ pub enum OrderStatus {
     Partial,
     Filled,
     Cancelled,
+    Rejected,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeInForce {
+    /// Good till cancelled: the remainder rests until filled or cancelled.
+    #[default]
+    Gtc,
+    /// Immediate or cancel: whatever does not fill on arrival is cancelled.
+    Ioc,
+    /// Fill or kill: the order fills completely on arrival or not at all.
+    Fok,
+    /// Good till date: rests like GTC until `expires_at`.
+    Gtd,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PostOnlyMode {
+    /// Reject a post-only order that would cross the book.
+    #[default]
+    Reject,
+    /// Re-price a crossing post-only order one tick behind the opposite best price.
+    Slide,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SelfTradePrevention {
+    /// Cancel the incoming order and leave the resting one on the book.
+    #[default]
+    CancelNewest,
+    /// Cancel the resting order and keep matching the incoming one.
+    CancelOldest,
+    /// Cancel both orders.
+    CancelBoth,
+    /// Reduce both orders by the would-be trade size and cancel whichever reaches zero.
+    DecrementAndCancel,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CancelReason {
+    UserRequested,
+    ImmediateOrCancel,
+    FillOrKill,
+    Expired,
+    NoLiquidity,
+    SlippageCollar,
+    PostOnly,
+    SelfTradePrevention,
+}
+
+impl std::fmt::Display for CancelReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CancelReason::UserRequested => write!(f, "User requested"),
+            CancelReason::ImmediateOrCancel => write!(f, "Immediate-or-cancel remainder"),
+            CancelReason::FillOrKill => write!(f, "Fill-or-kill could not be fully filled"),
+            CancelReason::Expired => write!(f, "Good-till-date order expired"),
+            CancelReason::NoLiquidity => write!(f, "No liquidity left on the opposite side"),
+            CancelReason::SlippageCollar => write!(f, "Remaining liquidity is outside the slippage collar"),
+            CancelReason::PostOnly => write!(f, "Post-only order would take liquidity"),
+            CancelReason::SelfTradePrevention => write!(f, "Self-trade prevention"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -33,10 +106,37 @@ pub struct Order {
     pub pair: String,
     pub order_type: OrderType,
     pub order_kind: OrderKind,
+    #[serde(with = "rust_decimal::serde::str")]
     pub amount: Decimal,
+    #[serde(serialize_with = "rust_decimal::serde::str_option::serialize")]
     pub price: Option<Decimal>,
+    #[serde(serialize_with = "rust_decimal::serde::str_option::serialize")]
+    pub trigger_price: Option<Decimal>,
+    /// Iceberg peak size: only this much of the order is shown in the book at a time.
+    #[serde(serialize_with = "rust_decimal::serde::str_option::serialize")]
+    pub display_amount: Option<Decimal>,
+    /// What is left of the currently displayed iceberg slice.
+    #[serde(with = "rust_decimal::serde::str")]
+    pub visible_amount: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
     pub filled: Decimal,
     pub status: OrderStatus,
+    pub time_in_force: TimeInForce,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub post_only: bool,
+    pub post_only_mode: PostOnlyMode,
+    /// Overrides the account's self-trade prevention mode for this order.
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+    pub cancel_reason: Option<CancelReason>,
+    /// Funds held for the order: quote for a buy, base for a sell. Fills spend it, and whatever
+    /// is left when the order is done goes back to the account.
+    #[serde(default, with = "rust_decimal::serde::str")]
+    pub locked: Decimal,
+    /// Fee tier the account was in when the order was placed; its fills pay that tier's rates.
+    #[serde(default)]
+    pub fee_tier: u8,
+    /// Arrival sequence inside the book; increases along every price level queue.
+    pub queue_seq: u64,
     pub created_at: DateTime<Utc>,
 }
 
@@ -57,8 +157,20 @@ impl Order {
             order_kind,
             amount,
             price,
+            trigger_price: None,
+            display_amount: None,
+            visible_amount: Decimal::ZERO,
             filled: Decimal::ZERO,
             status: OrderStatus::Pending,
+            time_in_force: TimeInForce::Gtc,
+            expires_at: None,
+            post_only: false,
+            post_only_mode: PostOnlyMode::Reject,
+            self_trade_prevention: None,
+            cancel_reason: None,
+            locked: Decimal::ZERO,
+            fee_tier: 0,
+            queue_seq: 0,
             created_at: Utc::now(),
         }
     }
@@ -67,8 +179,44 @@ impl Order {
         self.amount - self.filled
     }
 
-    pub fn fill(&mut self, amount: Decimal) {
+    /// Amount shown in the book: the current slice for icebergs, everything otherwise.
+    pub fn visible_remaining(&self) -> Decimal {
+        match self.display_amount {
+            Some(_) => self.visible_amount,
+            None => self.remaining_amount(),
+        }
+    }
+
+    /// Shows the next iceberg slice once the current one is used up.
+    pub fn replenish(&mut self) {
+        if let Some(display_amount) = self.display_amount {
+            self.visible_amount = display_amount.min(self.remaining_amount());
+        }
+    }
+
+    /// Funds a resting order needs locked for what is left of it: quote at its limit price for a
+    /// buy, base for a sell.
+    pub fn funds_required(&self) -> Decimal {
+        match (&self.order_type, self.price) {
+            (OrderType::Buy, Some(price)) => self.remaining_amount() * price,
+            (OrderType::Buy, None) => self.locked,
+            (OrderType::Sell, _) => self.remaining_amount(),
+        }
+    }
+
+    /// Applies a fill of `amount` at `price`.
+    ///
+    /// A buy gives up the funds it locked at its limit price, so any price improvement is freed
+    /// along with the cost. Market buys have no limit and give up exactly what they paid.
+    pub fn fill(&mut self, amount: Decimal, price: Decimal) {
+        self.locked -= match self.order_type {
+            OrderType::Buy => amount * self.price.unwrap_or(price),
+            OrderType::Sell => amount,
+        };
         self.filled += amount;
+        if self.is_iceberg() {
+            self.visible_amount = (self.visible_amount - amount).max(Decimal::ZERO);
+        }
         if self.filled >= self.amount {
             self.status = OrderStatus::Filled;
         } else {
@@ -76,8 +224,39 @@ impl Order {
         }
     }
 
-    pub fn cancel(&mut self) {
+    /// Reduces the order size without a fill, cancelling the order once nothing is left.
+    pub fn decrement(&mut self, amount: Decimal) {
+        self.locked -= match (&self.order_type, self.price) {
+            (OrderType::Buy, Some(price)) => amount * price,
+            (OrderType::Buy, None) => Decimal::ZERO, // A market buy keeps its budget until it is done
+            (OrderType::Sell, _) => amount,
+        };
+        self.amount -= amount;
+        if self.is_iceberg() {
+            self.visible_amount = self.visible_amount.min(self.remaining_amount());
+        }
+        if self.remaining_amount() <= Decimal::ZERO {
+            self.cancel(CancelReason::SelfTradePrevention);
+        }
+    }
+
+    pub fn cancel(&mut self, reason: CancelReason) {
         self.status = OrderStatus::Cancelled;
+        self.cancel_reason = Some(reason);
+    }
+
+    pub fn reject(&mut self, reason: CancelReason) {
+        self.status = OrderStatus::Rejected;
+        self.cancel_reason = Some(reason);
+    }
+
+    /// Whether the order can still trade.
+    pub fn is_active(&self) -> bool {
+        matches!(self.status, OrderStatus::Pending | OrderStatus::Partial) && self.remaining_amount() > Decimal::ZERO
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
     }
 
     pub fn is_buy(&self) -> bool {
@@ -95,6 +274,23 @@ impl Order {
     pub fn is_limit(&self) -> bool {
         matches!(self.order_kind, OrderKind::Limit)
     }
+
+    pub fn is_iceberg(&self) -> bool {
+        self.display_amount.is_some()
+    }
+
+    pub fn is_stop(&self) -> bool {
+        matches!(self.order_kind, OrderKind::Stop | OrderKind::StopLimit)
+    }
+
+    /// Converts a triggered stop into the market or limit order it stands for.
+    pub fn trigger(&mut self) {
+        match self.order_kind {
+            OrderKind::Stop => self.order_kind = OrderKind::Market,
+            OrderKind::StopLimit => self.order_kind = OrderKind::Limit,
+            OrderKind::Market | OrderKind::Limit => {}
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -107,18 +303,35 @@ pub struct Trade {
     pub pair: String,
     pub amount: Decimal,
     pub price: Decimal,
+    /// Side of the order that was resting on the book.
+    pub maker_side: OrderType,
+    /// Fee rates that applied, from the tiers the two orders were placed in.
+    pub maker_rate: Decimal,
+    pub taker_rate: Decimal,
+    /// Paid by the maker out of the asset it received; negative for a rebate.
+    pub maker_fee: Decimal,
+    pub maker_fee_asset: String,
+    /// Paid by the taker out of the asset it received.
+    pub taker_fee: Decimal,
+    pub taker_fee_asset: String,
+    /// Time of the input that caused the trade, so a replayed trade keeps its original time.
     pub timestamp: DateTime<Utc>,
 }
 
 impl Trade {
+    /// A trade at `timestamp` with no fees charged yet, see [`crate::fees::FeeSchedule::charge`].
+    /// Call it before either order is filled: its id is derived from what the two orders had
+    /// filled so far.
     pub fn new(
         buy_order: &Order,
         sell_order: &Order,
         amount: Decimal,
         price: Decimal,
+        maker_side: OrderType,
+        timestamp: DateTime<Utc>,
     ) -> Self {
         Self {
-            id: Uuid::new_v4(),
+            id: derived_id(b"trade", buy_order, sell_order, timestamp),
             buy_order_id: buy_order.id,
             sell_order_id: sell_order.id,
             buyer_id: buy_order.user_id,
@@ -126,7 +339,119 @@ impl Trade {
             pair: buy_order.pair.clone(),
             amount,
             price,
-            timestamp: Utc::now(),
+            maker_side,
+            maker_rate: Decimal::ZERO,
+            taker_rate: Decimal::ZERO,
+            maker_fee: Decimal::ZERO,
+            maker_fee_asset: String::new(),
+            taker_fee: Decimal::ZERO,
+            taker_fee_asset: String::new(),
+            timestamp,
+        }
+    }
+}
+
+/// One side of a trade, seen from the order that took part in it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fill {
+    pub trade_id: Uuid,
+    pub order_id: Uuid,
+    pub user_id: Uuid,
+    pub pair: String,
+    pub side: OrderType,
+    pub price: Decimal,
+    pub amount: Decimal,
+    /// Total filled on the order after this fill.
+    pub filled: Decimal,
+    /// Amount still open on the order after this fill.
+    pub remaining: Decimal,
+    pub is_maker: bool,
+    /// What the order's owner paid on this fill, see [`Trade::maker_fee`].
+    pub fee: Decimal,
+    pub fee_asset: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Fill {
+    /// Records `order`'s part in `trade`; call after the fill has been applied to the order and
+    /// the trade's fees charged.
+    pub fn new(order: &Order, trade: &Trade, is_maker: bool) -> Self {
+        let (fee, fee_asset) = if is_maker {
+            (trade.maker_fee, trade.maker_fee_asset.clone())
+        } else {
+            (trade.taker_fee, trade.taker_fee_asset.clone())
+        };
+        Self {
+            trade_id: trade.id,
+            order_id: order.id,
+            user_id: order.user_id,
+            pair: trade.pair.clone(),
+            side: order.order_type.clone(),
+            price: trade.price,
+            amount: trade.amount,
+            filled: order.filled,
+            remaining: order.remaining_amount(),
+            is_maker,
+            fee,
+            fee_asset,
+            timestamp: trade.timestamp,
         }
     }
 }
+
+/// Record of a trade the engine refused to create because both sides belong to the same user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SelfTradePrevented {
+    pub id: Uuid,
+    pub pair: String,
+    pub user_id: Uuid,
+    pub taker_order_id: Uuid,
+    pub maker_order_id: Uuid,
+    pub mode: SelfTradePrevention,
+    /// Size of the trade that would have happened.
+    pub prevented_amount: Decimal,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl SelfTradePrevented {
+    pub fn new(
+        taker_order: &Order,
+        maker_order: &Order,
+        mode: SelfTradePrevention,
+        prevented_amount: Decimal,
+        timestamp: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            id: derived_id(b"self-trade", taker_order, maker_order, timestamp),
+            pair: taker_order.pair.clone(),
+            user_id: taker_order.user_id,
+            taker_order_id: taker_order.id,
+            maker_order_id: maker_order.id,
+            mode,
+            prevented_amount,
+            timestamp,
+        }
+    }
+}
+
+/// Id for something that happened between two orders at `timestamp`, `kind` telling trades and
+/// other outcomes apart.
+///
+/// Taken from the orders' ids and how much of each was filled or left, which replaying the same
+/// inputs at the same times reproduces exactly, so a replayed trade keeps the id it was first
+/// reported under. Laid out as a version 8 (custom) UUID.
+fn derived_id(kind: &[u8], first: &Order, second: &Order, timestamp: DateTime<Utc>) -> Uuid {
+    let mut hasher = Sha256::new();
+    hasher.update(kind);
+    for order in [first, second] {
+        hasher.update(order.id.as_bytes());
+        hasher.update(order.filled.normalize().serialize());
+        hasher.update(order.remaining_amount().normalize().serialize());
+    }
+    hasher.update(timestamp.timestamp_nanos_opt().unwrap_or_default().to_le_bytes());
+
+    let digest = hasher.finalize();
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    uuid::Builder::from_custom_bytes(bytes).into_uuid()
+}
@@ -1,6 +1,13 @@
-use crate::order::{Order, OrderType, Trade};
+use crate::fees::FeeSchedule;
+use crate::matching::MatchingPolicy;
+use crate::order::{CancelReason, Fill, Order, OrderType, PostOnlyMode, SelfTradePrevented, SelfTradePrevention, TimeInForce, Trade};
+use crate::ticker::RollingStats;
+use crate::trigger_book::TriggerBook;
+use chrono::{DateTime, Utc};
 use rust_decimal::Decimal;
-use std::collections::{BTreeMap, VecDeque};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::Arc;
 use uuid::Uuid;
 
 #[derive(Debug, Clone)]
@@ -8,6 +15,186 @@ pub struct OrderBook {
     pub pair: String,
     pub bids: BTreeMap<Decimal, VecDeque<Order>>, // Buy orders (price -> orders)
     pub asks: BTreeMap<Decimal, VecDeque<Order>>, // Sell orders (price -> orders)
+    /// Max fraction a market order may trade away from the best opposite price at arrival.
+    pub market_collar: Decimal,
+    /// Price increment used when sliding post-only orders behind the opposite best price.
+    pub tick_size: Decimal,
+    /// Quantity increment that pro-rata allocations are rounded down to.
+    pub lot_size: Decimal,
+    /// How fills are shared between resting orders at the same price.
+    pub matching_policy: MatchingPolicy,
+    /// Fees charged on every trade.
+    pub fees: Arc<FeeSchedule>,
+    pub triggers: TriggerBook,
+    pub last_trade_price: Option<Decimal>,
+    /// Trading over the last 24 hours, for the ticker.
+    pub stats: RollingStats,
+    /// Journal sequence of the last input applied to this book, 0 if none.
+    pub last_input_seq: u64,
+    /// Sequence number of the last event published for this pair, 0 if none.
+    pub event_seq: u64,
+    /// Sequence number of the last price level change published, 0 if none. The L2 feed's own
+    /// counter, so depth subscribers see it advance by exactly one per level change.
+    pub depth_seq: u64,
+    /// Sequence number of the last order change published, 0 if none. Counts for the L3 feed
+    /// what `depth_seq` counts for L2.
+    pub l3_seq: u64,
+    index: HashMap<Uuid, OrderLocation>, // Live orders (order id -> location)
+    expiries: BTreeSet<(DateTime<Utc>, Uuid)>, // GTD orders by expiry, resting or waiting on a trigger
+    next_queue_seq: u64,
+    changed: BookChanges, // Levels and orders touched since they were last taken
+}
+
+/// Where a live order sits inside the book.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrderLocation {
+    /// Resting at `price`, found inside the level by its `queue_seq`.
+    Resting { side: OrderType, price: Decimal, queue_seq: u64 },
+    /// Waiting in the trigger book at `trigger_price`.
+    Stop { side: OrderType, trigger_price: Decimal },
+}
+
+/// Every order in a book, with queue order preserved, as written to a snapshot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BookState {
+    pub pair: String,
+    #[serde(serialize_with = "crate::snapshot::serialize_price_map")]
+    pub bids: BTreeMap<Decimal, VecDeque<Order>>,
+    #[serde(serialize_with = "crate::snapshot::serialize_price_map")]
+    pub asks: BTreeMap<Decimal, VecDeque<Order>>,
+    pub triggers: TriggerBook,
+    #[serde(serialize_with = "rust_decimal::serde::str_option::serialize")]
+    pub last_trade_price: Option<Decimal>,
+    #[serde(default)]
+    pub stats: RollingStats,
+    pub last_input_seq: u64,
+    #[serde(default)]
+    pub event_seq: u64,
+    #[serde(default)]
+    pub depth_seq: u64,
+    #[serde(default)]
+    pub l3_seq: u64,
+    pub next_queue_seq: u64,
+}
+
+/// Outcome of submitting an order to the book.
+#[derive(Debug, Clone)]
+pub struct MatchResult {
+    /// The incoming order after matching, with its final status.
+    pub order: Order,
+    pub trades: Vec<Trade>,
+    /// Resting orders removed from the book as a side effect (e.g. expired GTD orders).
+    pub cancelled: Vec<Order>,
+    /// Stop orders fired by this order's trades, in the order they executed, with their final status.
+    pub triggered: Vec<Order>,
+    /// Original limit price when a post-only order was slid behind the opposite best price.
+    pub repriced_from: Option<Decimal>,
+    pub self_trades_prevented: Vec<SelfTradePrevented>,
+    /// Both sides of every trade, taker first, in execution order.
+    pub fills: Vec<Fill>,
+}
+
+/// Outcome of amending a resting order.
+#[derive(Debug, Clone)]
+pub struct AmendResult {
+    /// Whether the order kept its place in the queue (quantity reductions only).
+    pub kept_priority: bool,
+    /// Amount and price the order had before the amend.
+    pub old_amount: Decimal,
+    pub old_price: Decimal,
+    pub result: MatchResult,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AmendError {
+    OrderNotFound,
+    Invalid(String),
+}
+
+impl std::fmt::Display for AmendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AmendError::OrderNotFound => write!(f, "Order not found"),
+            AmendError::Invalid(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+/// Side effects collected while matching the incoming order and any stops it fires.
+#[derive(Debug, Default)]
+struct MatchEffects {
+    /// Time of the input being matched, see [`Trade::timestamp`].
+    now: DateTime<Utc>,
+    fees: Arc<FeeSchedule>,
+    trades: Vec<Trade>,
+    cancelled: Vec<Order>,
+    self_trades_prevented: Vec<SelfTradePrevented>,
+    fills: Vec<Fill>,
+}
+
+/// What happened to a resting order, as seen in the order-by-order (L3) book.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderChangeKind {
+    /// Joined the back of the queue at its price.
+    Added,
+    /// Shrank in place, keeping its place in the queue.
+    Reduced,
+    /// Left the book. An iceberg that replenishes is removed and added again under a new handle.
+    Removed,
+}
+
+/// A change to one resting order, without revealing whose order it is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderChange {
+    pub kind: OrderChangeKind,
+    /// Anonymous handle for the order's place in the queue (its queue sequence). Handles only
+    /// grow, so at each price they sort in queue order.
+    pub handle: u64,
+    pub side: OrderType,
+    pub price: Decimal,
+    /// Visible size left on the order, zero once removed.
+    pub size: Decimal,
+}
+
+/// What changed in the book since the changes were last taken.
+#[derive(Debug, Clone, Default)]
+struct BookChanges {
+    bids: BTreeSet<Decimal>, // Price levels whose aggregate size may have changed
+    asks: BTreeSet<Decimal>,
+    orders: Vec<OrderChange>,
+}
+
+impl BookChanges {
+    fn mark(&mut self, side: &OrderType, price: Decimal) {
+        match side {
+            OrderType::Buy => self.bids.insert(price),
+            OrderType::Sell => self.asks.insert(price),
+        };
+    }
+
+    fn record(&mut self, kind: OrderChangeKind, order: &Order, price: Decimal) {
+        let size = match kind {
+            OrderChangeKind::Removed => Decimal::ZERO,
+            _ => order.visible_remaining(),
+        };
+        self.orders.push(OrderChange {
+            kind,
+            handle: order.queue_seq,
+            side: order.order_type.clone(),
+            price,
+            size,
+        });
+    }
+
+    /// Records what self-trade prevention did to a resting order that showed `visible` before.
+    fn record_prevented(&mut self, maker: &Order, visible: Decimal, price: Decimal) {
+        if !maker.is_active() {
+            self.record(OrderChangeKind::Removed, maker, price);
+        } else if maker.visible_remaining() != visible {
+            self.record(OrderChangeKind::Reduced, maker, price);
+        }
+    }
 }
 
 impl OrderBook {
@@ -16,86 +203,371 @@ impl OrderBook {
             pair,
             bids: BTreeMap::new(),
             asks: BTreeMap::new(),
+            market_collar: Decimal::new(5, 2),
+            tick_size: Decimal::new(1, 2),
+            lot_size: Decimal::new(1, 8),
+            matching_policy: MatchingPolicy::Fifo,
+            fees: Arc::default(),
+            triggers: TriggerBook::new(),
+            last_trade_price: None,
+            stats: RollingStats::default(),
+            last_input_seq: 0,
+            event_seq: 0,
+            depth_seq: 0,
+            l3_seq: 0,
+            index: HashMap::new(),
+            expiries: BTreeSet::new(),
+            next_queue_seq: 0,
+            changed: BookChanges::default(),
         }
     }
 
-    pub fn add_order(&mut self, mut order: Order) -> Vec<Trade> {
-        let mut trades = Vec::new();
+    pub fn add_order(&mut self, order: Order) -> MatchResult {
+        self.add_order_at(order, Utc::now())
+    }
 
-        match order.order_type {
-            OrderType::Buy => {
-                // Match against asks (sell orders)
-                trades = self.match_buy_order(&mut order);
+    /// Submits an order as of `now`, which decides what has expired. Replaying a recorded input
+    /// with its original time rebuilds the book exactly.
+    pub fn add_order_at(&mut self, mut order: Order, now: DateTime<Utc>) -> MatchResult {
+        // Expired orders must never trade, so sweep them before matching
+        let mut effects = MatchEffects {
+            now,
+            fees: Arc::clone(&self.fees),
+            cancelled: self.expire_orders(now),
+            ..MatchEffects::default()
+        };
+
+        if order.is_expired(now) {
+            order.reject(CancelReason::Expired);
+            return effects.into_result(order, Vec::new(), None);
+        }
 
-                // Add remaining order to book if not fully filled
-                if order.remaining_amount() > Decimal::ZERO {
-                    if let Some(price) = order.price {
-                        self.bids.entry(price).or_insert_with(VecDeque::new).push_back(order);
+        // Stops wait in the trigger book unless the last trade already reached them
+        if order.is_stop() {
+            match self.last_trade_price {
+                Some(last_price) if TriggerBook::is_triggered(&order, last_price) => order.trigger(),
+                _ => {
+                    if let Some(trigger_price) = order.trigger_price {
+                        let location = OrderLocation::Stop { side: order.order_type.clone(), trigger_price };
+                        self.index.insert(order.id, location);
+                    }
+                    if let Some(expires_at) = order.expires_at {
+                        self.expiries.insert((expires_at, order.id));
+                    }
+                    self.triggers.add_order(order.clone());
+                    return effects.into_result(order, Vec::new(), None);
+                }
+            }
+        }
+
+        // Post-only orders must never take liquidity
+        let mut repriced_from = None;
+        if order.post_only {
+            if let Some(slide_price) = self.post_only_slide_price(&order) {
+                match order.post_only_mode {
+                    PostOnlyMode::Slide if slide_price > Decimal::ZERO => {
+                        repriced_from = order.price.replace(slide_price);
+                        // A buy slid to a lower price needs less locked
+                        order.locked = order.locked.min(order.funds_required());
+                    }
+                    _ => {
+                        order.reject(CancelReason::PostOnly);
+                        return effects.into_result(order, Vec::new(), None);
                     }
                 }
             }
-            OrderType::Sell => {
-                // Match against bids (buy orders)
-                trades = self.match_sell_order(&mut order);
+        }
+
+        self.execute_order(&mut order, &mut effects);
+
+        // Every batch of trades can fire stops, whose own trades can fire more
+        let mut triggered = Vec::new();
+        let mut pending = VecDeque::new();
+        let mut checked_trades = 0;
+        loop {
+            if effects.trades.len() > checked_trades {
+                checked_trades = effects.trades.len();
+                if let Some(last_price) = self.last_trade_price {
+                    for stop_order in self.triggers.take_triggered(last_price) {
+                        // A triggered GTD stop limit is filed under its expiry again if it rests
+                        forget_order(&mut self.index, &mut self.expiries, &stop_order);
+                        pending.push_back(stop_order);
+                    }
+                }
+            }
+
+            let Some(mut stop_order) = pending.pop_front() else {
+                break;
+            };
+            stop_order.trigger();
+            self.execute_order(&mut stop_order, &mut effects);
+            triggered.push(stop_order);
+        }
+
+        effects.into_result(order, triggered, repriced_from)
+    }
+
+    /// Price one tick behind the opposite best if `order` would cross the book, `None` if it would rest.
+    fn post_only_slide_price(&self, order: &Order) -> Option<Decimal> {
+        let price = order.price?;
+        match order.order_type {
+            OrderType::Buy => self.get_best_ask().filter(|ask| price >= *ask).map(|ask| ask - self.tick_size),
+            OrderType::Sell => self.get_best_bid().filter(|bid| price <= *bid).map(|bid| bid + self.tick_size),
+        }
+    }
+
+    /// Matches a market or limit order and rests, cancels or rejects whatever is left.
+    fn execute_order(&mut self, order: &mut Order, effects: &mut MatchEffects) {
+        // Market orders trade no further than the collar around the best opposite price, and a
+        // market buy with funds locked pays no more per unit than they cover
+        let limit = if order.is_market() {
+            match self.market_collar_price(&order.order_type) {
+                Some(collar_price) if order.is_buy() && order.locked > Decimal::ZERO => {
+                    Some(collar_price.min(order.locked / order.remaining_amount()))
+                }
+                Some(collar_price) => Some(collar_price),
+                None => {
+                    order.reject(CancelReason::NoLiquidity);
+                    return;
+                }
+            }
+        } else {
+            order.price
+        };
+
+        // Fill-or-kill is all or nothing, so check liquidity before touching the book
+        if order.time_in_force == TimeInForce::Fok && self.fillable_amount(order, limit) < order.remaining_amount() {
+            order.reject(CancelReason::FillOrKill);
+            return;
+        }
+
+        let trades_before = effects.trades.len();
+        match order.order_type {
+            OrderType::Buy => self.match_buy_order(order, limit, effects), // Match against asks (sell orders)
+            OrderType::Sell => self.match_sell_order(order, limit, effects), // Match against bids (buy orders)
+        }
+
+        if effects.trades.len() > trades_before {
+            self.last_trade_price = effects.trades.last().map(|t| t.price);
+            for trade in &effects.trades[trades_before..] {
+                self.stats.record(trade);
+            }
+        }
+
+        // Add remaining order to book if not fully filled
+        if order.is_active() {
+            if order.is_market() {
+                // Anything left on the opposite side is beyond the collar
+                let opposite_empty = match order.order_type {
+                    OrderType::Buy => self.asks.is_empty(),
+                    OrderType::Sell => self.bids.is_empty(),
+                };
+                let reason = if opposite_empty {
+                    CancelReason::NoLiquidity
+                } else {
+                    CancelReason::SlippageCollar
+                };
+                order.cancel(reason);
+            } else {
+                self.close_limit_remainder(order);
+            }
+        }
+    }
+
+    /// Rests the unfilled part of a limit order, or cancels it if its time in force forbids resting.
+    fn close_limit_remainder(&mut self, order: &mut Order) {
+        match order.time_in_force {
+            TimeInForce::Ioc => order.cancel(CancelReason::ImmediateOrCancel),
+            // Liquidity is checked up front, so this only guards against a book that changed under us
+            TimeInForce::Fok => order.cancel(CancelReason::FillOrKill),
+            TimeInForce::Gtc | TimeInForce::Gtd => {
+                if let Some(price) = order.price {
+                    self.rest_order(price, order.clone());
+                }
+            }
+        }
+    }
+
+    /// Funds `order` must lock before it is submitted: base for a sell, quote for a buy.
+    ///
+    /// A buy with a limit price locks its full cost at that price. A market buy locks its amount
+    /// at the collar price, the most it can pay right now, and a stop buy locks its amount at the
+    /// collar above its trigger price.
+    pub fn funds_to_lock(&self, order: &Order) -> Decimal {
+        if order.is_sell() {
+            return order.amount;
+        }
+
+        let worst_price = match (order.price, order.trigger_price) {
+            (Some(price), _) => Some(price),
+            (None, Some(trigger_price)) => Some(trigger_price * (Decimal::ONE + self.market_collar)),
+            (None, None) => self.market_collar_price(&OrderType::Buy),
+        };
+        worst_price.map_or(Decimal::ZERO, |price| order.amount * price)
+    }
+
+    /// Best price an order on `order_type` could trade at right now: the best ask for a buy, the
+    /// best bid for a sell.
+    pub fn best_opposite_price(&self, order_type: &OrderType) -> Option<Decimal> {
+        match order_type {
+            OrderType::Buy => self.get_best_ask(),
+            OrderType::Sell => self.get_best_bid(),
+        }
+    }
+
+    /// Worst price a market order on `order_type` may trade at, or `None` if the opposite side is empty.
+    fn market_collar_price(&self, order_type: &OrderType) -> Option<Decimal> {
+        match order_type {
+            OrderType::Buy => self.best_opposite_price(order_type).map(|ask| ask * (Decimal::ONE + self.market_collar)),
+            OrderType::Sell => self.best_opposite_price(order_type).map(|bid| bid * (Decimal::ONE - self.market_collar)),
+        }
+    }
+
+    fn rest_order(&mut self, price: Decimal, mut order: Order) {
+        order.replenish();
+        order.queue_seq = self.next_queue_seq;
+        self.next_queue_seq += 1;
+
+        if let Some(expires_at) = order.expires_at {
+            self.expiries.insert((expires_at, order.id));
+        }
+        let location = OrderLocation::Resting { side: order.order_type.clone(), price, queue_seq: order.queue_seq };
+        self.index.insert(order.id, location);
+
+        self.changed.mark(&order.order_type, price);
+        self.changed.record(OrderChangeKind::Added, &order, price);
+        let side = match order.order_type {
+            OrderType::Buy => &mut self.bids,
+            OrderType::Sell => &mut self.asks,
+        };
+        side.entry(price).or_default().push_back(order);
+    }
+
+    /// Amount the opposite side could fill for `order` right now, capped at its remaining amount.
+    ///
+    /// The taker's own resting orders never fill it. Under [`SelfTradePrevention::CancelOldest`]
+    /// they are cancelled and matching carries on past them, so they are skipped; every other mode
+    /// stops or shrinks the taker when it reaches one, so nothing beyond it counts.
+    fn fillable_amount(&self, order: &Order, limit: Option<Decimal>) -> Decimal {
+        let remaining = order.remaining_amount();
+        let levels: Box<dyn Iterator<Item = (&Decimal, &VecDeque<Order>)>> = match order.order_type {
+            OrderType::Buy => Box::new(self.asks.iter()),
+            OrderType::Sell => Box::new(self.bids.iter().rev()),
+        };
+
+        let mut available = Decimal::ZERO;
+        for (price, orders) in levels {
+            let crosses = match (limit, &order.order_type) {
+                (Some(limit), OrderType::Buy) => *price <= limit,
+                (Some(limit), OrderType::Sell) => *price >= limit,
+                (None, _) => true,
+            };
+            if !crosses {
+                break;
+            }
 
-                // Add remaining order to book if not fully filled
-                if order.remaining_amount() > Decimal::ZERO {
-                    if let Some(price) = order.price {
-                        self.asks.entry(price).or_insert_with(VecDeque::new).push_back(order);
+            for maker in orders {
+                if maker.user_id == order.user_id {
+                    match order.self_trade_prevention.unwrap_or_default() {
+                        SelfTradePrevention::CancelOldest => continue,
+                        _ => return available,
                     }
                 }
+                available += maker.remaining_amount();
+                if available >= remaining {
+                    return remaining;
+                }
             }
         }
 
-        trades
+        available
     }
 
-    fn match_buy_order(&mut self, buy_order: &mut Order) -> Vec<Trade> {
-        let mut trades = Vec::new();
+    fn match_buy_order(&mut self, buy_order: &mut Order, limit: Option<Decimal>, effects: &mut MatchEffects) {
         let mut prices_to_remove = Vec::new();
 
         // Get all ask prices in ascending order
-        let ask_prices: Vec<&Decimal> = self.asks.keys().collect();
+        let ask_prices: Vec<Decimal> = self.asks.keys().copied().collect();
 
-        for &ask_price in ask_prices {
-            if buy_order.remaining_amount() <= Decimal::ZERO {
+        for ask_price in ask_prices {
+            if !buy_order.is_active() {
                 break;
             }
 
             // Check if buy order price is high enough for this ask
-            if let Some(buy_price) = buy_order.price {
-                if buy_price < *ask_price {
+            if let Some(buy_price) = limit {
+                if buy_price < ask_price {
                     break; // No more matches possible
                 }
             }
 
-            if let Some(ask_orders) = self.asks.get_mut(ask_price) {
+            self.changed.mark(&OrderType::Sell, ask_price);
+
+            if self.matching_policy != MatchingPolicy::Fifo {
+                let mut ask_orders = self.asks.remove(&ask_price).unwrap_or_default();
+                self.match_level_pro_rata(buy_order, &mut ask_orders, ask_price, effects);
+                if !ask_orders.is_empty() {
+                    self.asks.insert(ask_price, ask_orders);
+                }
+                continue;
+            }
+
+            if let Some(ask_orders) = self.asks.get_mut(&ask_price) {
                 while let Some(mut sell_order) = ask_orders.pop_front() {
-                    if buy_order.remaining_amount() <= Decimal::ZERO {
+                    if !buy_order.is_active() {
                         ask_orders.push_front(sell_order);
                         break;
                     }
 
-                    let trade_amount = buy_order.remaining_amount().min(sell_order.remaining_amount());
-                    let trade_price = *ask_price;
+                    // Never let a user trade with themselves
+                    if sell_order.user_id == buy_order.user_id {
+                        let visible = sell_order.visible_remaining();
+                        let keep_matching = prevent_self_trade(buy_order, &mut sell_order, effects);
+                        self.changed.record_prevented(&sell_order, visible, ask_price);
+                        if sell_order.is_active() {
+                            ask_orders.push_front(sell_order);
+                        } else {
+                            forget_order(&mut self.index, &mut self.expiries, &sell_order);
+                            effects.cancelled.push(sell_order);
+                        }
 
-                    // Create trade
-                    let trade = Trade::new(buy_order, &sell_order, trade_amount, trade_price);
-                    trades.push(trade);
+                        if keep_matching {
+                            continue;
+                        }
+                        break;
+                    }
+
+                    let trade_amount = buy_order.remaining_amount().min(sell_order.visible_remaining());
+                    let trade_price = ask_price;
 
-                    // Update orders
-                    buy_order.fill(trade_amount);
-                    sell_order.fill(trade_amount);
+                    execute_trade(buy_order, &mut sell_order, trade_amount, trade_price, effects);
 
                     // Put sell order back if not fully filled
-                    if sell_order.remaining_amount() > Decimal::ZERO {
+                    if sell_order.visible_remaining() > Decimal::ZERO {
+                        self.changed.record(OrderChangeKind::Reduced, &sell_order, ask_price);
                         ask_orders.push_front(sell_order);
                         break;
                     }
+                    self.changed.record(OrderChangeKind::Removed, &sell_order, ask_price);
+
+                    // An exhausted iceberg slice is replenished at the back of the queue
+                    if sell_order.remaining_amount() > Decimal::ZERO {
+                        sell_order.replenish();
+                        sell_order.queue_seq = self.next_queue_seq;
+                        self.next_queue_seq += 1;
+                        if let Some(OrderLocation::Resting { queue_seq, .. }) = self.index.get_mut(&sell_order.id) {
+                            *queue_seq = sell_order.queue_seq;
+                        }
+                        self.changed.record(OrderChangeKind::Added, &sell_order, ask_price);
+                        ask_orders.push_back(sell_order);
+                        continue;
+                    }
+
+                    forget_order(&mut self.index, &mut self.expiries, &sell_order);
                 }
 
                 if ask_orders.is_empty() {
-                    prices_to_remove.push(*ask_price);
+                    prices_to_remove.push(ask_price);
                 }
             }
         }
@@ -104,56 +576,93 @@ impl OrderBook {
         for price in prices_to_remove {
             self.asks.remove(&price);
         }
-
-        trades
     }
 
-    fn match_sell_order(&mut self, sell_order: &mut Order) -> Vec<Trade> {
-        let mut trades = Vec::new();
+    fn match_sell_order(&mut self, sell_order: &mut Order, limit: Option<Decimal>, effects: &mut MatchEffects) {
         let mut prices_to_remove = Vec::new();
 
         // Get all bid prices in descending order
-        let bid_prices: Vec<&Decimal> = self.bids.keys().rev().collect();
+        let bid_prices: Vec<Decimal> = self.bids.keys().rev().copied().collect();
 
-        for &bid_price in bid_prices {
-            if sell_order.remaining_amount() <= Decimal::ZERO {
+        for bid_price in bid_prices {
+            if !sell_order.is_active() {
                 break;
             }
 
             // Check if sell order price is low enough for this bid
-            if let Some(sell_price) = sell_order.price {
-                if sell_price > *bid_price {
+            if let Some(sell_price) = limit {
+                if sell_price > bid_price {
                     break; // No more matches possible
                 }
             }
 
-            if let Some(bid_orders) = self.bids.get_mut(bid_price) {
+            self.changed.mark(&OrderType::Buy, bid_price);
+
+            if self.matching_policy != MatchingPolicy::Fifo {
+                let mut bid_orders = self.bids.remove(&bid_price).unwrap_or_default();
+                self.match_level_pro_rata(sell_order, &mut bid_orders, bid_price, effects);
+                if !bid_orders.is_empty() {
+                    self.bids.insert(bid_price, bid_orders);
+                }
+                continue;
+            }
+
+            if let Some(bid_orders) = self.bids.get_mut(&bid_price) {
                 while let Some(mut buy_order) = bid_orders.pop_front() {
-                    if sell_order.remaining_amount() <= Decimal::ZERO {
+                    if !sell_order.is_active() {
                         bid_orders.push_front(buy_order);
                         break;
                     }
 
-                    let trade_amount = sell_order.remaining_amount().min(buy_order.remaining_amount());
-                    let trade_price = *bid_price;
+                    // Never let a user trade with themselves
+                    if buy_order.user_id == sell_order.user_id {
+                        let visible = buy_order.visible_remaining();
+                        let keep_matching = prevent_self_trade(sell_order, &mut buy_order, effects);
+                        self.changed.record_prevented(&buy_order, visible, bid_price);
+                        if buy_order.is_active() {
+                            bid_orders.push_front(buy_order);
+                        } else {
+                            forget_order(&mut self.index, &mut self.expiries, &buy_order);
+                            effects.cancelled.push(buy_order);
+                        }
+
+                        if keep_matching {
+                            continue;
+                        }
+                        break;
+                    }
 
-                    // Create trade
-                    let trade = Trade::new(&buy_order, sell_order, trade_amount, trade_price);
-                    trades.push(trade);
+                    let trade_amount = sell_order.remaining_amount().min(buy_order.visible_remaining());
+                    let trade_price = bid_price;
 
-                    // Update orders
-                    sell_order.fill(trade_amount);
-                    buy_order.fill(trade_amount);
+                    execute_trade(sell_order, &mut buy_order, trade_amount, trade_price, effects);
 
                     // Put buy order back if not fully filled
-                    if buy_order.remaining_amount() > Decimal::ZERO {
+                    if buy_order.visible_remaining() > Decimal::ZERO {
+                        self.changed.record(OrderChangeKind::Reduced, &buy_order, bid_price);
                         bid_orders.push_front(buy_order);
                         break;
                     }
+                    self.changed.record(OrderChangeKind::Removed, &buy_order, bid_price);
+
+                    // An exhausted iceberg slice is replenished at the back of the queue
+                    if buy_order.remaining_amount() > Decimal::ZERO {
+                        buy_order.replenish();
+                        buy_order.queue_seq = self.next_queue_seq;
+                        self.next_queue_seq += 1;
+                        if let Some(OrderLocation::Resting { queue_seq, .. }) = self.index.get_mut(&buy_order.id) {
+                            *queue_seq = buy_order.queue_seq;
+                        }
+                        self.changed.record(OrderChangeKind::Added, &buy_order, bid_price);
+                        bid_orders.push_back(buy_order);
+                        continue;
+                    }
+
+                    forget_order(&mut self.index, &mut self.expiries, &buy_order);
                 }
 
                 if bid_orders.is_empty() {
-                    prices_to_remove.push(*bid_price);
+                    prices_to_remove.push(bid_price);
                 }
             }
         }
@@ -162,30 +671,359 @@ impl OrderBook {
         for price in prices_to_remove {
             self.bids.remove(&price);
         }
+    }
 
-        trades
+    /// Matches `taker` against one price level under a pro-rata policy.
+    ///
+    /// Each round shares the taker's remaining amount across the displayed size of every order at
+    /// the level. Iceberg slices exhausted in a round are replenished at the back of the queue and
+    /// take part in the next round.
+    fn match_level_pro_rata(
+        &mut self,
+        taker: &mut Order,
+        makers: &mut VecDeque<Order>,
+        price: Decimal,
+        effects: &mut MatchEffects,
+    ) {
+        while taker.is_active() && !makers.is_empty() {
+            // Settle self-trades up front so the taker's own orders never receive a share
+            let mut eligible = VecDeque::with_capacity(makers.len());
+            while let Some(mut maker) = makers.pop_front() {
+                if maker.user_id == taker.user_id && taker.is_active() {
+                    let visible = maker.visible_remaining();
+                    prevent_self_trade(taker, &mut maker, effects);
+                    self.changed.record_prevented(&maker, visible, price);
+                    if !maker.is_active() {
+                        forget_order(&mut self.index, &mut self.expiries, &maker);
+                        effects.cancelled.push(maker);
+                        continue;
+                    }
+                }
+                eligible.push_back(maker);
+            }
+            *makers = eligible;
+
+            if !taker.is_active() {
+                break;
+            }
+
+            let sizes: Vec<Decimal> = makers.iter().map(|maker| maker.visible_remaining()).collect();
+            let allocations = self.matching_policy.allocate(&sizes, taker.remaining_amount(), self.lot_size);
+
+            let mut resting = VecDeque::with_capacity(makers.len());
+            let mut replenished = Vec::new();
+            for (mut maker, allocation) in makers.drain(..).zip(allocations) {
+                if allocation > Decimal::ZERO {
+                    execute_trade(taker, &mut maker, allocation, price, effects);
+                }
+
+                if maker.visible_remaining() > Decimal::ZERO {
+                    if allocation > Decimal::ZERO {
+                        self.changed.record(OrderChangeKind::Reduced, &maker, price);
+                    }
+                    resting.push_back(maker);
+                } else if maker.remaining_amount() > Decimal::ZERO {
+                    self.changed.record(OrderChangeKind::Removed, &maker, price);
+                    replenished.push(maker);
+                } else {
+                    self.changed.record(OrderChangeKind::Removed, &maker, price);
+                    forget_order(&mut self.index, &mut self.expiries, &maker);
+                }
+            }
+
+            // Exhausted iceberg slices are replenished at the back of the queue
+            for mut maker in replenished {
+                maker.replenish();
+                maker.queue_seq = self.next_queue_seq;
+                self.next_queue_seq += 1;
+                if let Some(OrderLocation::Resting { queue_seq, .. }) = self.index.get_mut(&maker.id) {
+                    *queue_seq = maker.queue_seq;
+                }
+                self.changed.record(OrderChangeKind::Added, &maker, price);
+                resting.push_back(maker);
+            }
+            *makers = resting;
+        }
     }
 
-    pub fn cancel_order(&mut self, order_id: Uuid) -> Option<Order> {
-        // Search in bids
-        for (_, orders) in self.bids.iter_mut() {
-            if let Some(pos) = orders.iter().position(|o| o.id == order_id) {
-                let mut order = orders.remove(pos).unwrap();
-                order.cancel();
-                return Some(order);
+    /// Whether any GTD order is due to expire at or before `now`.
+    pub fn has_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expiries.first().is_some_and(|(expires_at, _)| *expires_at <= now)
+    }
+
+    /// Removes every GTD order whose expiry is at or before `now`.
+    pub fn expire_orders(&mut self, now: DateTime<Utc>) -> Vec<Order> {
+        let mut expired = Vec::new();
+
+        while let Some(&(expires_at, order_id)) = self.expiries.first() {
+            if expires_at > now {
+                break;
+            }
+
+            if let Some(mut order) = self.remove_order(order_id) {
+                order.cancel(CancelReason::Expired);
+                expired.push(order);
+            } else {
+                self.expiries.remove(&(expires_at, order_id));
+            }
+        }
+
+        expired
+    }
+
+    /// Total visible size resting at `price` on `side`, zero if there is no such level.
+    pub fn level_size(&self, side: &OrderType, price: Decimal) -> Decimal {
+        let levels = match side {
+            OrderType::Buy => &self.bids,
+            OrderType::Sell => &self.asks,
+        };
+        levels.get(&price).map_or(Decimal::ZERO, level_total)
+    }
+
+    /// Every price level on `side` with its total visible size, lowest price first.
+    pub fn depth(&self, side: &OrderType) -> Vec<(Decimal, Decimal)> {
+        let levels = match side {
+            OrderType::Buy => &self.bids,
+            OrderType::Sell => &self.asks,
+        };
+        levels.iter().map(|(price, orders)| (*price, level_total(orders))).collect()
+    }
+
+    /// Aggregate visible size of every price level touched since the last call, bids first.
+    /// A size of zero means the level is gone.
+    pub fn take_changed_levels(&mut self) -> Vec<(OrderType, Decimal, Decimal)> {
+        let changed_bids = std::mem::take(&mut self.changed.bids);
+        let changed_asks = std::mem::take(&mut self.changed.asks);
+
+        let bids = changed_bids.into_iter().rev().map(|price| (OrderType::Buy, price, self.level_size(&OrderType::Buy, price)));
+        let asks = changed_asks.into_iter().map(|price| (OrderType::Sell, price, self.level_size(&OrderType::Sell, price)));
+        bids.chain(asks).collect()
+    }
+
+    /// Changes to individual resting orders since the last call, in the order they happened.
+    pub fn take_order_changes(&mut self) -> Vec<OrderChange> {
+        std::mem::take(&mut self.changed.orders)
+    }
+
+    /// Copies out the book's orders and matching state. Pair settings are not included.
+    pub fn state(&self) -> BookState {
+        BookState {
+            pair: self.pair.clone(),
+            bids: self.bids.clone(),
+            asks: self.asks.clone(),
+            triggers: self.triggers.clone(),
+            last_trade_price: self.last_trade_price,
+            stats: self.stats.clone(),
+            last_input_seq: self.last_input_seq,
+            event_seq: self.event_seq,
+            depth_seq: self.depth_seq,
+            l3_seq: self.l3_seq,
+            next_queue_seq: self.next_queue_seq,
+        }
+    }
+
+    /// Replaces the book's orders with `state`, rebuilding the order index and expiry queue.
+    pub fn restore_state(&mut self, state: BookState) {
+        self.bids = state.bids;
+        self.asks = state.asks;
+        self.triggers = state.triggers;
+        self.last_trade_price = state.last_trade_price;
+        self.stats = state.stats;
+        self.last_input_seq = state.last_input_seq;
+        self.event_seq = state.event_seq;
+        self.depth_seq = state.depth_seq;
+        self.l3_seq = state.l3_seq;
+        self.changed = BookChanges::default();
+        self.next_queue_seq = state.next_queue_seq;
+        self.index.clear();
+        self.expiries.clear();
+
+        for (price, orders) in self.bids.iter().chain(self.asks.iter()) {
+            for order in orders {
+                let location = OrderLocation::Resting { side: order.order_type.clone(), price: *price, queue_seq: order.queue_seq };
+                self.index.insert(order.id, location);
+                if let Some(expires_at) = order.expires_at {
+                    self.expiries.insert((expires_at, order.id));
+                }
+            }
+        }
+
+        for (trigger_price, orders) in self.triggers.buy_stops.iter().chain(self.triggers.sell_stops.iter()) {
+            for order in orders {
+                let location = OrderLocation::Stop { side: order.order_type.clone(), trigger_price: *trigger_price };
+                self.index.insert(order.id, location);
+                if let Some(expires_at) = order.expires_at {
+                    self.expiries.insert((expires_at, order.id));
+                }
+            }
+        }
+    }
+
+    /// Ids of every live order, resting or waiting on a trigger.
+    pub fn order_ids(&self) -> impl Iterator<Item = Uuid> + '_ {
+        self.index.keys().copied()
+    }
+
+    /// Takes a live order out of the book or the trigger book.
+    ///
+    /// Finding the order is a hash lookup plus a binary search of its price level by queue
+    /// sequence, O(log n) in the level's depth. Taking it out of the level's `VecDeque` shifts the
+    /// orders on the shorter side of it, so removal is O(n) in the worst case: an order in the
+    /// middle of a level of n orders moves n/2 of them. Orders at either end of the queue, the
+    /// common case for cancels, come out in O(1).
+    fn remove_order(&mut self, order_id: Uuid) -> Option<Order> {
+        let order = match self.index.get(&order_id)? {
+            OrderLocation::Resting { side, price, queue_seq } => {
+                let (price, queue_seq) = (*price, *queue_seq);
+                self.changed.mark(side, price);
+                let levels = match side {
+                    OrderType::Buy => &mut self.bids,
+                    OrderType::Sell => &mut self.asks,
+                };
+
+                let orders = levels.get_mut(&price)?;
+                let pos = orders.binary_search_by_key(&queue_seq, |o| o.queue_seq).ok()?;
+                let order = orders.remove(pos)?;
+                self.changed.record(OrderChangeKind::Removed, &order, price);
+
+                if orders.is_empty() {
+                    levels.remove(&price);
+                }
+
+                Some(order)
+            }
+            OrderLocation::Stop { side, trigger_price } => {
+                let (side, trigger_price) = (side.clone(), *trigger_price);
+                self.triggers.remove_order(&side, trigger_price, order_id)
+            }
+        }?;
+
+        forget_order(&mut self.index, &mut self.expiries, &order);
+        Some(order)
+    }
+
+    pub fn get_order(&self, order_id: Uuid) -> Option<&Order> {
+        match self.index.get(&order_id)? {
+            OrderLocation::Resting { side, price, queue_seq } => {
+                let levels = match side {
+                    OrderType::Buy => &self.bids,
+                    OrderType::Sell => &self.asks,
+                };
+
+                let orders = levels.get(price)?;
+                let pos = orders.binary_search_by_key(queue_seq, |o| o.queue_seq).ok()?;
+                orders.get(pos)
+            }
+            OrderLocation::Stop { side, trigger_price } => {
+                let stops = match side {
+                    OrderType::Buy => &self.triggers.buy_stops,
+                    OrderType::Sell => &self.triggers.sell_stops,
+                };
+
+                stops.get(trigger_price)?.iter().find(|o| o.id == order_id)
+            }
+        }
+    }
+
+    pub fn get_order_location(&self, order_id: Uuid) -> Option<&OrderLocation> {
+        self.index.get(&order_id)
+    }
+
+    /// Changes the total amount and/or price of a resting order.
+    ///
+    /// Reducing the amount keeps queue priority. A new price or a larger amount sends the order
+    /// to the back of its level, matching it first if it now crosses the book. The book is left
+    /// untouched when the amend is rejected.
+    pub fn amend_order(
+        &mut self,
+        order_id: Uuid,
+        new_amount: Option<Decimal>,
+        new_price: Option<Decimal>,
+    ) -> Result<AmendResult, AmendError> {
+        self.amend_order_at(order_id, new_amount, new_price, Utc::now())
+    }
+
+    /// Amends a resting order as of `now`, see [`OrderBook::add_order_at`].
+    pub fn amend_order_at(
+        &mut self,
+        order_id: Uuid,
+        new_amount: Option<Decimal>,
+        new_price: Option<Decimal>,
+        now: DateTime<Utc>,
+    ) -> Result<AmendResult, AmendError> {
+        let (side, price, queue_seq) = match self.index.get(&order_id) {
+            Some(OrderLocation::Resting { side, price, queue_seq }) => (side.clone(), *price, *queue_seq),
+            Some(OrderLocation::Stop { .. }) => {
+                return Err(AmendError::Invalid("Untriggered stop orders cannot be amended".to_string()));
             }
+            None => return Err(AmendError::OrderNotFound),
+        };
+        let levels = match side {
+            OrderType::Buy => &mut self.bids,
+            OrderType::Sell => &mut self.asks,
+        };
+        let orders = levels.get_mut(&price).ok_or(AmendError::OrderNotFound)?;
+        let pos = orders.binary_search_by_key(&queue_seq, |o| o.queue_seq).map_err(|_| AmendError::OrderNotFound)?;
+
+        let current = &orders[pos];
+        let old_amount = current.amount;
+        let amount = new_amount.unwrap_or(current.amount);
+        let amended_price = new_price.unwrap_or(price);
+
+        if amount <= current.filled {
+            return Err(AmendError::Invalid("Amended amount must exceed the filled amount".to_string()));
+        }
+        if amended_price <= Decimal::ZERO {
+            return Err(AmendError::Invalid("Amended price must be positive".to_string()));
+        }
+        if matches!(current.display_amount, Some(display_amount) if display_amount >= amount) {
+            return Err(AmendError::Invalid("Amended amount must exceed the display amount".to_string()));
         }
 
-        // Search in asks
-        for (_, orders) in self.asks.iter_mut() {
-            if let Some(pos) = orders.iter().position(|o| o.id == order_id) {
-                let mut order = orders.remove(pos).unwrap();
-                order.cancel();
-                return Some(order);
+        // Reducing size in place keeps the order's place in the queue
+        if amended_price == price && amount <= current.amount {
+            self.changed.mark(&side, price);
+            let order = &mut orders[pos];
+            order.amount = amount;
+            order.locked = order.funds_required();
+            if order.is_iceberg() {
+                order.visible_amount = order.visible_amount.min(order.remaining_amount());
             }
+            self.changed.record(OrderChangeKind::Reduced, order, price);
+
+            return Ok(AmendResult {
+                kept_priority: true,
+                old_amount,
+                old_price: price,
+                result: MatchEffects::default().into_result(order.clone(), Vec::new(), None),
+            });
         }
 
-        None
+        let mut amended = current.clone();
+        amended.amount = amount;
+        amended.price = Some(amended_price);
+        amended.locked = amended.funds_required();
+        if amended.post_only && amended.post_only_mode == PostOnlyMode::Reject && self.post_only_slide_price(&amended).is_some() {
+            return Err(AmendError::Invalid("Post-only amend would take liquidity".to_string()));
+        }
+
+        // Anything else is a fresh aggression from the back of the queue
+        self.remove_order(order_id);
+
+        Ok(AmendResult {
+            kept_priority: false,
+            old_amount,
+            old_price: price,
+            result: self.add_order_at(amended, now),
+        })
+    }
+
+    /// Cancels a live order, at the cost of `remove_order`: O(n) in the depth of its price
+    /// level in the worst case.
+    pub fn cancel_order(&mut self, order_id: Uuid) -> Option<Order> {
+        let mut order = self.remove_order(order_id)?;
+        order.cancel(CancelReason::UserRequested);
+        Some(order)
     }
 
     pub fn get_best_bid(&self) -> Option<Decimal> {
@@ -203,3 +1041,548 @@ impl OrderBook {
         }
     }
 }
+
+impl MatchEffects {
+    fn into_result(self, order: Order, triggered: Vec<Order>, repriced_from: Option<Decimal>) -> MatchResult {
+        MatchResult {
+            order,
+            trades: self.trades,
+            cancelled: self.cancelled,
+            triggered,
+            repriced_from,
+            self_trades_prevented: self.self_trades_prevented,
+            fills: self.fills,
+        }
+    }
+}
+
+/// What a price level shows in the aggregated book: hidden iceberg size is left out.
+fn level_total(orders: &VecDeque<Order>) -> Decimal {
+    orders.iter().map(|o| o.visible_remaining()).sum()
+}
+
+/// Trades `amount` at `price` between the incoming `taker` and the resting `maker`.
+fn execute_trade(taker: &mut Order, maker: &mut Order, amount: Decimal, price: Decimal, effects: &mut MatchEffects) {
+    let mut trade = match taker.order_type {
+        OrderType::Buy => Trade::new(taker, maker, amount, price, OrderType::Sell, effects.now),
+        OrderType::Sell => Trade::new(maker, taker, amount, price, OrderType::Buy, effects.now),
+    };
+    effects.fees.charge(&mut trade, maker.fee_tier, taker.fee_tier);
+
+    taker.fill(amount, price);
+    maker.fill(amount, price);
+
+    effects.fills.push(Fill::new(taker, &trade, false));
+    effects.fills.push(Fill::new(maker, &trade, true));
+    effects.trades.push(trade);
+}
+
+/// Applies the taker's self-trade prevention mode to a taker/maker pair owned by the same user.
+///
+/// Returns whether the taker may keep matching against the rest of the book.
+fn prevent_self_trade(taker: &mut Order, maker: &mut Order, effects: &mut MatchEffects) -> bool {
+    let mode = taker.self_trade_prevention.unwrap_or_default();
+    let prevented_amount = taker.remaining_amount().min(maker.remaining_amount());
+    effects.self_trades_prevented.push(SelfTradePrevented::new(taker, maker, mode, prevented_amount, effects.now));
+
+    match mode {
+        SelfTradePrevention::CancelNewest => taker.cancel(CancelReason::SelfTradePrevention),
+        SelfTradePrevention::CancelOldest => maker.cancel(CancelReason::SelfTradePrevention),
+        SelfTradePrevention::CancelBoth => {
+            taker.cancel(CancelReason::SelfTradePrevention);
+            maker.cancel(CancelReason::SelfTradePrevention);
+        }
+        SelfTradePrevention::DecrementAndCancel => {
+            taker.decrement(prevented_amount);
+            maker.decrement(prevented_amount);
+        }
+    }
+
+    taker.is_active()
+}
+
+/// Drops the index and expiry entries of an order that has left the book.
+fn forget_order(index: &mut HashMap<Uuid, OrderLocation>, expiries: &mut BTreeSet<(DateTime<Utc>, Uuid)>, order: &Order) {
+    index.remove(&order.id);
+    if let Some(expires_at) = order.expires_at {
+        expiries.remove(&(expires_at, order.id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order::{OrderKind, OrderStatus};
+    use chrono::Duration;
+
+    fn limit(user_id: Uuid, order_type: OrderType, amount: i64, price: i64) -> Order {
+        Order::new(user_id, "BTC/USDT".to_string(), order_type, OrderKind::Limit, amount.into(), Some(price.into()))
+    }
+
+    fn market(user_id: Uuid, order_type: OrderType, amount: i64) -> Order {
+        Order::new(user_id, "BTC/USDT".to_string(), order_type, OrderKind::Market, amount.into(), None)
+    }
+
+    fn fok(mut order: Order) -> Order {
+        order.time_in_force = TimeInForce::Fok;
+        order
+    }
+
+    fn gtd(mut order: Order, expires_at: DateTime<Utc>) -> Order {
+        order.time_in_force = TimeInForce::Gtd;
+        order.expires_at = Some(expires_at);
+        order
+    }
+
+    #[test]
+    fn fok_fills_completely_across_levels() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 2, 100));
+        book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 3, 101));
+
+        let result = book.add_order(fok(limit(Uuid::new_v4(), OrderType::Buy, 5, 101)));
+
+        assert_eq!(result.order.status, OrderStatus::Filled);
+        assert_eq!(result.trades.iter().map(|trade| trade.amount).sum::<Decimal>(), Decimal::from(5));
+        assert!(book.asks.is_empty());
+    }
+
+    #[test]
+    fn fok_leaves_the_book_alone_when_it_cannot_fill() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 2, 100));
+        book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 3, 102));
+
+        // Enough size on the book, but not inside the limit price
+        let result = book.add_order(fok(limit(Uuid::new_v4(), OrderType::Buy, 5, 101)));
+
+        assert_eq!(result.order.status, OrderStatus::Rejected);
+        assert_eq!(result.order.cancel_reason, Some(CancelReason::FillOrKill));
+        assert!(result.trades.is_empty());
+        assert_eq!(book.level_size(&OrderType::Sell, 100.into()), Decimal::from(2));
+        assert_eq!(book.level_size(&OrderType::Sell, 102.into()), Decimal::from(3));
+    }
+
+    #[test]
+    fn gtd_orders_expire_at_their_expiry() {
+        let now = Utc::now();
+        let (early_expiry, late_expiry) = (now + Duration::seconds(5), now + Duration::seconds(10));
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        let early = book.add_order(gtd(limit(Uuid::new_v4(), OrderType::Buy, 1, 99), early_expiry)).order;
+        let late = book.add_order(gtd(limit(Uuid::new_v4(), OrderType::Buy, 2, 99), late_expiry)).order;
+        book.add_order(limit(Uuid::new_v4(), OrderType::Buy, 3, 98));
+
+        assert!(book.expire_orders(now).is_empty());
+
+        let expired = book.expire_orders(early_expiry);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, early.id);
+        assert_eq!(expired[0].status, OrderStatus::Cancelled);
+        assert_eq!(expired[0].cancel_reason, Some(CancelReason::Expired));
+        assert_eq!(book.level_size(&OrderType::Buy, 99.into()), Decimal::from(2));
+
+        let expired = book.expire_orders(late_expiry);
+        assert_eq!(expired.iter().map(|order| order.id).collect::<Vec<_>>(), vec![late.id]);
+        assert_eq!(book.level_size(&OrderType::Buy, 99.into()), Decimal::ZERO);
+        assert_eq!(book.level_size(&OrderType::Buy, 98.into()), Decimal::from(3));
+    }
+
+    #[test]
+    fn expired_gtd_orders_never_trade() {
+        let expiry = Utc::now() + Duration::seconds(5);
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        let maker = book.add_order(gtd(limit(Uuid::new_v4(), OrderType::Sell, 1, 100), expiry)).order;
+
+        let result = book.add_order_at(limit(Uuid::new_v4(), OrderType::Buy, 1, 100), expiry);
+
+        assert!(result.trades.is_empty());
+        assert_eq!(result.cancelled.iter().map(|order| order.id).collect::<Vec<_>>(), vec![maker.id]);
+        assert_eq!(result.order.status, OrderStatus::Pending);
+        assert!(book.asks.is_empty());
+    }
+
+    #[test]
+    fn unfilled_remainders_are_cancelled_by_time_in_force() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+
+        let mut ioc = limit(Uuid::new_v4(), OrderType::Buy, 5, 100);
+        ioc.time_in_force = TimeInForce::Ioc;
+        ioc.fill(2.into(), 100.into());
+        book.close_limit_remainder(&mut ioc);
+        assert_eq!(ioc.cancel_reason, Some(CancelReason::ImmediateOrCancel));
+
+        let mut fok = fok(limit(Uuid::new_v4(), OrderType::Buy, 5, 100));
+        fok.fill(2.into(), 100.into());
+        book.close_limit_remainder(&mut fok);
+        assert_eq!(fok.status, OrderStatus::Cancelled);
+        assert_eq!(fok.cancel_reason, Some(CancelReason::FillOrKill));
+
+        assert!(book.bids.is_empty());
+    }
+
+    #[test]
+    fn gtd_stops_expire_out_of_the_trigger_book() {
+        let now = Utc::now();
+        let expiry = now + Duration::seconds(5);
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        let mut stop = gtd(limit(Uuid::new_v4(), OrderType::Buy, 1, 105), expiry);
+        stop.order_kind = OrderKind::StopLimit;
+        stop.trigger_price = Some(105.into());
+        let stop = book.add_order_at(stop, now).order;
+        assert!(book.get_order_location(stop.id).is_some());
+
+        let expired = book.expire_orders(expiry);
+
+        assert_eq!(expired.iter().map(|order| order.id).collect::<Vec<_>>(), vec![stop.id]);
+        assert_eq!(expired[0].cancel_reason, Some(CancelReason::Expired));
+        assert!(book.get_order_location(stop.id).is_none());
+        assert!(book.triggers.buy_stops.is_empty());
+    }
+
+    #[test]
+    fn triggered_gtd_stops_rest_until_their_expiry() {
+        let now = Utc::now();
+        let expiry = now + Duration::seconds(5);
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        let mut stop = gtd(limit(Uuid::new_v4(), OrderType::Buy, 1, 99), expiry);
+        stop.order_kind = OrderKind::StopLimit;
+        stop.trigger_price = Some(100.into());
+        let stop = book.add_order_at(stop, now).order;
+
+        // A trade at the trigger price fires the stop, which rests as a limit at 99
+        book.add_order_at(limit(Uuid::new_v4(), OrderType::Sell, 1, 100), now);
+        let result = book.add_order_at(limit(Uuid::new_v4(), OrderType::Buy, 1, 100), now);
+        assert_eq!(result.triggered.iter().map(|order| order.id).collect::<Vec<_>>(), vec![stop.id]);
+        assert_eq!(book.level_size(&OrderType::Buy, 99.into()), Decimal::ONE);
+
+        assert!(book.expire_orders(now).is_empty());
+        let expired = book.expire_orders(expiry);
+        assert_eq!(expired.iter().map(|order| order.id).collect::<Vec<_>>(), vec![stop.id]);
+        assert_eq!(book.level_size(&OrderType::Buy, 99.into()), Decimal::ZERO);
+    }
+
+    #[test]
+    fn fok_does_not_count_own_resting_orders() {
+        let user = Uuid::new_v4();
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 2, 100));
+        book.add_order(limit(user, OrderType::Sell, 3, 100));
+
+        let result = book.add_order(fok(limit(user, OrderType::Buy, 5, 100)));
+
+        assert_eq!(result.order.status, OrderStatus::Rejected);
+        assert_eq!(result.order.cancel_reason, Some(CancelReason::FillOrKill));
+        assert!(result.trades.is_empty());
+        assert_eq!(book.level_size(&OrderType::Sell, 100.into()), Decimal::from(5));
+    }
+
+    #[test]
+    fn fok_skips_own_orders_it_would_cancel() {
+        let user = Uuid::new_v4();
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        book.add_order(limit(user, OrderType::Sell, 3, 100));
+        book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 5, 101));
+
+        let mut order = fok(limit(user, OrderType::Buy, 5, 101));
+        order.self_trade_prevention = Some(SelfTradePrevention::CancelOldest);
+        let result = book.add_order(order);
+
+        assert_eq!(result.order.status, OrderStatus::Filled);
+        assert_eq!(result.trades.len(), 1);
+        assert!(book.asks.is_empty());
+    }
+
+    #[test]
+    fn market_orders_trade_no_further_than_the_collar() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 1, 100));
+        book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 1, 104));
+        book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 1, 106));
+
+        // The default 5% collar around the best ask of 100 stops at 105
+        let result = book.add_order(market(Uuid::new_v4(), OrderType::Buy, 3));
+
+        assert_eq!(result.trades.iter().map(|trade| trade.price).collect::<Vec<_>>(), vec![Decimal::from(100), Decimal::from(104)]);
+        assert_eq!(result.order.filled, Decimal::from(2));
+        assert_eq!(result.order.status, OrderStatus::Cancelled);
+        assert_eq!(result.order.cancel_reason, Some(CancelReason::SlippageCollar));
+        assert_eq!(book.level_size(&OrderType::Sell, 106.into()), Decimal::ONE);
+    }
+
+    #[test]
+    fn market_orders_need_liquidity() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+
+        let result = book.add_order(market(Uuid::new_v4(), OrderType::Sell, 1));
+        assert_eq!(result.order.status, OrderStatus::Rejected);
+        assert_eq!(result.order.cancel_reason, Some(CancelReason::NoLiquidity));
+
+        // Whatever the opposite side cannot fill is cancelled, never rested
+        book.add_order(limit(Uuid::new_v4(), OrderType::Buy, 1, 100));
+        let result = book.add_order(market(Uuid::new_v4(), OrderType::Sell, 3));
+        assert_eq!(result.order.filled, Decimal::ONE);
+        assert_eq!(result.order.cancel_reason, Some(CancelReason::NoLiquidity));
+        assert!(book.bids.is_empty());
+        assert!(book.asks.is_empty());
+    }
+
+    #[test]
+    fn market_buys_pay_no_more_than_their_locked_funds() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 1, 100));
+        book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 1, 102));
+
+        // 201 covers two units at no more than 100.5 each, so the 102 ask is out of reach
+        let mut order = market(Uuid::new_v4(), OrderType::Buy, 2);
+        order.locked = Decimal::from(201);
+        let result = book.add_order(order);
+
+        assert_eq!(result.trades.len(), 1);
+        assert_eq!(result.trades[0].price, Decimal::from(100));
+        assert_eq!(result.order.cancel_reason, Some(CancelReason::SlippageCollar));
+        assert_eq!(book.level_size(&OrderType::Sell, 102.into()), Decimal::ONE);
+    }
+
+    fn stop(mut order: Order, trigger_price: i64) -> Order {
+        order.order_kind = match order.order_kind {
+            OrderKind::Market => OrderKind::Stop,
+            _ => OrderKind::StopLimit,
+        };
+        order.trigger_price = Some(trigger_price.into());
+        order
+    }
+
+    #[test]
+    fn stops_wait_for_a_trade_at_their_trigger() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 1, 100));
+        book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 2, 101));
+        let stop = book.add_order(stop(market(Uuid::new_v4(), OrderType::Buy, 1), 101)).order;
+        assert_eq!(book.triggers.len(), 1);
+
+        // A trade below the trigger leaves the stop waiting
+        let result = book.add_order(limit(Uuid::new_v4(), OrderType::Buy, 1, 100));
+        assert!(result.triggered.is_empty());
+
+        // A trade at the trigger fires it, and it buys what is left at 101
+        let result = book.add_order(limit(Uuid::new_v4(), OrderType::Buy, 1, 101));
+        assert_eq!(result.triggered.len(), 1);
+        assert_eq!(result.triggered[0].id, stop.id);
+        assert_eq!(result.triggered[0].order_kind, OrderKind::Market);
+        assert_eq!(result.triggered[0].status, OrderStatus::Filled);
+        assert_eq!(result.trades.len(), 2);
+        assert!(book.triggers.is_empty());
+        assert!(book.asks.is_empty());
+    }
+
+    #[test]
+    fn stops_already_reached_execute_on_arrival() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        book.add_order(limit(Uuid::new_v4(), OrderType::Buy, 2, 100));
+        book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 1, 100));
+        assert_eq!(book.last_trade_price, Some(100.into()));
+
+        let result = book.add_order(stop(limit(Uuid::new_v4(), OrderType::Sell, 1, 100), 101));
+
+        assert_eq!(result.order.order_kind, OrderKind::Limit);
+        assert_eq!(result.order.status, OrderStatus::Filled);
+        assert!(book.triggers.is_empty());
+    }
+
+    #[test]
+    fn triggered_stops_can_fire_further_stops() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        book.add_order(limit(Uuid::new_v4(), OrderType::Buy, 1, 100));
+        book.add_order(limit(Uuid::new_v4(), OrderType::Buy, 1, 98));
+        book.add_order(limit(Uuid::new_v4(), OrderType::Buy, 1, 96));
+        let first = book.add_order(stop(market(Uuid::new_v4(), OrderType::Sell, 1), 100)).order;
+        let second = book.add_order(stop(market(Uuid::new_v4(), OrderType::Sell, 1), 98)).order;
+
+        // The trade at 100 fires the first stop, whose trade at 98 fires the second
+        let result = book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 1, 100));
+
+        assert_eq!(result.triggered.iter().map(|order| order.id).collect::<Vec<_>>(), vec![first.id, second.id]);
+        assert_eq!(result.trades.iter().map(|trade| trade.price).collect::<Vec<_>>(), vec![Decimal::from(100), Decimal::from(98), Decimal::from(96)]);
+        assert!(book.bids.is_empty());
+    }
+
+    #[test]
+    fn cancelled_stops_leave_the_trigger_book() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        let stop = book.add_order(stop(limit(Uuid::new_v4(), OrderType::Buy, 1, 102), 101)).order;
+
+        assert_eq!(book.cancel_order(stop.id).map(|order| order.id), Some(stop.id));
+        assert!(book.triggers.is_empty());
+        assert!(book.get_order_location(stop.id).is_none());
+    }
+
+    fn post_only(mut order: Order, mode: PostOnlyMode) -> Order {
+        order.post_only = true;
+        order.post_only_mode = mode;
+        order
+    }
+
+    #[test]
+    fn post_only_orders_that_would_cross_are_rejected() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 1, 100));
+
+        let result = book.add_order(post_only(limit(Uuid::new_v4(), OrderType::Buy, 1, 100), PostOnlyMode::Reject));
+
+        assert_eq!(result.order.status, OrderStatus::Rejected);
+        assert_eq!(result.order.cancel_reason, Some(CancelReason::PostOnly));
+        assert!(result.trades.is_empty());
+        assert!(book.bids.is_empty());
+        assert_eq!(book.level_size(&OrderType::Sell, 100.into()), Decimal::ONE);
+    }
+
+    #[test]
+    fn sliding_post_only_orders_rest_one_tick_behind_the_opposite_best() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 1, 100));
+        book.add_order(limit(Uuid::new_v4(), OrderType::Buy, 1, 90));
+
+        let mut buy = post_only(limit(Uuid::new_v4(), OrderType::Buy, 1, 101), PostOnlyMode::Slide);
+        buy.locked = Decimal::from(101);
+        let result = book.add_order(buy);
+        let slid_price = Decimal::new(9_999, 2);
+        assert!(result.trades.is_empty());
+        assert_eq!(result.repriced_from, Some(101.into()));
+        assert_eq!(result.order.price, Some(slid_price));
+        assert_eq!(result.order.locked, slid_price);
+        assert_eq!(book.level_size(&OrderType::Buy, slid_price), Decimal::ONE);
+
+        let result = book.add_order(post_only(limit(Uuid::new_v4(), OrderType::Sell, 1, 80), PostOnlyMode::Slide));
+        assert_eq!(result.order.price, Some(Decimal::new(10_000, 2)));
+        assert_eq!(book.level_size(&OrderType::Sell, 100.into()), Decimal::from(2));
+    }
+
+    #[test]
+    fn post_only_orders_that_do_not_cross_rest_unchanged() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 1, 100));
+
+        let result = book.add_order(post_only(limit(Uuid::new_v4(), OrderType::Buy, 1, 99), PostOnlyMode::Slide));
+
+        assert_eq!(result.repriced_from, None);
+        assert_eq!(result.order.price, Some(99.into()));
+        assert_eq!(book.level_size(&OrderType::Buy, 99.into()), Decimal::ONE);
+    }
+
+    fn iceberg(mut order: Order, display_amount: i64) -> Order {
+        order.display_amount = Some(display_amount.into());
+        order
+    }
+
+    #[test]
+    fn icebergs_show_only_their_display_amount() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        let order = book.add_order(iceberg(limit(Uuid::new_v4(), OrderType::Sell, 10, 100), 2)).order;
+
+        assert_eq!(book.get_order(order.id).map(|order| order.visible_amount), Some(Decimal::from(2)));
+        assert_eq!(book.level_size(&OrderType::Sell, 100.into()), Decimal::from(2));
+        assert_eq!(book.depth(&OrderType::Sell), vec![(Decimal::from(100), Decimal::from(2))]);
+    }
+
+    #[test]
+    fn exhausted_iceberg_slices_refresh_at_the_back_of_the_queue() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        let hidden = book.add_order(iceberg(limit(Uuid::new_v4(), OrderType::Sell, 5, 100), 2)).order;
+        let plain = book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 1, 100)).order;
+        let plain_queue_seq = book.get_order(plain.id).unwrap().queue_seq;
+
+        // The first slice trades, then the plain order ahead of the refreshed slice
+        let result = book.add_order(limit(Uuid::new_v4(), OrderType::Buy, 3, 100));
+        assert_eq!(
+            result.trades.iter().map(|trade| (trade.sell_order_id, trade.amount)).collect::<Vec<_>>(),
+            vec![(hidden.id, Decimal::from(2)), (plain.id, Decimal::ONE)]
+        );
+
+        let level = &book.asks[&Decimal::from(100)];
+        assert_eq!(level.len(), 1);
+        assert_eq!(level[0].remaining_amount(), Decimal::from(3));
+        assert_eq!(level[0].visible_amount, Decimal::from(2));
+        assert!(level[0].queue_seq > plain_queue_seq);
+
+        // The refreshed slice is still found by id
+        assert_eq!(book.cancel_order(hidden.id).map(|order| order.remaining_amount()), Some(Decimal::from(3)));
+        assert!(book.asks.is_empty());
+    }
+
+    #[test]
+    fn takers_trade_through_successive_iceberg_slices() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        book.add_order(iceberg(limit(Uuid::new_v4(), OrderType::Buy, 5, 100), 2));
+
+        let result = book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 5, 100));
+
+        assert_eq!(result.order.status, OrderStatus::Filled);
+        assert_eq!(
+            result.trades.iter().map(|trade| trade.amount).collect::<Vec<_>>(),
+            vec![Decimal::from(2), Decimal::from(2), Decimal::ONE]
+        );
+        assert!(book.bids.is_empty());
+    }
+
+    #[test]
+    fn pro_rata_sweeps_levels_best_price_first_and_shares_within_each() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        book.matching_policy = MatchingPolicy::ProRata;
+        book.lot_size = Decimal::ONE;
+        let mut sell = |amount, price| book.add_order(limit(Uuid::new_v4(), OrderType::Sell, amount, price)).order;
+        let near = [sell(1, 100), sell(1, 100)];
+        let far = [sell(2, 101), sell(6, 101)];
+
+        // The 100 level fills whole, then the 4 left is shared 1:3 at 101
+        let result = book.add_order(limit(Uuid::new_v4(), OrderType::Buy, 6, 101));
+
+        let filled = |order: &Order| -> Decimal {
+            result.trades.iter().filter(|trade| trade.sell_order_id == order.id).map(|trade| trade.amount).sum()
+        };
+        assert_eq!(result.order.status, OrderStatus::Filled);
+        assert_eq!(near.iter().map(filled).collect::<Vec<_>>(), vec![Decimal::ONE, Decimal::ONE]);
+        assert_eq!(far.iter().map(filled).collect::<Vec<_>>(), vec![Decimal::ONE, Decimal::from(3)]);
+        assert_eq!(result.trades.iter().map(|trade| trade.price).max(), Some(Decimal::from(101)));
+        assert!(!book.asks.contains_key(&Decimal::from(100)));
+        assert_eq!(book.level_size(&OrderType::Sell, 101.into()), Decimal::from(4));
+    }
+
+    #[test]
+    fn pro_rata_respects_the_limit_price() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        book.matching_policy = MatchingPolicy::ProRata;
+        book.add_order(limit(Uuid::new_v4(), OrderType::Buy, 2, 100));
+        book.add_order(limit(Uuid::new_v4(), OrderType::Buy, 2, 99));
+
+        let result = book.add_order(limit(Uuid::new_v4(), OrderType::Sell, 3, 100));
+
+        assert_eq!(result.trades.iter().map(|trade| trade.amount).sum::<Decimal>(), Decimal::from(2));
+        assert_eq!(book.level_size(&OrderType::Sell, 100.into()), Decimal::ONE);
+        assert_eq!(book.level_size(&OrderType::Buy, 99.into()), Decimal::from(2));
+    }
+
+    #[test]
+    fn replaying_the_same_inputs_gives_trades_the_same_ids() {
+        let now = Utc::now();
+        let inputs = [
+            iceberg(limit(Uuid::new_v4(), OrderType::Sell, 5, 100), 2),
+            limit(Uuid::new_v4(), OrderType::Sell, 1, 101),
+            limit(Uuid::new_v4(), OrderType::Buy, 6, 101),
+        ];
+        let trade_ids = || -> Vec<Uuid> {
+            let mut book = OrderBook::new("BTC/USDT".to_string());
+            inputs.iter().flat_map(|order| book.add_order_at(order.clone(), now).trades).map(|trade| trade.id).collect()
+        };
+
+        let ids = trade_ids();
+        assert_eq!(ids.len(), 4);
+        assert_eq!(ids, trade_ids());
+
+        // The same two orders trading again, slice after slice, still get a fresh id each time
+        let mut unique = ids.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), ids.len());
+    }
+}
+
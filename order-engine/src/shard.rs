@@ -0,0 +1,623 @@
+use crate::accounts::{Accounts, Posting};
+use crate::candles::CandleAggregator;
+use crate::engine::{EngineError, EngineResponse, EngineResult};
+use crate::events::{self, EventBus};
+use crate::journal::{JournalInput, JournalRecord, JournalWriter};
+use crate::market::MarketSpec;
+use crate::order::{Order, Trade};
+use crate::orderbook::{AmendError, AmendResult, BookState, MatchResult, OrderBook};
+use crate::volume::VolumeTracker;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::thread;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Work queued for a shard. It runs on the shard's thread with exclusive access to its books.
+pub(crate) type ShardTask = Box<dyn FnOnce(&mut Shard) + Send>;
+
+/// State the engine front end and every shard share.
+#[derive(Default)]
+pub(crate) struct SharedState {
+    pub markets: DashMap<String, MarketSpec>,
+    pub order_pairs: DashMap<Uuid, String>, // Live orders (order id -> pair), the book indexes the rest
+    /// Unset until a journal is attached.
+    pub journal: OnceLock<JournalWriter>,
+    /// Held while a deposit, withdrawal or account setting change is journaled and applied, so
+    /// a snapshot sees all of it or none.
+    pub account_inputs: Mutex<()>,
+    pub events: EventBus,
+    pub accounts: Accounts,
+    pub volumes: VolumeTracker,
+}
+
+impl SharedState {
+    pub fn lock_account_inputs(&self) -> EngineResult<MutexGuard<'_, ()>> {
+        self.account_inputs.lock().map_err(|_| EngineError::ProcessingError("Account input lock poisoned".to_string()))
+    }
+
+    /// Records an accepted input and returns its sequence number, `None` until a journal is
+    /// attached.
+    pub fn write_journal(&self, timestamp: DateTime<Utc>, input: impl FnOnce() -> JournalInput) -> EngineResult<Option<u64>> {
+        let Some(journal) = self.journal.get() else {
+            return Ok(None);
+        };
+
+        journal
+            .append(timestamp, input())
+            .map(Some)
+            .map_err(|e| EngineError::ProcessingError(format!("Failed to write journal: {}", e)))
+    }
+}
+
+/// A partition of the listed pairs, matched by a single worker thread.
+///
+/// Inputs for a pair are applied strictly in the order they were queued, so the books need no
+/// locking of their own.
+pub(crate) struct Shard {
+    pub books: HashMap<String, OrderBook>,
+    pub candles: CandleAggregator,
+    shared: Arc<SharedState>,
+    reservations: HashMap<Uuid, Reservation>, // Funds locked in the accounts for each live order
+}
+
+/// Funds the accounts hold locked for one order, as of its last settlement.
+#[derive(Debug, Clone)]
+struct Reservation {
+    user_id: Uuid,
+    asset: String,
+    amount: Decimal,
+}
+
+impl Shard {
+    /// Starts the worker thread for shard `id` and returns its input queue.
+    pub fn spawn(
+        id: usize,
+        books: HashMap<String, OrderBook>,
+        shared: Arc<SharedState>,
+        queue_capacity: usize,
+    ) -> std::io::Result<mpsc::Sender<ShardTask>> {
+        let (tx, mut rx) = mpsc::channel::<ShardTask>(queue_capacity);
+        let mut shard = Shard {
+            books,
+            candles: CandleAggregator::new(),
+            shared,
+            reservations: HashMap::new(),
+        };
+
+        thread::Builder::new().name(format!("matcher-{}", id)).spawn(move || {
+            let mut pairs: Vec<&String> = shard.books.keys().collect();
+            pairs.sort();
+            info!("Starting worker thread {} for pairs {:?}", id, pairs);
+
+            while let Some(task) = rx.blocking_recv() {
+                task(&mut shard);
+            }
+
+            info!("Worker thread {} stopped", id);
+        })?;
+
+        Ok(tx)
+    }
+
+    fn book_mut(&mut self, pair: &str) -> EngineResult<&mut OrderBook> {
+        self.books.get_mut(pair).ok_or_else(|| EngineError::OrderBookNotFound(pair.to_string()))
+    }
+
+    /// Asset an order on `pair` locks, see [`MarketSpec::locked_asset`].
+    fn locked_asset(&self, pair: &str, order: &Order) -> EngineResult<String> {
+        self.shared
+            .markets
+            .get(pair)
+            .map(|spec| spec.locked_asset(&order.order_type).to_string())
+            .ok_or_else(|| EngineError::OrderBookNotFound(pair.to_string()))
+    }
+
+    pub fn add_order(&mut self, mut order: Order) -> EngineResult<EngineResponse> {
+        // Clients choose order ids, and a live order's id is its key in every index. That includes
+        // its reservation here, which must never be replaced while the first order holds funds
+        if self.shared.order_pairs.contains_key(&order.id) || self.reservations.contains_key(&order.id) {
+            return Err(EngineError::InvalidOrder(format!("Duplicate order id {}", order.id)));
+        }
+
+        let pair = order.pair.clone();
+        let asset = self.locked_asset(&pair, &order)?;
+
+        // A market order has no price of its own, so its notional is taken at the best price it
+        // would trade at. With the opposite side empty the book rejects it anyway.
+        if order.is_market() {
+            if let Some(price) = self.book_mut(&pair)?.best_opposite_price(&order.order_type) {
+                if let Some(spec) = self.shared.markets.get(&pair) {
+                    spec.validate_notional(order.amount, price).map_err(EngineError::InvalidOrder)?;
+                }
+            }
+        }
+
+        // Funds are locked before the order is journaled, so only funded orders are ever replayed
+        order.fee_tier = self.shared.accounts.fee_tier(order.user_id);
+        order.locked = self.book_mut(&pair)?.funds_to_lock(&order);
+        self.shared.accounts.lock(order.user_id, &asset, order.locked)?;
+
+        let now = Utc::now();
+        let seq = match self.shared.write_journal(now, || JournalInput::NewOrder { order: order.clone() }) {
+            Ok(seq) => seq,
+            Err(e) => {
+                self.shared.accounts.unlock(order.user_id, &asset, order.locked);
+                return Err(e);
+            }
+        };
+        self.reservations.insert(order.id, Reservation { user_id: order.user_id, asset, amount: order.locked });
+
+        let shared = Arc::clone(&self.shared);
+        let orderbook = self.books.get_mut(&pair).ok_or_else(|| EngineError::OrderBookNotFound(pair.clone()))?;
+        let result = orderbook.add_order_at(order, now);
+        orderbook.last_input_seq = seq.unwrap_or(orderbook.last_input_seq);
+        index_orders(&shared, orderbook, &result);
+        settle(&shared, &mut self.reservations, orderbook, &result.trades, touched_orders(&result));
+        let mut events = events::new_order_events(orderbook, &result, now);
+        events.extend(events::candle_events(orderbook, &self.candles.record(&result.trades), now));
+        shared.events.publish(events);
+
+        info!(
+            "Order {} processed for pair {}, generated {} trades",
+            result.order.id,
+            pair,
+            result.trades.len()
+        );
+
+        Ok(result.into())
+    }
+
+    pub fn cancel_order(&mut self, pair: &str, order_id: Uuid) -> EngineResult<Option<Order>> {
+        let is_live = self.book_mut(pair)?.get_order_location(order_id).is_some();
+        let now = Utc::now();
+        let seq = if is_live {
+            self.shared.write_journal(now, || JournalInput::CancelOrder { order_id })?
+        } else {
+            None
+        };
+
+        let shared = Arc::clone(&self.shared);
+        let orderbook = self.books.get_mut(pair).ok_or_else(|| EngineError::OrderBookNotFound(pair.to_string()))?;
+        orderbook.last_input_seq = seq.unwrap_or(orderbook.last_input_seq);
+        let cancelled_order = orderbook.cancel_order(order_id);
+        shared.order_pairs.remove(&order_id);
+        if let Some(order) = &cancelled_order {
+            settle(&shared, &mut self.reservations, orderbook, &[], [order_id]);
+            shared.events.publish(events::cancel_events(orderbook, std::slice::from_ref(order), now));
+        }
+
+        if cancelled_order.is_some() {
+            info!("Order {} cancelled in pair {}", order_id, pair);
+        } else {
+            warn!("Order {} not found for cancellation in pair {}", order_id, pair);
+        }
+
+        Ok(cancelled_order)
+    }
+
+    pub fn amend_order(
+        &mut self,
+        spec: &MarketSpec,
+        order_id: Uuid,
+        new_amount: Option<Decimal>,
+        new_price: Option<Decimal>,
+    ) -> EngineResult<AmendResult> {
+        let order = self.book_mut(&spec.pair)?.get_order(order_id).ok_or(EngineError::OrderNotFound(order_id))?.clone();
+        let amount = new_amount.unwrap_or(order.amount);
+        if new_amount.is_some() {
+            spec.validate_quantity(amount).map_err(EngineError::InvalidOrder)?;
+        }
+        if let Some(price) = new_price {
+            spec.validate_price(price).map_err(EngineError::InvalidOrder)?;
+        }
+        if let Some(price) = new_price.or(order.price) {
+            spec.validate_notional(amount, price).map_err(EngineError::InvalidOrder)?;
+        }
+
+        // A larger size or a higher buy price needs more funds locked before anything is journaled
+        let asset = spec.locked_asset(&order.order_type);
+        let extra = amend_extra_funds(&order, new_amount, new_price);
+        self.shared.accounts.lock(order.user_id, asset, extra)?;
+
+        let now = Utc::now();
+        let seq = match self.shared.write_journal(now, || JournalInput::AmendOrder { order_id, new_amount, new_price }) {
+            Ok(seq) => seq,
+            Err(e) => {
+                self.shared.accounts.unlock(order.user_id, asset, extra);
+                return Err(e);
+            }
+        };
+
+        if let Some(reservation) = self.reservations.get_mut(&order_id) {
+            reservation.amount += extra;
+        }
+
+        let shared = Arc::clone(&self.shared);
+        let orderbook = self.books.get_mut(&spec.pair).ok_or_else(|| EngineError::OrderBookNotFound(spec.pair.clone()))?;
+        orderbook.last_input_seq = seq.unwrap_or(orderbook.last_input_seq);
+        let amend = apply_amend(&shared, &mut self.reservations, orderbook, order_id, new_amount, new_price, now)
+            .map_err(|e| match e {
+                AmendError::OrderNotFound => EngineError::OrderNotFound(order_id),
+                AmendError::Invalid(msg) => EngineError::InvalidOrder(msg),
+            })?;
+        let mut events = events::amend_events(orderbook, &amend, now);
+        events.extend(events::candle_events(orderbook, &self.candles.record(&amend.result.trades), now));
+        shared.events.publish(events);
+
+        info!(
+            "Order {} amended in pair {} ({} priority), generated {} trades",
+            order_id,
+            spec.pair,
+            if amend.kept_priority { "kept" } else { "lost" },
+            amend.result.trades.len()
+        );
+
+        Ok(amend)
+    }
+
+    /// Sweeps expired GTD orders off every book in the shard.
+    ///
+    /// Each sweep that removes anything is journaled with `now`, so replay expires the same
+    /// orders at the same time. A book whose sweep cannot be journaled is left for the next one.
+    pub fn expire_orders(&mut self, now: DateTime<Utc>) -> Vec<Order> {
+        let mut expired = Vec::new();
+
+        for (pair, orderbook) in self.books.iter_mut() {
+            if !orderbook.has_expired(now) {
+                continue;
+            }
+            let seq = match self.shared.write_journal(now, || JournalInput::ExpireOrders { pair: pair.clone() }) {
+                Ok(seq) => seq,
+                Err(e) => {
+                    warn!("Not expiring orders in pair {}: {}", pair, e);
+                    continue;
+                }
+            };
+            orderbook.last_input_seq = seq.unwrap_or(orderbook.last_input_seq);
+
+            let orders = expire_book(&self.shared, &mut self.reservations, orderbook, now);
+            self.shared.events.publish(events::cancel_events(orderbook, &orders, now));
+            if !orders.is_empty() {
+                info!("Expired {} GTD orders in pair {}", orders.len(), pair);
+            }
+            expired.extend(orders);
+        }
+
+        expired
+    }
+
+    /// Closes every candle whose interval ended by `now` and publishes them.
+    pub fn close_candles(&mut self, now: DateTime<Utc>) {
+        for candle in self.candles.close_due(now) {
+            if let Some(orderbook) = self.books.get_mut(&candle.pair) {
+                self.shared.events.publish(events::candle_events(orderbook, std::slice::from_ref(&candle), now));
+            }
+        }
+    }
+
+    /// Replaces a book's orders with those in `state` and takes over the funds they hold.
+    pub fn restore_book(&mut self, state: BookState) {
+        let pair = state.pair.clone();
+        let Some(orderbook) = self.books.get_mut(&pair) else {
+            return;
+        };
+
+        for order_id in orderbook.order_ids() {
+            self.reservations.remove(&order_id);
+        }
+        orderbook.restore_state(state);
+
+        let spec = self.shared.markets.get(&pair);
+        for order_id in orderbook.order_ids() {
+            self.shared.order_pairs.insert(order_id, pair.clone());
+            if let (Some(spec), Some(order)) = (&spec, orderbook.get_order(order_id)) {
+                let asset = spec.locked_asset(&order.order_type).to_string();
+                self.reservations.insert(order_id, Reservation { user_id: order.user_id, asset, amount: order.locked });
+            }
+        }
+    }
+
+    /// Re-applies journaled inputs routed to this shard, in journal order. Returns how many were
+    /// applied; records a book already reflects are skipped.
+    pub fn replay(&mut self, records: Vec<(String, JournalRecord)>) -> usize {
+        let mut applied = 0;
+
+        for (pair, record) in records {
+            let Some(orderbook) = self.books.get_mut(&pair) else {
+                warn!("Skipping journal record {}: unknown pair {}", record.seq, pair);
+                continue;
+            };
+            if record.seq <= orderbook.last_input_seq {
+                continue;
+            }
+
+            // Events are regenerated too, so per-pair event sequences carry on where they left off.
+            // Funds are locked again as recorded, without the funds check the input already passed.
+            let timestamp = record.timestamp;
+            let accounts = &self.shared.accounts;
+            let events = match record.input {
+                JournalInput::NewOrder { order } => {
+                    if let Some(spec) = self.shared.markets.get(&pair) {
+                        let asset = spec.locked_asset(&order.order_type).to_string();
+                        accounts.lock_replayed(order.user_id, &asset, order.locked);
+                        self.reservations.insert(order.id, Reservation { user_id: order.user_id, asset, amount: order.locked });
+                    }
+
+                    let result = orderbook.add_order_at(order, timestamp);
+                    index_orders(&self.shared, orderbook, &result);
+                    settle(&self.shared, &mut self.reservations, orderbook, &result.trades, touched_orders(&result));
+                    let mut events = events::new_order_events(orderbook, &result, timestamp);
+                    events.extend(events::candle_events(orderbook, &self.candles.record(&result.trades), timestamp));
+                    events
+                }
+                JournalInput::CancelOrder { order_id } => {
+                    self.shared.order_pairs.remove(&order_id);
+                    let cancelled: Vec<Order> = orderbook.cancel_order(order_id).into_iter().collect();
+                    settle(&self.shared, &mut self.reservations, orderbook, &[], cancelled.iter().map(|o| o.id));
+                    events::cancel_events(orderbook, &cancelled, timestamp)
+                }
+                JournalInput::AmendOrder { order_id, new_amount, new_price } => {
+                    if let (Some(order), Some(reservation)) = (orderbook.get_order(order_id), self.reservations.get_mut(&order_id)) {
+                        let extra = amend_extra_funds(order, new_amount, new_price);
+                        accounts.lock_replayed(reservation.user_id, &reservation.asset, extra);
+                        reservation.amount += extra;
+                    }
+
+                    // Amends the book turned down first time round are turned down again here
+                    match apply_amend(&self.shared, &mut self.reservations, orderbook, order_id, new_amount, new_price, timestamp) {
+                        Ok(amend) => {
+                            let mut events = events::amend_events(orderbook, &amend, timestamp);
+                            events.extend(events::candle_events(orderbook, &self.candles.record(&amend.result.trades), timestamp));
+                            events
+                        }
+                        Err(_) => Vec::new(),
+                    }
+                }
+                JournalInput::ExpireOrders { .. } => {
+                    let expired = expire_book(&self.shared, &mut self.reservations, orderbook, timestamp);
+                    events::cancel_events(orderbook, &expired, timestamp)
+                }
+                // Account inputs are applied by the engine front end, never routed here
+                JournalInput::Deposit { .. }
+                | JournalInput::Withdraw { .. }
+                | JournalInput::SetFeeTier { .. }
+                | JournalInput::SetSelfTradePrevention { .. } => continue,
+            };
+            self.shared.events.publish(events);
+            orderbook.last_input_seq = record.seq;
+            applied += 1;
+        }
+
+        applied
+    }
+}
+
+/// Removes the orders in `orderbook` that expired by `now` and releases their funds.
+fn expire_book(
+    shared: &SharedState,
+    reservations: &mut HashMap<Uuid, Reservation>,
+    orderbook: &mut OrderBook,
+    now: DateTime<Utc>,
+) -> Vec<Order> {
+    let expired = orderbook.expire_orders(now);
+    for order in &expired {
+        shared.order_pairs.remove(&order.id);
+    }
+    settle(shared, reservations, orderbook, &[], expired.iter().map(|o| o.id));
+    expired
+}
+
+/// Every order a book call may have changed.
+fn touched_orders(result: &MatchResult) -> impl Iterator<Item = Uuid> + '_ {
+    std::iter::once(result.order.id)
+        .chain(result.trades.iter().flat_map(|t| [t.buy_order_id, t.sell_order_id]))
+        .chain(result.cancelled.iter().map(|o| o.id))
+        .chain(result.triggered.iter().map(|o| o.id))
+        .chain(result.self_trades_prevented.iter().map(|p| p.maker_order_id))
+}
+
+/// Keeps the order id -> pair index in step with what a book call left live.
+fn index_orders(shared: &SharedState, orderbook: &OrderBook, result: &MatchResult) {
+    for order_id in touched_orders(result) {
+        if orderbook.get_order_location(order_id).is_some() {
+            shared.order_pairs.insert(order_id, orderbook.pair.clone());
+        } else {
+            shared.order_pairs.remove(&order_id);
+        }
+    }
+}
+
+/// Moves funds for what a book call did.
+///
+/// Each trade is settled by its postings, see [`Posting::for_trade`], paid for out of the two
+/// orders' locked funds. Whatever a touched order has locked beyond what it spent and what it
+/// still holds (all of it, once the order is done) goes back to its owner.
+fn settle(
+    shared: &SharedState,
+    reservations: &mut HashMap<Uuid, Reservation>,
+    orderbook: &OrderBook,
+    trades: &[Trade],
+    touched: impl IntoIterator<Item = Uuid>,
+) {
+    let mut spent: HashMap<Uuid, Decimal> = HashMap::new();
+    if let Some(spec) = shared.markets.get(&orderbook.pair) {
+        for trade in trades {
+            shared.accounts.post(&Posting::for_trade(trade, &spec));
+            shared.volumes.record(trade);
+            *spent.entry(trade.buy_order_id).or_default() += trade.amount * trade.price;
+            *spent.entry(trade.sell_order_id).or_default() += trade.amount;
+        }
+    }
+
+    for order_id in touched {
+        let Some(reservation) = reservations.get_mut(&order_id) else {
+            continue;
+        };
+        let live = orderbook.get_order(order_id);
+        let still_locked = live.map_or(Decimal::ZERO, |order| order.locked);
+        let released = reservation.amount - spent.remove(&order_id).unwrap_or_default() - still_locked;
+        if !released.is_zero() {
+            shared.accounts.unlock(reservation.user_id, &reservation.asset, released);
+        }
+
+        if live.is_some() {
+            reservation.amount = still_locked;
+        } else {
+            reservations.remove(&order_id);
+        }
+    }
+}
+
+/// Funds an amend needs locked on top of what the order holds: for more size, or a higher price
+/// on a buy. Zero when it needs less, the difference is given back once the amend is settled.
+fn amend_extra_funds(order: &Order, new_amount: Option<Decimal>, new_price: Option<Decimal>) -> Decimal {
+    let mut amended = order.clone();
+    amended.amount = new_amount.unwrap_or(order.amount);
+    amended.price = new_price.or(order.price);
+    (amended.funds_required() - order.locked).max(Decimal::ZERO)
+}
+
+/// Amends an order, then indexes and settles what changed.
+///
+/// Any extra funds the amend needs must already be locked and reserved for the order. A rejected
+/// amend leaves the book untouched, so settling it just gives them back.
+fn apply_amend(
+    shared: &SharedState,
+    reservations: &mut HashMap<Uuid, Reservation>,
+    orderbook: &mut OrderBook,
+    order_id: Uuid,
+    new_amount: Option<Decimal>,
+    new_price: Option<Decimal>,
+    now: DateTime<Utc>,
+) -> Result<AmendResult, AmendError> {
+    let amend = orderbook.amend_order_at(order_id, new_amount, new_price, now);
+    match &amend {
+        Ok(amend) => {
+            index_orders(shared, orderbook, &amend.result);
+            settle(shared, reservations, orderbook, &amend.result.trades, touched_orders(&amend.result));
+        }
+        Err(_) => settle(shared, reservations, orderbook, &[], [order_id]),
+    }
+    amend
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::{EngineError, OrderEngine};
+    use crate::journal::{FsyncPolicy, Journal};
+    use crate::order::{Order, OrderKind, OrderType};
+    use rust_decimal::Decimal;
+    use uuid::Uuid;
+
+    const PAIR: &str = "BTC/USDT";
+
+    fn limit(user_id: Uuid, order_type: OrderType, amount: i64, price: i64) -> Order {
+        Order::new(user_id, PAIR.to_string(), order_type, OrderKind::Limit, amount.into(), Some(price.into()))
+    }
+
+    #[tokio::test]
+    async fn duplicate_live_order_ids_are_rejected_before_locking_funds() {
+        let path = std::env::temp_dir().join(format!("order-engine-shard-{}", Uuid::new_v4())).join("journal.log");
+        let user = Uuid::new_v4();
+        let engine = OrderEngine::new(1);
+        engine.attach_journal(Journal::open(&path, FsyncPolicy::Always).unwrap().0).await.unwrap();
+        engine.deposit(user, "USDT", Decimal::from(1_000)).unwrap();
+        let order = limit(user, OrderType::Buy, 1, 100);
+        engine.add_order(order.clone()).await.unwrap();
+
+        let mut duplicate = limit(user, OrderType::Buy, 2, 90);
+        duplicate.id = order.id;
+        let result = engine.add_order(duplicate).await;
+
+        assert!(matches!(result, Err(EngineError::InvalidOrder(_))));
+        assert_eq!(engine.get_balances(user)["USDT"].locked, Decimal::from(100));
+        let resting = engine.get_order(order.id).await.unwrap().unwrap();
+        assert_eq!(resting.price, Some(Decimal::from(100)));
+        let (_, records) = Journal::open(&path, FsyncPolicy::Always).unwrap();
+        assert_eq!(records.len(), 2);
+
+        // Once the first order is gone its id is free again
+        engine.cancel_order(order.id).await.unwrap();
+        engine.add_order(order).await.unwrap();
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    /// USDT and BTC balances of `user`, as (available, locked) pairs.
+    fn holdings(engine: &OrderEngine, user: Uuid) -> [(Decimal, Decimal); 2] {
+        let balances = engine.get_balances(user);
+        ["USDT", "BTC"].map(|asset| balances.get(asset).map_or((Decimal::ZERO, Decimal::ZERO), |b| (b.available, b.locked)))
+    }
+
+    fn funded_engine() -> (OrderEngine, Uuid, Uuid) {
+        let engine = OrderEngine::new(1);
+        let (buyer, seller) = (Uuid::new_v4(), Uuid::new_v4());
+        engine.deposit(buyer, "USDT", Decimal::from(1_000)).unwrap();
+        engine.deposit(seller, "BTC", Decimal::from(5)).unwrap();
+        (engine, buyer, seller)
+    }
+
+    #[tokio::test]
+    async fn orders_lock_funds_until_cancelled() {
+        let (engine, buyer, seller) = funded_engine();
+
+        // Buys lock quote at their limit price, sells lock base
+        let buy = limit(buyer, OrderType::Buy, 2, 100);
+        engine.add_order(buy.clone()).await.unwrap();
+        let sell = limit(seller, OrderType::Sell, 3, 110);
+        engine.add_order(sell.clone()).await.unwrap();
+        assert_eq!(holdings(&engine, buyer)[0], (Decimal::from(800), Decimal::from(200)));
+        assert_eq!(holdings(&engine, seller)[1], (Decimal::from(2), Decimal::from(3)));
+
+        // An order the available balance cannot cover is turned away without locking anything
+        let result = engine.add_order(limit(buyer, OrderType::Buy, 9, 100)).await;
+        assert!(matches!(result, Err(EngineError::InsufficientFunds { required, available, .. })
+            if required == Decimal::from(900) && available == Decimal::from(800)));
+        assert_eq!(holdings(&engine, buyer)[0], (Decimal::from(800), Decimal::from(200)));
+
+        engine.cancel_order(buy.id).await.unwrap();
+        engine.cancel_order(sell.id).await.unwrap();
+        assert_eq!(holdings(&engine, buyer)[0], (Decimal::from(1_000), Decimal::ZERO));
+        assert_eq!(holdings(&engine, seller)[1], (Decimal::from(5), Decimal::ZERO));
+
+        // Cancelling again releases nothing twice
+        assert!(engine.cancel_order(buy.id).await.unwrap().is_none());
+        assert_eq!(holdings(&engine, buyer)[0], (Decimal::from(1_000), Decimal::ZERO));
+    }
+
+    #[tokio::test]
+    async fn fills_spend_the_reservation_and_release_what_is_left() {
+        let (engine, buyer, seller) = funded_engine();
+        engine.set_fee_tier(buyer, 7).unwrap();
+        engine.set_fee_tier(seller, 7).unwrap();
+
+        // A buy that trades below its limit gets the difference back
+        engine.add_order(limit(seller, OrderType::Sell, 1, 95)).await.unwrap();
+        engine.add_order(limit(buyer, OrderType::Buy, 1, 110)).await.unwrap();
+        assert_eq!(holdings(&engine, buyer)[0], (Decimal::from(905), Decimal::ZERO));
+
+        // A partial fill spends the filled part and keeps the rest locked for the resting remainder
+        let buy = limit(buyer, OrderType::Buy, 2, 100);
+        engine.add_order(buy.clone()).await.unwrap();
+        engine.add_order(limit(seller, OrderType::Sell, 1, 100)).await.unwrap();
+        assert_eq!(holdings(&engine, buyer)[0], (Decimal::from(705), Decimal::from(100)));
+        assert_eq!(holdings(&engine, seller)[1], (Decimal::from(3), Decimal::ZERO));
+
+        // Filling the rest leaves nothing locked anywhere
+        engine.add_order(limit(seller, OrderType::Sell, 1, 100)).await.unwrap();
+        let [usdt, btc] = holdings(&engine, buyer);
+        assert_eq!(usdt, (Decimal::from(705), Decimal::ZERO));
+        assert_eq!(btc.1, Decimal::ZERO);
+        assert_eq!(holdings(&engine, seller)[1], (Decimal::from(2), Decimal::ZERO));
+        assert!(engine.get_order(buy.id).await.unwrap().is_none());
+
+        // The taker fee (tier 7: 0.04%) comes out of what each side received, the maker pays nothing
+        assert_eq!(btc.0, Decimal::from(3) - Decimal::new(4, 4));
+        assert_eq!(holdings(&engine, seller)[0], (Decimal::from(295) - Decimal::new(8, 2), Decimal::ZERO));
+    }
+}
@@ -0,0 +1,239 @@
+use crate::accounts::AccountsState;
+use crate::candles::Candle;
+use crate::journal::crc32;
+use crate::orderbook::BookState;
+use crate::volume::VolumeState;
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Format version written into every snapshot file.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Every snapshot file starts with these bytes.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"OESNAP\0\0";
+
+/// Magic, version, CRC-32 of the payload and payload length.
+const SNAPSHOT_HEADER_LEN: usize = 8 + 4 + 4 + 8;
+
+const SNAPSHOT_EXTENSION: &str = "snap";
+
+/// Writes a map keyed by price with the keys as exact decimal strings rather than the floats
+/// `Decimal` serializes to by default. Reading them back needs nothing special, since `Decimal`
+/// parses strings as they are.
+pub(crate) fn serialize_price_map<S, V>(map: &BTreeMap<Decimal, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    V: Serialize,
+{
+    serializer.collect_map(map.iter().map(|(price, value)| (price.to_string(), value)))
+}
+
+/// Point-in-time copy of every order book, account balance and user's recent volume, plus the
+/// candles not yet on disk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EngineSnapshot {
+    pub version: u32,
+    pub taken_at: DateTime<Utc>,
+    pub books: Vec<BookState>,
+    #[serde(default)]
+    pub accounts: AccountsState,
+    #[serde(default)]
+    pub volumes: VolumeState,
+    /// Open candles and closed ones not yet written to the candle store.
+    #[serde(default)]
+    pub candles: Vec<Candle>,
+}
+
+impl EngineSnapshot {
+    pub fn new(books: Vec<BookState>, accounts: AccountsState, volumes: VolumeState, candles: Vec<Candle>) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            taken_at: Utc::now(),
+            books,
+            accounts,
+            volumes,
+            candles,
+        }
+    }
+
+    /// Journal sequence the snapshot is complete up to: every record numbered this or lower is
+    /// already reflected in it, so the journal no longer needs them once it is on disk.
+    ///
+    /// Every shard pauses while a snapshot is taken, and no shard or account input is ever
+    /// journaled without being applied in the same step, so nothing numbered below the newest
+    /// record any book or the accounts applied can still be outstanding.
+    pub fn last_input_seq(&self) -> u64 {
+        self.books.iter().map(|book| book.last_input_seq).max().unwrap_or(0).max(self.accounts.last_input_seq)
+    }
+}
+
+/// Writes `snapshot` into `dir` and returns its path.
+///
+/// The file is written under a temporary name, fsynced and then renamed, so a crash never leaves
+/// a half-written snapshot behind under a real name.
+pub fn write_snapshot(dir: impl AsRef<Path>, snapshot: &EngineSnapshot) -> io::Result<PathBuf> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+
+    let payload = serde_json::to_vec(snapshot).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let name = format!("snapshot-{}.{}", snapshot.taken_at.format("%Y%m%dT%H%M%S%3fZ"), SNAPSHOT_EXTENSION);
+    let path = dir.join(&name);
+    let tmp_path = dir.join(format!("{}.tmp", name));
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(SNAPSHOT_MAGIC)?;
+    file.write_all(&snapshot.version.to_le_bytes())?;
+    file.write_all(&crc32(&payload).to_le_bytes())?;
+    file.write_all(&(payload.len() as u64).to_le_bytes())?;
+    file.write_all(&payload)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp_path, &path)?;
+    if let Ok(dir_handle) = File::open(dir) {
+        // Persist the rename itself; not every platform can sync a directory
+        let _ = dir_handle.sync_all();
+    }
+
+    info!("Wrote snapshot of {} books to {}", snapshot.books.len(), path.display());
+    Ok(path)
+}
+
+/// Reads and verifies a single snapshot file.
+pub fn read_snapshot(path: impl AsRef<Path>) -> io::Result<EngineSnapshot> {
+    let bytes = fs::read(path)?;
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    if bytes.len() < SNAPSHOT_HEADER_LEN || &bytes[..8] != SNAPSHOT_MAGIC {
+        return Err(invalid("Not a snapshot file"));
+    }
+
+    let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+    if version != SNAPSHOT_VERSION {
+        return Err(invalid(&format!("Unsupported snapshot version {}", version)));
+    }
+
+    let checksum = u32::from_le_bytes(bytes[12..16].try_into().unwrap());
+    let len = u64::from_le_bytes(bytes[16..24].try_into().unwrap()) as usize;
+    let payload = &bytes[SNAPSHOT_HEADER_LEN..];
+    if payload.len() != len {
+        return Err(invalid("Snapshot is truncated"));
+    }
+    if crc32(payload) != checksum {
+        return Err(invalid("Snapshot checksum mismatch"));
+    }
+
+    let snapshot: EngineSnapshot =
+        serde_json::from_slice(payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if snapshot.version != version {
+        return Err(invalid("Snapshot header and payload versions differ"));
+    }
+
+    Ok(snapshot)
+}
+
+/// Snapshot files in `dir`, newest first.
+fn list_snapshots(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == SNAPSHOT_EXTENSION))
+            .collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+
+    // Names embed the UTC time they were taken, so they sort chronologically
+    paths.sort();
+    paths.reverse();
+    Ok(paths)
+}
+
+/// Loads the newest snapshot in `dir` that passes verification, skipping any that do not.
+pub fn latest_snapshot(dir: impl AsRef<Path>) -> io::Result<Option<(PathBuf, EngineSnapshot)>> {
+    for path in list_snapshots(dir.as_ref())? {
+        match read_snapshot(&path) {
+            Ok(snapshot) => return Ok(Some((path, snapshot))),
+            Err(e) => warn!("Skipping invalid snapshot {}: {}", path.display(), e),
+        }
+    }
+
+    Ok(None)
+}
+
+/// Deletes all but the newest `keep` snapshots in `dir`.
+pub fn prune_snapshots(dir: impl AsRef<Path>, keep: usize) -> io::Result<()> {
+    for path in list_snapshots(dir.as_ref())?.into_iter().skip(keep) {
+        fs::remove_file(&path)?;
+        info!("Removed old snapshot {}", path.display());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::OrderEngine;
+    use crate::journal::{FsyncPolicy, Journal};
+    use crate::order::{Order, OrderKind, OrderType};
+    use uuid::Uuid;
+
+    const PAIR: &str = "BTC/USDT";
+
+    fn limit(user_id: Uuid, order_type: OrderType, price: i64) -> Order {
+        Order::new(user_id, PAIR.to_string(), order_type, OrderKind::Limit, Decimal::ONE, Some(price.into()))
+    }
+
+    #[tokio::test]
+    async fn snapshot_plus_journal_replay_restores_the_engine() {
+        let dir = std::env::temp_dir().join(format!("order-engine-snapshot-{}", Uuid::new_v4()));
+        let journal_path = dir.join("journal.log");
+        let (buyer, seller) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let engine = OrderEngine::new(1);
+        engine.attach_journal(Journal::open(&journal_path, FsyncPolicy::Always).unwrap().0).await.unwrap();
+        engine.deposit(buyer, "USDT", Decimal::from(1_000)).unwrap();
+        engine.deposit(seller, "BTC", Decimal::from(5)).unwrap();
+        let filled = limit(seller, OrderType::Sell, 100);
+        let cancelled = limit(seller, OrderType::Sell, 101);
+        let resting = limit(seller, OrderType::Sell, 102);
+        for order in [&filled, &cancelled, &resting] {
+            engine.add_order(order.clone()).await.unwrap();
+        }
+
+        write_snapshot(&dir, &engine.snapshot().await.unwrap()).unwrap();
+
+        // Inputs after the snapshot only survive in the journal
+        engine.add_order(limit(buyer, OrderType::Buy, 100)).await.unwrap();
+        engine.cancel_order(cancelled.id).await.unwrap();
+        engine.deposit(buyer, "USDT", Decimal::from(50)).unwrap();
+
+        let restored = OrderEngine::new(1);
+        let (_, snapshot) = latest_snapshot(&dir).unwrap().unwrap();
+        restored.restore_snapshot(snapshot).await.unwrap();
+        let (_, records) = Journal::open(&journal_path, FsyncPolicy::Always).unwrap();
+        assert_eq!(restored.replay_journal(records).await.unwrap(), 3);
+
+        for user_id in [buyer, seller] {
+            assert_eq!(restored.get_balances(user_id), engine.get_balances(user_id));
+        }
+        assert!(restored.get_order(filled.id).await.unwrap().is_none());
+        assert!(restored.get_order(cancelled.id).await.unwrap().is_none());
+        assert!(restored.get_order(resting.id).await.unwrap().is_some());
+        let (book, restored_book) = (
+            engine.get_orderbook(PAIR).await.unwrap().unwrap().state(),
+            restored.get_orderbook(PAIR).await.unwrap().unwrap().state(),
+        );
+        assert_eq!(serde_json::to_value(&book.asks).unwrap(), serde_json::to_value(&restored_book.asks).unwrap());
+        assert_eq!(book.last_trade_price, restored_book.last_trade_price);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
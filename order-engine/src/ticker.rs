@@ -0,0 +1,241 @@
+use crate::order::{OrderType, Trade};
+use crate::orderbook::OrderBook;
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// Length of the window [`RollingStats`] covers.
+pub const ROLLING_WINDOW_HOURS: i64 = 24;
+
+/// Last traded price, top of book and the last 24 hours of trading for a pair.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ticker {
+    pub pair: String,
+    pub last_price: Option<Decimal>,
+    pub best_bid: Option<Decimal>,
+    pub best_bid_size: Decimal,
+    pub best_ask: Option<Decimal>,
+    pub best_ask_size: Decimal,
+    /// First price traded in the window; `None`, like the other prices, when nothing traded.
+    pub open: Option<Decimal>,
+    pub high: Option<Decimal>,
+    pub low: Option<Decimal>,
+    /// Base asset traded in the window.
+    pub volume: Decimal,
+    /// Quote asset traded in the window.
+    pub quote_volume: Decimal,
+    /// Change from `open` to the last price in the window, in percent to two decimal places.
+    pub price_change_percent: Option<Decimal>,
+    /// Volume weighted average price over the window, quote volume over volume.
+    pub weighted_avg_price: Option<Decimal>,
+    pub trade_count: u64,
+}
+
+impl Ticker {
+    /// The book's ticker, with its trading over the window ending at `now`.
+    pub fn from_book(orderbook: &OrderBook, now: DateTime<Utc>) -> Self {
+        let best_bid = orderbook.get_best_bid();
+        let best_ask = orderbook.get_best_ask();
+        let window = orderbook.stats.window(now);
+
+        let change = window.open.zip(window.last).filter(|(open, _)| !open.is_zero());
+        let weighted_avg_price = (!window.volume.is_zero()).then(|| window.quote_volume / window.volume);
+
+        Self {
+            pair: orderbook.pair.clone(),
+            last_price: orderbook.last_trade_price,
+            best_bid,
+            best_bid_size: best_bid.map_or(Decimal::ZERO, |price| orderbook.level_size(&OrderType::Buy, price)),
+            best_ask,
+            best_ask_size: best_ask.map_or(Decimal::ZERO, |price| orderbook.level_size(&OrderType::Sell, price)),
+            open: window.open,
+            high: window.high,
+            low: window.low,
+            volume: window.volume,
+            quote_volume: window.quote_volume,
+            price_change_percent: change.map(|(open, last)| ((last - open) / open * Decimal::ONE_HUNDRED).round_dp(2)),
+            weighted_avg_price,
+            trade_count: window.trade_count,
+        }
+    }
+}
+
+/// A pair's trading over the last [`ROLLING_WINDOW_HOURS`], kept in one-minute buckets.
+///
+/// The window moves a minute at a time: it always covers the current minute and the ones before
+/// it back to 24 hours ago.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RollingStats {
+    buckets: VecDeque<StatsBucket>, // Oldest first, only minutes that traded
+}
+
+/// Trading within one minute.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StatsBucket {
+    minute: DateTime<Utc>,
+    #[serde(with = "rust_decimal::serde::str")]
+    open: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    high: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    low: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    close: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    volume: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    quote_volume: Decimal,
+    trade_count: u64,
+}
+
+/// What [`RollingStats::window`] adds up to.
+#[derive(Debug, Clone, Default)]
+struct Window {
+    open: Option<Decimal>,
+    high: Option<Decimal>,
+    low: Option<Decimal>,
+    last: Option<Decimal>,
+    volume: Decimal,
+    quote_volume: Decimal,
+    trade_count: u64,
+}
+
+impl RollingStats {
+    pub fn record(&mut self, trade: &Trade) {
+        let minute = minute_of(trade.timestamp);
+        match self.buckets.back_mut() {
+            // A trade stamped before the newest minute (the clock stepped back) counts towards it
+            Some(bucket) if bucket.minute >= minute => {
+                bucket.high = bucket.high.max(trade.price);
+                bucket.low = bucket.low.min(trade.price);
+                bucket.close = trade.price;
+                bucket.volume += trade.amount;
+                bucket.quote_volume += trade.amount * trade.price;
+                bucket.trade_count += 1;
+            }
+            _ => self.buckets.push_back(StatsBucket {
+                minute,
+                open: trade.price,
+                high: trade.price,
+                low: trade.price,
+                close: trade.price,
+                volume: trade.amount,
+                quote_volume: trade.amount * trade.price,
+                trade_count: 1,
+            }),
+        }
+
+        let start = window_start(trade.timestamp);
+        while self.buckets.front().is_some_and(|bucket| bucket.minute < start) {
+            self.buckets.pop_front();
+        }
+    }
+
+    fn window(&self, now: DateTime<Utc>) -> Window {
+        let start = window_start(now);
+        self.buckets.iter().filter(|bucket| bucket.minute >= start).fold(Window::default(), |mut window, bucket| {
+            window.open = window.open.or(Some(bucket.open));
+            window.high = Some(window.high.map_or(bucket.high, |high| high.max(bucket.high)));
+            window.low = Some(window.low.map_or(bucket.low, |low| low.min(bucket.low)));
+            window.last = Some(bucket.close);
+            window.volume += bucket.volume;
+            window.quote_volume += bucket.quote_volume;
+            window.trade_count += bucket.trade_count;
+            window
+        })
+    }
+}
+
+fn minute_of(timestamp: DateTime<Utc>) -> DateTime<Utc> {
+    timestamp.duration_trunc(Duration::minutes(1)).unwrap_or(timestamp)
+}
+
+/// First minute inside the window ending at `now`.
+fn window_start(now: DateTime<Utc>) -> DateTime<Utc> {
+    minute_of(now) - Duration::hours(ROLLING_WINDOW_HOURS) + Duration::minutes(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order::{Order, OrderKind};
+    use chrono::TimeZone;
+    use uuid::Uuid;
+
+    fn limit(order_type: OrderType, amount: i64, price: i64) -> Order {
+        Order::new(Uuid::new_v4(), "BTC/USDT".to_string(), order_type, OrderKind::Limit, amount.into(), Some(price.into()))
+    }
+
+    fn trade(price: i64, amount: i64, timestamp: DateTime<Utc>) -> Trade {
+        Trade::new(&limit(OrderType::Buy, amount, price), &limit(OrderType::Sell, amount, price), amount.into(), price.into(), OrderType::Sell, timestamp)
+    }
+
+    fn start() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 3, 10, 12, 0, 30).unwrap()
+    }
+
+    #[test]
+    fn the_window_adds_up_every_trade_in_it() {
+        let mut stats = RollingStats::default();
+        stats.record(&trade(100, 1, start()));
+        stats.record(&trade(110, 2, start() + Duration::hours(1)));
+        stats.record(&trade(90, 1, start() + Duration::hours(2)));
+        stats.record(&trade(95, 1, start() + Duration::hours(2)));
+
+        let window = stats.window(start() + Duration::hours(3));
+        assert_eq!((window.open, window.high, window.low, window.last), (Some(100.into()), Some(110.into()), Some(90.into()), Some(95.into())));
+        assert_eq!(window.volume, Decimal::from(5));
+        assert_eq!(window.quote_volume, Decimal::from(100 + 220 + 90 + 95));
+        assert_eq!(window.trade_count, 4);
+    }
+
+    #[test]
+    fn trades_leave_the_window_a_minute_at_a_time() {
+        let mut stats = RollingStats::default();
+        stats.record(&trade(100, 1, start()));
+        stats.record(&trade(120, 1, start() + Duration::minutes(1)));
+
+        // The first trade's minute is in the window until 24 hours after it began
+        let first_minute_ends = minute_of(start()) + Duration::hours(ROLLING_WINDOW_HOURS);
+        assert_eq!(stats.window(first_minute_ends - Duration::seconds(1)).trade_count, 2);
+        let window = stats.window(first_minute_ends);
+        assert_eq!((window.open, window.trade_count), (Some(120.into()), 1));
+        assert_eq!(stats.window(first_minute_ends + Duration::minutes(1)).trade_count, 0);
+
+        // Recording a trade drops buckets that have left its window
+        stats.record(&trade(130, 1, first_minute_ends));
+        assert_eq!(stats.buckets.len(), 2);
+    }
+
+    #[test]
+    fn tickers_combine_the_book_with_the_window() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        book.add_order(limit(OrderType::Sell, 1, 100));
+        book.add_order(limit(OrderType::Sell, 3, 104));
+        book.add_order(limit(OrderType::Buy, 2, 99));
+        book.add_order(limit(OrderType::Buy, 1, 100));
+        book.add_order(limit(OrderType::Buy, 1, 104));
+
+        let ticker = Ticker::from_book(&book, Utc::now());
+        assert_eq!(ticker.last_price, Some(104.into()));
+        assert_eq!((ticker.best_bid, ticker.best_bid_size), (Some(99.into()), Decimal::from(2)));
+        assert_eq!((ticker.best_ask, ticker.best_ask_size), (Some(104.into()), Decimal::from(2)));
+        assert_eq!((ticker.open, ticker.high, ticker.low), (Some(100.into()), Some(104.into()), Some(100.into())));
+        assert_eq!((ticker.volume, ticker.quote_volume, ticker.trade_count), (Decimal::from(2), Decimal::from(204), 2));
+        assert_eq!(ticker.price_change_percent, Some(Decimal::new(400, 2)));
+        assert_eq!(ticker.weighted_avg_price, Some(Decimal::from(102)));
+    }
+
+    #[test]
+    fn a_quiet_pair_has_no_window_prices() {
+        let mut book = OrderBook::new("BTC/USDT".to_string());
+        book.add_order(limit(OrderType::Buy, 1, 99));
+
+        let ticker = Ticker::from_book(&book, Utc::now());
+        assert_eq!((ticker.best_bid, ticker.best_ask, ticker.best_ask_size), (Some(99.into()), None, Decimal::ZERO));
+        assert_eq!((ticker.open, ticker.high, ticker.low, ticker.last_price), (None, None, None, None));
+        assert_eq!((ticker.price_change_percent, ticker.weighted_avg_price), (None, None));
+        assert_eq!((ticker.volume, ticker.trade_count), (Decimal::ZERO, 0));
+    }
+}
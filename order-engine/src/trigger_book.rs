@@ -0,0 +1,141 @@
+use crate::order::{Order, OrderType};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use uuid::Uuid;
+
+/// Stop and stop-limit orders waiting for the last trade price to reach their trigger.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TriggerBook {
+    #[serde(serialize_with = "crate::snapshot::serialize_price_map")]
+    pub buy_stops: BTreeMap<Decimal, VecDeque<Order>>, // Fire when last price >= trigger (trigger -> orders)
+    #[serde(serialize_with = "crate::snapshot::serialize_price_map")]
+    pub sell_stops: BTreeMap<Decimal, VecDeque<Order>>, // Fire when last price <= trigger (trigger -> orders)
+}
+
+impl TriggerBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_order(&mut self, order: Order) {
+        let Some(trigger_price) = order.trigger_price else {
+            return;
+        };
+
+        let side = match order.order_type {
+            OrderType::Buy => &mut self.buy_stops,
+            OrderType::Sell => &mut self.sell_stops,
+        };
+        side.entry(trigger_price).or_default().push_back(order);
+    }
+
+    pub fn is_triggered(order: &Order, last_price: Decimal) -> bool {
+        match (order.trigger_price, &order.order_type) {
+            (Some(trigger_price), OrderType::Buy) => last_price >= trigger_price,
+            (Some(trigger_price), OrderType::Sell) => last_price <= trigger_price,
+            (None, _) => false,
+        }
+    }
+
+    /// Removes every order triggered by `last_price`.
+    ///
+    /// Buy stops come first, lowest trigger first, then sell stops, highest trigger first;
+    /// orders sharing a trigger price keep their arrival order.
+    pub fn take_triggered(&mut self, last_price: Decimal) -> Vec<Order> {
+        let mut triggered = Vec::new();
+
+        while let Some(entry) = self.buy_stops.first_entry() {
+            if *entry.key() > last_price {
+                break;
+            }
+            triggered.extend(entry.remove());
+        }
+
+        while let Some(entry) = self.sell_stops.last_entry() {
+            if *entry.key() < last_price {
+                break;
+            }
+            triggered.extend(entry.remove());
+        }
+
+        triggered
+    }
+
+    pub fn remove_order(&mut self, order_type: &OrderType, trigger_price: Decimal, order_id: Uuid) -> Option<Order> {
+        let side = match order_type {
+            OrderType::Buy => &mut self.buy_stops,
+            OrderType::Sell => &mut self.sell_stops,
+        };
+
+        let orders = side.get_mut(&trigger_price)?;
+        let pos = orders.iter().position(|o| o.id == order_id)?;
+        let order = orders.remove(pos);
+
+        if orders.is_empty() {
+            side.remove(&trigger_price);
+        }
+
+        order
+    }
+
+    pub fn len(&self) -> usize {
+        self.buy_stops.values().chain(self.sell_stops.values()).map(|orders| orders.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buy_stops.is_empty() && self.sell_stops.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order::OrderKind;
+
+    fn stop(order_type: OrderType, trigger_price: i64) -> Order {
+        let mut order = Order::new(Uuid::new_v4(), "BTC/USDT".to_string(), order_type, OrderKind::Stop, Decimal::ONE, None);
+        order.trigger_price = Some(trigger_price.into());
+        order
+    }
+
+    #[test]
+    fn buy_stops_fire_at_or_above_their_trigger_and_sell_stops_at_or_below() {
+        let mut triggers = TriggerBook::new();
+        let (buy_low, buy_high) = (stop(OrderType::Buy, 101), stop(OrderType::Buy, 103));
+        let (sell_high, sell_low) = (stop(OrderType::Sell, 99), stop(OrderType::Sell, 97));
+        for order in [&buy_high, &buy_low, &sell_low, &sell_high] {
+            triggers.add_order(order.clone());
+        }
+
+        assert!(triggers.take_triggered(100.into()).is_empty());
+        let fired: Vec<Uuid> = triggers.take_triggered(102.into()).iter().map(|order| order.id).collect();
+        assert_eq!(fired, vec![buy_low.id]);
+        let fired: Vec<Uuid> = triggers.take_triggered(97.into()).iter().map(|order| order.id).collect();
+        assert_eq!(fired, vec![sell_high.id, sell_low.id]);
+        assert_eq!(triggers.len(), 1);
+    }
+
+    #[test]
+    fn stops_at_one_trigger_fire_in_arrival_order() {
+        let mut triggers = TriggerBook::new();
+        let (first, second) = (stop(OrderType::Buy, 101), stop(OrderType::Buy, 101));
+        triggers.add_order(first.clone());
+        triggers.add_order(second.clone());
+
+        let fired: Vec<Uuid> = triggers.take_triggered(101.into()).iter().map(|order| order.id).collect();
+        assert_eq!(fired, vec![first.id, second.id]);
+        assert!(triggers.is_empty());
+    }
+
+    #[test]
+    fn removed_stops_never_fire() {
+        let mut triggers = TriggerBook::new();
+        let order = stop(OrderType::Sell, 99);
+        triggers.add_order(order.clone());
+
+        assert_eq!(triggers.remove_order(&OrderType::Sell, 99.into(), order.id).map(|o| o.id), Some(order.id));
+        assert!(triggers.sell_stops.is_empty());
+        assert!(triggers.take_triggered(90.into()).is_empty());
+    }
+}
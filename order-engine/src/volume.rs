@@ -0,0 +1,245 @@
+use crate::order::Trade;
+use chrono::{DateTime, Days, NaiveDate, NaiveTime, Utc};
+use dashmap::DashMap;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+/// Whole UTC days of trading a user's fee tier is based on, see [`VolumeTracker`].
+pub const VOLUME_WINDOW_DAYS: u64 = 30;
+
+/// How users are placed into fee tiers by what they traded.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FeeTierSchedule {
+    /// Least rolling notional each tier needs, from VIP0 up. VIP0 starts at zero.
+    pub min_volumes: Vec<Decimal>,
+    /// Time of day (UTC) every user's tier is recalculated.
+    pub recalculation_time: NaiveTime,
+}
+
+impl Default for FeeTierSchedule {
+    /// VIP0 to VIP9, recalculated at midnight UTC.
+    fn default() -> Self {
+        Self {
+            min_volumes: [0, 100_000, 500_000, 1_000_000, 5_000_000, 10_000_000, 50_000_000, 100_000_000, 500_000_000, 1_000_000_000]
+                .into_iter()
+                .map(Decimal::from)
+                .collect(),
+            recalculation_time: NaiveTime::MIN,
+        }
+    }
+}
+
+impl FeeTierSchedule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_volumes.first() != Some(&Decimal::ZERO) {
+            return Err("The lowest fee tier must start at zero volume".to_string());
+        }
+        if self.min_volumes.len() > u8::MAX as usize + 1 {
+            return Err(format!("At most {} fee tiers are supported", u8::MAX as usize + 1));
+        }
+        if self.min_volumes.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err("Fee tier volumes must increase with the tier".to_string());
+        }
+        Ok(())
+    }
+
+    /// Highest tier `volume` qualifies for.
+    pub fn tier_for(&self, volume: Decimal) -> u8 {
+        let qualified = self.min_volumes.iter().take_while(|min_volume| volume >= **min_volume).count();
+        qualified.saturating_sub(1) as u8
+    }
+
+    pub fn tier_name(tier: u8) -> String {
+        format!("VIP{}", tier)
+    }
+
+    /// First recalculation strictly after `now`.
+    pub fn next_recalculation(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let today = now.date_naive().and_time(self.recalculation_time).and_utc();
+        if today > now {
+            today
+        } else {
+            today + Days::new(1)
+        }
+    }
+}
+
+/// Every user's traded notional by day, as written to a snapshot.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VolumeState {
+    #[serde(serialize_with = "serialize_daily_volumes")]
+    pub users: BTreeMap<Uuid, BTreeMap<NaiveDate, Decimal>>,
+}
+
+/// Writes each day's volume as an exact decimal string rather than a float.
+fn serialize_daily_volumes<S>(users: &BTreeMap<Uuid, BTreeMap<NaiveDate, Decimal>>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_map(users.iter().map(|(user_id, days)| {
+        let days: BTreeMap<_, _> = days.iter().map(|(day, volume)| (day, volume.to_string())).collect();
+        (user_id, days)
+    }))
+}
+
+/// Notional each user traded per UTC day, over the last [`VOLUME_WINDOW_DAYS`].
+///
+/// Volume is kept in UTC day buckets, so the window does not roll continuously: it is the
+/// current day so far plus the [`VOLUME_WINDOW_DAYS`] whole days before it, and the oldest of
+/// those drops out all at once at midnight. Tiers are recalculated once a day, and at the default
+/// midnight recalculation the window is exactly the last [`VOLUME_WINDOW_DAYS`] days.
+/// Both sides of a trade are credited with its price * amount, in the pair's quote asset.
+#[derive(Debug, Default)]
+pub struct VolumeTracker {
+    users: DashMap<Uuid, BTreeMap<NaiveDate, Decimal>>,
+}
+
+impl VolumeTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, trade: &Trade) {
+        let notional = trade.amount * trade.price;
+        let day = trade.timestamp.date_naive();
+        for user_id in [trade.buyer_id, trade.seller_id] {
+            *self.users.entry(user_id).or_default().entry(day).or_default() += notional;
+        }
+    }
+
+    /// What the user traded in the window ending at `now`.
+    pub fn rolling_volume(&self, user_id: Uuid, now: DateTime<Utc>) -> Decimal {
+        let Some(days) = self.users.get(&user_id) else {
+            return Decimal::ZERO;
+        };
+        days.range(window_start(now)..).map(|(_, notional)| *notional).sum()
+    }
+
+    /// Every user with any volume recorded.
+    pub fn users(&self) -> Vec<Uuid> {
+        self.users.iter().map(|entry| *entry.key()).collect()
+    }
+
+    /// Forgets days that have left the window as of `now`.
+    pub fn prune(&self, now: DateTime<Utc>) {
+        let start = window_start(now);
+        self.users.retain(|_, days| {
+            days.retain(|day, _| *day >= start);
+            !days.is_empty()
+        });
+    }
+
+    pub fn state(&self) -> VolumeState {
+        VolumeState {
+            users: self.users.iter().map(|entry| (*entry.key(), entry.value().clone())).collect(),
+        }
+    }
+
+    pub fn restore_state(&self, state: VolumeState) {
+        self.users.clear();
+        for (user_id, days) in state.users {
+            self.users.insert(user_id, days);
+        }
+    }
+}
+
+/// Earliest day bucket still inside the window ending at `now`: the whole day, even the part
+/// of it more than [`VOLUME_WINDOW_DAYS`] before `now`.
+fn window_start(now: DateTime<Utc>) -> NaiveDate {
+    (now - Days::new(VOLUME_WINDOW_DAYS)).date_naive()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order::{Order, OrderKind, OrderType};
+    use chrono::TimeZone;
+
+    fn at(day: u32, hour: u32, min: u32, sec: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 3, day, hour, min, sec).unwrap()
+    }
+
+    fn trade(buyer: Uuid, notional: i64, timestamp: DateTime<Utc>) -> Trade {
+        let order = |user_id, order_type| {
+            Order::new(user_id, "BTC/USDT".to_string(), order_type, OrderKind::Limit, Decimal::ONE, Some(notional.into()))
+        };
+        let (buy, sell) = (order(buyer, OrderType::Buy), order(Uuid::new_v4(), OrderType::Sell));
+        Trade::new(&buy, &sell, Decimal::ONE, notional.into(), OrderType::Sell, timestamp)
+    }
+
+    #[test]
+    fn tiers_start_at_their_minimum_volume() {
+        let schedule = FeeTierSchedule::default();
+        assert_eq!(schedule.validate(), Ok(()));
+        assert_eq!(schedule.tier_for(Decimal::ZERO), 0);
+        assert_eq!(schedule.tier_for(Decimal::from(100_000) - Decimal::new(1, 8)), 0);
+        assert_eq!(schedule.tier_for(Decimal::from(100_000)), 1);
+        assert_eq!(schedule.tier_for(Decimal::from(999_999_999)), 8);
+        assert_eq!(schedule.tier_for(Decimal::from(1_000_000_000)), 9);
+        assert_eq!(schedule.tier_for(Decimal::from(u64::MAX)), 9);
+        assert_eq!(FeeTierSchedule::tier_name(9), "VIP9");
+    }
+
+    #[test]
+    fn unusable_schedules_are_refused() {
+        let schedule = |volumes: &[i64]| FeeTierSchedule {
+            min_volumes: volumes.iter().copied().map(Decimal::from).collect(),
+            ..FeeTierSchedule::default()
+        };
+        assert!(schedule(&[]).validate().is_err());
+        assert!(schedule(&[10, 20]).validate().is_err());
+        assert!(schedule(&[0, 20, 20]).validate().is_err());
+        assert!(schedule(&[0, 30, 20]).validate().is_err());
+    }
+
+    #[test]
+    fn recalculations_happen_strictly_after_now() {
+        let schedule = FeeTierSchedule {
+            recalculation_time: NaiveTime::from_hms_opt(8, 0, 0).unwrap(),
+            ..FeeTierSchedule::default()
+        };
+        assert_eq!(schedule.next_recalculation(at(10, 7, 59, 59)), at(10, 8, 0, 0));
+        assert_eq!(schedule.next_recalculation(at(10, 8, 0, 0)), at(11, 8, 0, 0));
+        assert_eq!(schedule.next_recalculation(at(10, 23, 0, 0)), at(11, 8, 0, 0));
+    }
+
+    #[test]
+    fn a_day_leaves_the_window_all_at_once_at_midnight() {
+        let user = Uuid::new_v4();
+        let volumes = VolumeTracker::new();
+        volumes.record(&trade(user, 100, at(1, 0, 0, 0)));
+        volumes.record(&trade(user, 20, at(1, 23, 59, 59)));
+        volumes.record(&trade(user, 3, at(2, 12, 0, 0)));
+
+        // Day 1 is in the window for all of day 31, even once its trades are more than 30 days old
+        assert_eq!(volumes.rolling_volume(user, at(31, 0, 0, 0)), Decimal::from(123));
+        assert_eq!(volumes.rolling_volume(user, at(31, 23, 59, 59)), Decimal::from(123));
+        let april = |day| Utc.with_ymd_and_hms(2026, 4, day, 0, 0, 0).unwrap();
+        assert_eq!(volumes.rolling_volume(user, april(1)), Decimal::from(3));
+        assert_eq!(volumes.rolling_volume(user, april(2)), Decimal::ZERO);
+
+        // Pruning forgets exactly the days that have left
+        volumes.prune(april(1));
+        assert_eq!(volumes.state().users[&user].keys().copied().collect::<Vec<_>>(), vec![at(2, 0, 0, 0).date_naive()]);
+        volumes.prune(april(2));
+        assert!(volumes.users().is_empty());
+    }
+
+    #[test]
+    fn both_sides_are_credited_with_the_notional() {
+        let volumes = VolumeTracker::new();
+        let trade = trade(Uuid::new_v4(), 250, at(5, 9, 0, 0));
+        volumes.record(&trade);
+
+        for user_id in [trade.buyer_id, trade.seller_id] {
+            assert_eq!(volumes.rolling_volume(user_id, at(5, 10, 0, 0)), Decimal::from(250));
+        }
+        assert_eq!(volumes.rolling_volume(Uuid::new_v4(), at(5, 10, 0, 0)), Decimal::ZERO);
+
+        let restored = VolumeTracker::new();
+        restored.restore_state(serde_json::from_value(serde_json::to_value(volumes.state()).unwrap()).unwrap());
+        assert_eq!(restored.rolling_volume(trade.seller_id, at(5, 10, 0, 0)), Decimal::from(250));
+    }
+}
@@ -1,31 +1,103 @@
-use crate::engine::{OrderEngine, EngineError};
-use crate::order::{Order, OrderType, OrderKind};
+use crate::accounts::Balance;
+use crate::auth::{Identity, TokenVerifier};
+use crate::candles::{Candle, CandleInterval, CANDLE_HISTORY_LEN};
+use crate::engine::{EngineError, EngineResponse, L3Level, OrderEngine};
+use crate::events::{EngineEvent, EventKind};
+use crate::order::{Fill, Order, OrderStatus, OrderType, OrderKind, PostOnlyMode, SelfTradePrevented, SelfTradePrevention, TimeInForce};
+use crate::orderbook::OrderChangeKind;
+use crate::ticker::Ticker;
+use crate::volume::FeeTierSchedule;
+use crate::wire::{Protocol, WireDecimal};
 use anyhow::Result;
+use chrono::{DateTime, TimeZone, Utc};
 use futures_util::{SinkExt, StreamExt};
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::str::FromStr;
 use std::sync::Arc;
 use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
 use tokio_tungstenite::{accept_async, tungstenite::Message};
-use tracing::{info, warn, error};
+use tracing::{info, error, warn};
 use uuid::Uuid;
 
+/// Candles sent for a `get_candles` request that does not set a limit.
+const DEFAULT_CANDLE_LIMIT: usize = 500;
+
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type")]
 pub enum IncomingMessage {
+    #[serde(rename = "hello")]
+    Hello {
+        data: HelloData,
+    },
     #[serde(rename = "new_order")]
     NewOrder {
-        data: OrderData,
+        data: Box<OrderData>,
     },
     #[serde(rename = "cancel_order")]
     CancelOrder {
         data: CancelOrderData,
     },
+    #[serde(rename = "amend_order")]
+    AmendOrder {
+        data: AmendOrderData,
+    },
+    #[serde(rename = "get_order")]
+    GetOrder {
+        data: GetOrderData,
+    },
     #[serde(rename = "get_orderbook")]
     GetOrderBook {
         data: OrderBookRequest,
     },
+    #[serde(rename = "get_l3_snapshot")]
+    GetL3Snapshot {
+        data: OrderBookRequest,
+    },
+    #[serde(rename = "set_self_trade_prevention")]
+    SetSelfTradePrevention {
+        data: SelfTradePreventionData,
+    },
+    #[serde(rename = "authenticate")]
+    Authenticate {
+        data: AuthenticateData,
+    },
+    #[serde(rename = "subscribe")]
+    Subscribe {
+        data: SubscriptionData,
+    },
+    #[serde(rename = "unsubscribe")]
+    Unsubscribe {
+        data: SubscriptionData,
+    },
+    /// Credits funds custody has received. Only a service connection may send it.
+    #[serde(rename = "deposit")]
+    Deposit {
+        data: TransferData,
+    },
+    /// Debits funds custody is about to pay out. Only a service connection may send it.
+    #[serde(rename = "withdraw")]
+    Withdraw {
+        data: TransferData,
+    },
+    #[serde(rename = "get_balances")]
+    GetBalances {
+        data: GetBalancesData,
+    },
+    #[serde(rename = "get_fee_tier")]
+    GetFeeTier {
+        data: GetFeeTierData,
+    },
+    #[serde(rename = "get_candles")]
+    GetCandles {
+        data: GetCandlesData,
+    },
+    #[serde(rename = "get_tickers")]
+    GetTickers {
+        data: GetTickersData,
+    },
 }
 
 #[derive(Debug, Deserialize)]
@@ -38,8 +110,22 @@ pub struct OrderData {
     pub order_type: String,
     #[serde(rename = "orderType")]
     pub order_kind: String,
-    pub amount: f64,
-    pub price: Option<f64>,
+    pub amount: WireDecimal,
+    pub price: Option<WireDecimal>,
+    #[serde(rename = "triggerPrice")]
+    pub trigger_price: Option<WireDecimal>,
+    #[serde(rename = "displayAmount")]
+    pub display_amount: Option<WireDecimal>,
+    #[serde(rename = "timeInForce")]
+    pub time_in_force: Option<String>,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: Option<i64>,
+    #[serde(rename = "postOnly")]
+    pub post_only: Option<bool>,
+    #[serde(rename = "postOnlyMode")]
+    pub post_only_mode: Option<String>,
+    #[serde(rename = "selfTradePrevention")]
+    pub self_trade_prevention: Option<String>,
     pub timestamp: Option<i64>,
 }
 
@@ -47,7 +133,20 @@ pub struct OrderData {
 pub struct CancelOrderData {
     #[serde(rename = "orderId")]
     pub order_id: String,
-    pub pair: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AmendOrderData {
+    #[serde(rename = "orderId")]
+    pub order_id: String,
+    pub amount: Option<WireDecimal>,
+    pub price: Option<WireDecimal>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetOrderData {
+    #[serde(rename = "orderId")]
+    pub order_id: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -55,6 +154,66 @@ pub struct OrderBookRequest {
     pub pair: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct SelfTradePreventionData {
+    #[serde(rename = "userId")]
+    pub user_id: String,
+    pub mode: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthenticateData {
+    /// JSON Web Token issued by the backend: a user's login token, or a service token.
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TransferData {
+    #[serde(rename = "userId")]
+    pub user_id: String,
+    pub asset: String,
+    pub amount: WireDecimal,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetBalancesData {
+    #[serde(rename = "userId")]
+    pub user_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetFeeTierData {
+    #[serde(rename = "userId")]
+    pub user_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetCandlesData {
+    pub pair: String,
+    pub interval: CandleInterval,
+    /// Only candles opening before this time, in milliseconds since the epoch.
+    #[serde(rename = "endTime")]
+    pub end_time: Option<i64>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetTickersData {
+    /// Only these pairs; every listed pair when absent.
+    pub pairs: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HelloData {
+    #[serde(rename = "protocolVersion")]
+    pub protocol_version: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubscriptionData {
+    pub channel: String,
+}
+
 #[derive(Debug, Serialize)]
 #[serde(tag = "type")]
 pub enum OutgoingMessage {
@@ -70,10 +229,90 @@ pub enum OutgoingMessage {
     OrderCancelled {
         data: OrderCancelledData,
     },
+    #[serde(rename = "order_rejected")]
+    OrderRejected {
+        data: OrderRejectedData,
+    },
+    #[serde(rename = "order_repriced")]
+    OrderRepriced {
+        data: OrderRepricedData,
+    },
+    #[serde(rename = "order_amended")]
+    OrderAmended {
+        data: OrderAmendedData,
+    },
+    #[serde(rename = "amend_rejected")]
+    AmendRejected {
+        data: AmendRejectedData,
+    },
+    #[serde(rename = "self_trade_prevented")]
+    SelfTradePrevented {
+        data: SelfTradePreventedData,
+    },
+    #[serde(rename = "order_status")]
+    OrderStatus {
+        data: OrderStatusData,
+    },
     #[serde(rename = "orderbook_snapshot")]
     OrderBookSnapshot {
         data: OrderBookSnapshotData,
     },
+    #[serde(rename = "depth_update")]
+    DepthUpdate {
+        data: DepthUpdateData,
+    },
+    #[serde(rename = "l3_snapshot")]
+    L3Snapshot {
+        data: L3SnapshotData,
+    },
+    #[serde(rename = "l3_update")]
+    L3Update {
+        data: L3UpdateData,
+    },
+    #[serde(rename = "trade")]
+    Trade {
+        data: TradeData,
+    },
+    #[serde(rename = "ticker")]
+    Ticker {
+        data: TickerData,
+    },
+    #[serde(rename = "tickers")]
+    Tickers {
+        data: TickersData,
+    },
+    #[serde(rename = "balances")]
+    Balances {
+        data: BalancesData,
+    },
+    #[serde(rename = "fee_tier")]
+    FeeTier {
+        data: FeeTierData,
+    },
+    #[serde(rename = "candle")]
+    Candle {
+        data: CandleData,
+    },
+    #[serde(rename = "candles")]
+    Candles {
+        data: CandlesData,
+    },
+    #[serde(rename = "hello")]
+    Hello {
+        data: HelloData,
+    },
+    #[serde(rename = "authenticated")]
+    Authenticated {
+        data: AuthenticatedData,
+    },
+    #[serde(rename = "subscribed")]
+    Subscribed {
+        data: SubscriptionData,
+    },
+    #[serde(rename = "unsubscribed")]
+    Unsubscribed {
+        data: SubscriptionData,
+    },
     #[serde(rename = "error")]
     Error {
         message: String,
@@ -84,20 +323,110 @@ pub enum OutgoingMessage {
 pub struct OrderFilledData {
     #[serde(rename = "orderId")]
     pub order_id: String,
+    #[serde(rename = "tradeId")]
+    pub trade_id: String,
     #[serde(rename = "filledAmount")]
-    pub filled_amount: f64,
+    pub filled_amount: WireDecimal,
     #[serde(rename = "executedPrice")]
-    pub executed_price: f64,
+    pub executed_price: WireDecimal,
+    /// Cumulative amount filled on the order.
+    pub filled: WireDecimal,
+    #[serde(rename = "remainingAmount")]
+    pub remaining_amount: WireDecimal,
+    #[serde(rename = "isMaker")]
+    pub is_maker: bool,
+    /// Charged out of what the fill received; negative for a maker rebate.
+    pub fee: WireDecimal,
+    #[serde(rename = "feeAsset")]
+    pub fee_asset: String,
 }
 
 #[derive(Debug, Serialize)]
 pub struct OrderPartialData {
     #[serde(rename = "orderId")]
     pub order_id: String,
+    #[serde(rename = "tradeId")]
+    pub trade_id: String,
     #[serde(rename = "partialFill")]
-    pub partial_fill: f64,
+    pub partial_fill: WireDecimal,
+    #[serde(rename = "executedPrice")]
+    pub executed_price: WireDecimal,
+    /// Cumulative amount filled on the order.
+    pub filled: WireDecimal,
     #[serde(rename = "remainingAmount")]
-    pub remaining_amount: f64,
+    pub remaining_amount: WireDecimal,
+    #[serde(rename = "isMaker")]
+    pub is_maker: bool,
+    /// Charged out of what the fill received; negative for a maker rebate.
+    pub fee: WireDecimal,
+    #[serde(rename = "feeAsset")]
+    pub fee_asset: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BalancesData {
+    #[serde(rename = "userId")]
+    pub user_id: String,
+    /// Every asset the user holds, by name.
+    pub balances: Vec<BalanceData>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BalanceData {
+    pub asset: String,
+    pub available: WireDecimal,
+    pub locked: WireDecimal,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FeeTierData {
+    #[serde(rename = "userId")]
+    pub user_id: String,
+    pub tier: u8,
+    /// The tier as shown to traders, e.g. `VIP3`.
+    pub name: String,
+    /// Notional traded over the rolling window the tier is based on.
+    pub volume: WireDecimal,
+    /// When tiers are next recalculated, in milliseconds since the epoch.
+    #[serde(rename = "nextRecalculation")]
+    pub next_recalculation: i64,
+    /// What the tier pays on each pair, by pair name.
+    pub rates: Vec<FeeRateData>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FeeRateData {
+    pub pair: String,
+    pub maker: WireDecimal,
+    pub taker: WireDecimal,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CandleData {
+    pub pair: String,
+    pub interval: CandleInterval,
+    #[serde(rename = "openTime")]
+    pub open_time: i64,
+    /// End of the interval (exclusive), in milliseconds since the epoch.
+    #[serde(rename = "closeTime")]
+    pub close_time: i64,
+    pub open: WireDecimal,
+    pub high: WireDecimal,
+    pub low: WireDecimal,
+    pub close: WireDecimal,
+    pub volume: WireDecimal,
+    #[serde(rename = "quoteVolume")]
+    pub quote_volume: WireDecimal,
+    pub trades: u64,
+    pub closed: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CandlesData {
+    pub pair: String,
+    pub interval: CandleInterval,
+    /// Oldest first.
+    pub candles: Vec<CandleData>,
 }
 
 #[derive(Debug, Serialize)]
@@ -107,47 +436,433 @@ pub struct OrderCancelledData {
     pub reason: String,
 }
 
+#[derive(Debug, Serialize)]
+pub struct OrderRejectedData {
+    #[serde(rename = "orderId")]
+    pub order_id: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrderRepricedData {
+    #[serde(rename = "orderId")]
+    pub order_id: String,
+    #[serde(rename = "originalPrice")]
+    pub original_price: WireDecimal,
+    pub price: WireDecimal,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrderAmendedData {
+    #[serde(rename = "orderId")]
+    pub order_id: String,
+    pub amount: WireDecimal,
+    pub price: Option<WireDecimal>,
+    pub filled: WireDecimal,
+    #[serde(rename = "keptPriority")]
+    pub kept_priority: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AmendRejectedData {
+    #[serde(rename = "orderId")]
+    pub order_id: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SelfTradePreventedData {
+    pub id: String,
+    pub pair: String,
+    #[serde(rename = "userId")]
+    pub user_id: String,
+    #[serde(rename = "takerOrderId")]
+    pub taker_order_id: String,
+    #[serde(rename = "makerOrderId")]
+    pub maker_order_id: String,
+    pub mode: SelfTradePrevention,
+    #[serde(rename = "preventedAmount")]
+    pub prevented_amount: WireDecimal,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrderStatusData {
+    #[serde(rename = "orderId")]
+    pub order_id: String,
+    pub pair: String,
+    #[serde(rename = "type")]
+    pub order_type: OrderType,
+    #[serde(rename = "orderType")]
+    pub order_kind: OrderKind,
+    pub status: OrderStatus,
+    pub amount: WireDecimal,
+    pub price: Option<WireDecimal>,
+    pub filled: WireDecimal,
+    #[serde(rename = "remainingAmount")]
+    pub remaining_amount: WireDecimal,
+}
+
+/// Changed price levels with their new totals, zero when a level is gone.
+///
+/// Covers depth sequence numbers `first_seq..=last_seq`. Each update starts right after the one
+/// before it, so a client that sees `first_seq` skip ahead has missed changes and should fetch a
+/// fresh `get_orderbook` snapshot, dropping updates up to the snapshot's `seq`.
+#[derive(Debug, Serialize)]
+pub struct DepthUpdateData {
+    pub pair: String,
+    #[serde(rename = "firstSeq")]
+    pub first_seq: u64,
+    #[serde(rename = "lastSeq")]
+    pub last_seq: u64,
+    pub bids: Vec<(WireDecimal, WireDecimal)>,
+    pub asks: Vec<(WireDecimal, WireDecimal)>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct L3SnapshotData {
+    pub pair: String,
+    pub bids: Vec<L3LevelData>,
+    pub asks: Vec<L3LevelData>,
+    /// L3 sequence number the snapshot reflects.
+    pub seq: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct L3LevelData {
+    pub price: WireDecimal,
+    /// (handle, size) of each order, front of the queue first.
+    pub orders: Vec<(u64, WireDecimal)>,
+}
+
+/// One change to a resting order. `seq` goes up by one per change, so a skipped number means
+/// updates were missed and the client should fetch a fresh `get_l3_snapshot`.
+#[derive(Debug, Serialize)]
+pub struct L3UpdateData {
+    pub pair: String,
+    pub seq: u64,
+    pub kind: OrderChangeKind,
+    pub handle: u64,
+    pub side: OrderType,
+    pub price: WireDecimal,
+    pub size: WireDecimal,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TradeData {
+    pub pair: String,
+    #[serde(rename = "tradeId")]
+    pub trade_id: String,
+    pub price: WireDecimal,
+    pub amount: WireDecimal,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TickerData {
+    pub pair: String,
+    #[serde(rename = "lastPrice")]
+    pub last_price: Option<WireDecimal>,
+    #[serde(rename = "bestBid")]
+    pub best_bid: Option<WireDecimal>,
+    #[serde(rename = "bestBidSize")]
+    pub best_bid_size: WireDecimal,
+    #[serde(rename = "bestAsk")]
+    pub best_ask: Option<WireDecimal>,
+    #[serde(rename = "bestAskSize")]
+    pub best_ask_size: WireDecimal,
+    /// Rolling 24 hour statistics; prices are absent when nothing traded in that time.
+    pub open: Option<WireDecimal>,
+    pub high: Option<WireDecimal>,
+    pub low: Option<WireDecimal>,
+    pub volume: WireDecimal,
+    #[serde(rename = "quoteVolume")]
+    pub quote_volume: WireDecimal,
+    #[serde(rename = "priceChangePercent")]
+    pub price_change_percent: Option<WireDecimal>,
+    #[serde(rename = "weightedAvgPrice")]
+    pub weighted_avg_price: Option<WireDecimal>,
+    pub trades: u64,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TickersData {
+    pub tickers: Vec<TickerData>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuthenticatedData {
+    /// The authenticated user, absent for a service connection.
+    #[serde(rename = "userId", skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    /// `user` or `service`.
+    pub role: &'static str,
+}
+
 #[derive(Debug, Serialize)]
 pub struct OrderBookSnapshotData {
     pub pair: String,
-    pub bids: Vec<(f64, f64)>,
-    pub asks: Vec<(f64, f64)>,
+    pub bids: Vec<(WireDecimal, WireDecimal)>,
+    pub asks: Vec<(WireDecimal, WireDecimal)>,
     #[serde(rename = "bestBid")]
-    pub best_bid: Option<f64>,
+    pub best_bid: Option<WireDecimal>,
     #[serde(rename = "bestAsk")]
-    pub best_ask: Option<f64>,
-    pub spread: Option<f64>,
+    pub best_ask: Option<WireDecimal>,
+    pub spread: Option<WireDecimal>,
+    /// Depth sequence number the snapshot reflects.
+    pub seq: u64,
+}
+
+/// A stream a connection can subscribe to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Channel {
+    /// Private events for one user's orders, `user:{userId}`.
+    User(Uuid),
+    /// Price level changes, `depth:{pair}`.
+    Depth(String),
+    /// Executed trades, `trades:{pair}`.
+    Trades(String),
+    /// Last price and top of book, `ticker:{pair}`.
+    Ticker(String),
+    /// Order-by-order book changes, `l3:{pair}`.
+    L3(String),
+    /// Candle updates at one interval, `candles:{pair}:{interval}`.
+    Candles(String, CandleInterval),
+}
+
+impl Channel {
+    /// The pair a public market data channel is for.
+    pub fn pair(&self) -> Option<&str> {
+        match self {
+            Channel::User(_) => None,
+            Channel::Depth(pair) | Channel::Trades(pair) | Channel::Ticker(pair) | Channel::L3(pair) | Channel::Candles(pair, _) => {
+                Some(pair)
+            }
+        }
+    }
+}
+
+impl FromStr for Channel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some(("user", user_id)) => Ok(Channel::User(Uuid::from_str(user_id)?)),
+            Some(("depth", pair)) => Ok(Channel::Depth(pair.to_string())),
+            Some(("trades", pair)) => Ok(Channel::Trades(pair.to_string())),
+            Some(("ticker", pair)) => Ok(Channel::Ticker(pair.to_string())),
+            Some(("l3", pair)) => Ok(Channel::L3(pair.to_string())),
+            Some(("candles", rest)) => match rest.rsplit_once(':') {
+                Some((pair, interval)) => Ok(Channel::Candles(pair.to_string(), interval.parse().map_err(anyhow::Error::msg)?)),
+                None => Err(anyhow::anyhow!("Invalid channel: {}", s)),
+            },
+            _ => Err(anyhow::anyhow!("Invalid channel: {}", s)),
+        }
+    }
+}
+
+/// What one connection has proven it is and asked to hear about.
+#[derive(Debug, Default)]
+pub struct ConnectionState {
+    pub protocol: Protocol,
+    /// Set by a verified `authenticate`; `None` for an anonymous connection.
+    pub identity: Option<Identity>,
+    pub channels: HashSet<Channel>,
+    pending_depth: HashMap<String, DepthDelta>, // Level changes not yet sent, by pair
+}
+
+/// Level changes for one pair, collected into a single depth update.
+#[derive(Debug)]
+struct DepthDelta {
+    first_seq: u64,
+    last_seq: u64,
+    bids: BTreeMap<Decimal, Decimal>,
+    asks: BTreeMap<Decimal, Decimal>,
 }
 
-pub async fn handle_connection(stream: TcpStream, engine: Arc<OrderEngine>) -> Result<()> {
+impl ConnectionState {
+    /// Whether private events for `user_id` are routed to this connection.
+    pub fn follows_user(&self, user_id: Uuid) -> bool {
+        self.identity == Some(Identity::User(user_id)) || self.channels.contains(&Channel::User(user_id))
+    }
+
+    /// Whether the connection has authenticated as `user_id` or as a service acting for them.
+    pub fn may_act_for(&self, user_id: Uuid) -> bool {
+        self.identity.is_some_and(|identity| identity.may_act_for(user_id))
+    }
+
+    /// Whether the connection may subscribe to `channel`. A user's private stream is only open to
+    /// that user and to services.
+    fn may_subscribe(&self, channel: &Channel) -> bool {
+        match channel {
+            Channel::User(user_id) => self.may_act_for(*user_id),
+            _ => true,
+        }
+    }
+
+    /// One depth update per pair for the level changes routed since the last call.
+    fn take_depth_updates(&mut self) -> Vec<OutgoingMessage> {
+        let protocol = self.protocol;
+        let levels = |levels: BTreeMap<Decimal, Decimal>| {
+            levels
+                .into_iter()
+                .map(|(price, amount)| (protocol.number(price), protocol.number(amount)))
+                .collect()
+        };
+
+        self.pending_depth
+            .drain()
+            .map(|(pair, delta)| OutgoingMessage::DepthUpdate {
+                data: DepthUpdateData {
+                    pair,
+                    first_seq: delta.first_seq,
+                    last_seq: delta.last_seq,
+                    bids: levels(delta.bids),
+                    asks: levels(delta.asks),
+                },
+            })
+            .collect()
+    }
+
+    fn is_subscribed(&self, channel: fn(String) -> Channel, pair: &str) -> bool {
+        self.channels.contains(&channel(pair.to_string()))
+    }
+
+    /// The messages this connection should get for an engine event. Level changes are held back
+    /// for [`ConnectionState::take_depth_updates`].
+    fn route_event(&mut self, event: &EngineEvent) -> Vec<OutgoingMessage> {
+        let protocol = self.protocol;
+        match &event.kind {
+            EventKind::OrderFilled { fill } | EventKind::OrderPartiallyFilled { fill } if self.follows_user(fill.user_id) => {
+                vec![fill_message(fill, protocol)]
+            }
+            EventKind::OrderCancelled { order } | EventKind::OrderRejected { order } if self.follows_user(order.user_id) => {
+                closed_order_message(order).into_iter().collect()
+            }
+            EventKind::OrderAmended { order, kept_priority, .. } if self.follows_user(order.user_id) => {
+                vec![order_amended_message(order, *kept_priority, protocol)]
+            }
+            EventKind::SelfTradePrevented { prevented } if self.follows_user(prevented.user_id) => {
+                vec![self_trade_prevented_message(prevented, protocol)]
+            }
+            EventKind::TradeExecuted { trade } if self.is_subscribed(Channel::Trades, &event.pair) => {
+                vec![OutgoingMessage::Trade {
+                    data: TradeData {
+                        pair: trade.pair.clone(),
+                        trade_id: trade.id.to_string(),
+                        price: protocol.number(trade.price),
+                        amount: protocol.number(trade.amount),
+                        timestamp: trade.timestamp.timestamp_millis(),
+                    },
+                }]
+            }
+            EventKind::BookLevelChanged { side, price, amount, depth_seq } if self.is_subscribed(Channel::Depth, &event.pair) => {
+                let delta = self.pending_depth.entry(event.pair.clone()).or_insert_with(|| DepthDelta {
+                    first_seq: *depth_seq,
+                    last_seq: *depth_seq,
+                    bids: BTreeMap::new(),
+                    asks: BTreeMap::new(),
+                });
+                delta.last_seq = *depth_seq;
+                match side {
+                    OrderType::Buy => delta.bids.insert(*price, *amount),
+                    OrderType::Sell => delta.asks.insert(*price, *amount),
+                };
+                Vec::new()
+            }
+            EventKind::BookOrderChanged { change, l3_seq } if self.is_subscribed(Channel::L3, &event.pair) => {
+                vec![OutgoingMessage::L3Update {
+                    data: L3UpdateData {
+                        pair: event.pair.clone(),
+                        seq: *l3_seq,
+                        kind: change.kind,
+                        handle: change.handle,
+                        side: change.side.clone(),
+                        price: protocol.number(change.price),
+                        size: protocol.number(change.size),
+                    },
+                }]
+            }
+            EventKind::TickerChanged { ticker } if self.is_subscribed(Channel::Ticker, &event.pair) => {
+                vec![ticker_message(ticker, event.timestamp, protocol)]
+            }
+            EventKind::CandleUpdated { candle } if self.channels.contains(&Channel::Candles(event.pair.clone(), candle.interval)) => {
+                vec![OutgoingMessage::Candle { data: candle_data(candle, protocol) }]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+pub async fn handle_connection(stream: TcpStream, engine: Arc<OrderEngine>, tokens: Arc<TokenVerifier>) -> Result<()> {
     let ws_stream = accept_async(stream).await?;
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+    let mut events = engine.subscribe();
+    let mut state = ConnectionState::default();
 
     info!("WebSocket connection established");
 
-    while let Some(msg) = ws_receiver.next().await {
-        match msg {
-            Ok(Message::Text(text)) => {
-                if let Err(e) = handle_message(text, &mut ws_sender, &engine).await {
-                    error!("Error handling message: {}", e);
-                    let error_msg = OutgoingMessage::Error {
-                        message: e.to_string(),
-                    };
+    loop {
+        tokio::select! {
+            msg = ws_receiver.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    if let Err(e) = handle_message(text, &mut ws_sender, &engine, &tokens, &mut state).await {
+                        error!("Error handling message: {}", e);
+                        let error_msg = OutgoingMessage::Error {
+                            message: e.to_string(),
+                        };
 
-                    if let Ok(json) = serde_json::to_string(&error_msg) {
-                        let _ = ws_sender.send(Message::Text(json)).await;
+                        if let Ok(json) = serde_json::to_string(&error_msg) {
+                            let _ = ws_sender.send(Message::Text(json)).await;
+                        }
                     }
                 }
+                Some(Ok(Message::Close(_))) | None => {
+                    info!("WebSocket connection closed");
+                    break;
+                }
+                Some(Err(e)) => {
+                    error!("WebSocket error: {}", e);
+                    break;
+                }
+                _ => {}
+            },
+            event = events.recv() => {
+                // Drain whatever else is already queued, so level changes go out in as few
+                // depth updates as possible
+                let mut next = Some(event);
+                while let Some(event) = next {
+                    match event {
+                        Ok(event) => {
+                            for msg in state.route_event(&event) {
+                                let json = serde_json::to_string(&msg)?;
+                                ws_sender.send(Message::Text(json)).await?;
+                            }
+                        }
+                        Err(RecvError::Lagged(missed)) => {
+                            warn!("WebSocket connection fell behind and missed {} engine events", missed);
+                            let error_msg = OutgoingMessage::Error {
+                                message: format!("Missed {} events, re-query open orders and resync depth", missed),
+                            };
+                            let json = serde_json::to_string(&error_msg)?;
+                            ws_sender.send(Message::Text(json)).await?;
+                        }
+                        Err(RecvError::Closed) => return Ok(()),
+                    }
+
+                    next = match events.try_recv() {
+                        Ok(event) => Some(Ok(event)),
+                        Err(TryRecvError::Lagged(missed)) => Some(Err(RecvError::Lagged(missed))),
+                        Err(TryRecvError::Empty | TryRecvError::Closed) => None,
+                    };
+                }
+
+                for msg in state.take_depth_updates() {
+                    let json = serde_json::to_string(&msg)?;
+                    ws_sender.send(Message::Text(json)).await?;
+                }
             }
-            Ok(Message::Close(_)) => {
-                info!("WebSocket connection closed");
-                break;
-            }
-            Err(e) => {
-                error!("WebSocket error: {}", e);
-                break;
-            }
-            _ => {}
         }
     }
 
@@ -161,21 +876,182 @@ async fn handle_message(
         Message,
     >,
     engine: &Arc<OrderEngine>,
+    tokens: &TokenVerifier,
+    state: &mut ConnectionState,
 ) -> Result<()> {
     let incoming_msg: IncomingMessage = serde_json::from_str(&text)?;
 
     match incoming_msg {
+        IncomingMessage::Hello { data } => {
+            state.protocol = Protocol::from_version(data.protocol_version)?;
+
+            let msg = OutgoingMessage::Hello {
+                data: HelloData { protocol_version: state.protocol.version() },
+            };
+            let json = serde_json::to_string(&msg)?;
+            ws_sender.send(Message::Text(json)).await?;
+        }
         IncomingMessage::NewOrder { data } => {
-            handle_new_order(data, ws_sender, engine).await?;
+            handle_new_order(*data, ws_sender, engine, state).await?;
         }
         IncomingMessage::CancelOrder { data } => {
-            handle_cancel_order(data, ws_sender, engine).await?;
+            authorize_order(engine, state, &data.order_id).await?;
+            handle_cancel_order(data, ws_sender, engine, state).await?;
+        }
+        IncomingMessage::AmendOrder { data } => {
+            authorize_order(engine, state, &data.order_id).await?;
+            handle_amend_order(data, ws_sender, engine, state).await?;
+        }
+        IncomingMessage::GetOrder { data } => {
+            authorize_order(engine, state, &data.order_id).await?;
+            handle_get_order(data, ws_sender, engine, state.protocol).await?;
         }
         IncomingMessage::GetOrderBook { data } => {
-            handle_get_orderbook(data, ws_sender, engine).await?;
+            handle_get_orderbook(data, ws_sender, engine, state.protocol).await?;
+        }
+        IncomingMessage::GetL3Snapshot { data } => {
+            handle_get_l3_snapshot(data, ws_sender, engine, state.protocol).await?;
+        }
+        IncomingMessage::SetSelfTradePrevention { data } => {
+            let user_id = Uuid::from_str(&data.user_id)?;
+            authorize(state, user_id)?;
+            engine.set_self_trade_prevention(user_id, parse_self_trade_prevention(&data.mode)?)?;
+        }
+        IncomingMessage::Authenticate { data } => {
+            let identity = tokens.verify(&data.token, Utc::now()).map_err(|e| anyhow::anyhow!("Authentication failed: {}", e))?;
+            state.identity = Some(identity);
+            // Re-authenticating as someone else drops the private streams they may not follow
+            let channels = std::mem::take(&mut state.channels);
+            state.channels = channels.into_iter().filter(|channel| state.may_subscribe(channel)).collect();
+
+            let data = match identity {
+                Identity::User(user_id) => AuthenticatedData { user_id: Some(user_id.to_string()), role: "user" },
+                Identity::Service => AuthenticatedData { user_id: None, role: "service" },
+            };
+            let msg = OutgoingMessage::Authenticated { data };
+            let json = serde_json::to_string(&msg)?;
+            ws_sender.send(Message::Text(json)).await?;
+        }
+        IncomingMessage::Subscribe { data } => {
+            let channel = Channel::from_str(&data.channel)?;
+            if !state.may_subscribe(&channel) {
+                return Err(anyhow::anyhow!("Not authorized to subscribe to {}", data.channel));
+            }
+            if let Some(pair) = channel.pair() {
+                if engine.get_market(pair).is_none() {
+                    return Err(anyhow::anyhow!("Unknown trading pair: {}", pair));
+                }
+            }
+            state.channels.insert(channel.clone());
+
+            let msg = OutgoingMessage::Subscribed { data };
+            let json = serde_json::to_string(&msg)?;
+            ws_sender.send(Message::Text(json)).await?;
+
+            // Start the subscriber off with the current state; updates follow as it changes
+            match channel {
+                Channel::Depth(pair) => {
+                    handle_get_orderbook(OrderBookRequest { pair }, ws_sender, engine, state.protocol).await?;
+                }
+                Channel::Ticker(pair) => {
+                    if let Some(ticker) = engine.get_ticker(&pair).await? {
+                        let json = serde_json::to_string(&ticker_message(&ticker, Utc::now(), state.protocol))?;
+                        ws_sender.send(Message::Text(json)).await?;
+                    }
+                }
+                Channel::L3(pair) => {
+                    handle_get_l3_snapshot(OrderBookRequest { pair }, ws_sender, engine, state.protocol).await?;
+                }
+                Channel::Candles(pair, interval) => {
+                    if let Some(candle) = engine.get_candles(&pair, interval, None, 1).await?.pop() {
+                        let msg = OutgoingMessage::Candle { data: candle_data(&candle, state.protocol) };
+                        let json = serde_json::to_string(&msg)?;
+                        ws_sender.send(Message::Text(json)).await?;
+                    }
+                }
+                Channel::User(_) | Channel::Trades(_) => {}
+            }
+        }
+        IncomingMessage::Unsubscribe { data } => {
+            state.channels.remove(&Channel::from_str(&data.channel)?);
+
+            let msg = OutgoingMessage::Unsubscribed { data };
+            let json = serde_json::to_string(&msg)?;
+            ws_sender.send(Message::Text(json)).await?;
+        }
+        IncomingMessage::Deposit { data } => {
+            handle_transfer(data, OrderEngine::deposit, ws_sender, engine, state).await?;
+        }
+        IncomingMessage::Withdraw { data } => {
+            handle_transfer(data, OrderEngine::withdraw, ws_sender, engine, state).await?;
+        }
+        IncomingMessage::GetBalances { data } => {
+            let user_id = Uuid::from_str(&data.user_id)?;
+            authorize(state, user_id)?;
+            let msg = balances_message(engine, user_id, state.protocol);
+            let json = serde_json::to_string(&msg)?;
+            ws_sender.send(Message::Text(json)).await?;
+        }
+        IncomingMessage::GetFeeTier { data } => {
+            let user_id = Uuid::from_str(&data.user_id)?;
+            authorize(state, user_id)?;
+            let msg = fee_tier_message(engine, user_id, state.protocol);
+            let json = serde_json::to_string(&msg)?;
+            ws_sender.send(Message::Text(json)).await?;
         }
+        IncomingMessage::GetCandles { data } => {
+            let end_time = data
+                .end_time
+                .map(|ms| Utc.timestamp_millis_opt(ms).single().ok_or_else(|| anyhow::anyhow!("Invalid endTime: {}", ms)))
+                .transpose()?;
+            let limit = data.limit.unwrap_or(DEFAULT_CANDLE_LIMIT).clamp(1, CANDLE_HISTORY_LEN);
+            let candles = engine.get_candles(&data.pair, data.interval, end_time, limit).await?;
+
+            let msg = OutgoingMessage::Candles {
+                data: CandlesData {
+                    pair: data.pair,
+                    interval: data.interval,
+                    candles: candles.iter().map(|candle| candle_data(candle, state.protocol)).collect(),
+                },
+            };
+            let json = serde_json::to_string(&msg)?;
+            ws_sender.send(Message::Text(json)).await?;
+        }
+        IncomingMessage::GetTickers { data } => {
+            let now = Utc::now();
+            let tickers = engine
+                .get_tickers()
+                .await?
+                .iter()
+                .filter(|ticker| data.pairs.as_ref().is_none_or(|pairs| pairs.contains(&ticker.pair)))
+                .map(|ticker| ticker_data(ticker, now, state.protocol))
+                .collect();
+
+            let msg = OutgoingMessage::Tickers {
+                data: TickersData { tickers },
+            };
+            let json = serde_json::to_string(&msg)?;
+            ws_sender.send(Message::Text(json)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Fails unless the connection has authenticated as `user_id` or as a service.
+fn authorize(state: &ConnectionState, user_id: Uuid) -> Result<()> {
+    if !state.may_act_for(user_id) {
+        return Err(anyhow::anyhow!("Not authorized to act for user {}", user_id));
     }
+    Ok(())
+}
 
+/// Fails unless the connection may act for the owner of order `order_id`. Orders the engine does
+/// not know are left to the handler to report.
+async fn authorize_order(engine: &OrderEngine, state: &ConnectionState, order_id: &str) -> Result<()> {
+    if let Some(order) = engine.get_order(Uuid::from_str(order_id)?).await? {
+        authorize(state, order.user_id)?;
+    }
     Ok(())
 }
 
@@ -186,6 +1062,7 @@ async fn handle_new_order(
         Message,
     >,
     engine: &Arc<OrderEngine>,
+    state: &ConnectionState,
 ) -> Result<()> {
     // Parse order data
     let order_id = data.id
@@ -194,6 +1071,7 @@ async fn handle_new_order(
         .unwrap_or_else(Uuid::new_v4);
 
     let user_id = Uuid::from_str(&data.user_id)?;
+    authorize(state, user_id)?;
 
     let order_type = match data.order_type.as_str() {
         "buy" => OrderType::Buy,
@@ -204,16 +1082,51 @@ async fn handle_new_order(
     let order_kind = match data.order_kind.as_str() {
         "market" => OrderKind::Market,
         "limit" => OrderKind::Limit,
+        "stop" => OrderKind::Stop,
+        "stop_limit" => OrderKind::StopLimit,
         _ => return Err(anyhow::anyhow!("Invalid order kind: {}", data.order_kind)),
     };
 
-    let amount = Decimal::from_f64_retain(data.amount)
-        .ok_or_else(|| anyhow::anyhow!("Invalid amount: {}", data.amount))?;
+    let spec = engine.get_market(&data.pair)
+        .ok_or_else(|| anyhow::anyhow!("Unknown trading pair: {}", data.pair))?;
+    let protocol = state.protocol;
+
+    let amount = protocol.parse(&data.amount, "amount", spec.quantity_precision())?;
 
     let price = data.price
-        .map(|p| Decimal::from_f64_retain(p))
-        .transpose()
-        .ok_or_else(|| anyhow::anyhow!("Invalid price: {:?}", data.price))?;
+        .map(|p| protocol.parse(&p, "price", spec.price_precision))
+        .transpose()?;
+
+    let trigger_price = data.trigger_price
+        .map(|p| protocol.parse(&p, "trigger price", spec.price_precision))
+        .transpose()?;
+
+    let display_amount = data.display_amount
+        .map(|a| protocol.parse(&a, "display amount", spec.quantity_precision()))
+        .transpose()?;
+
+    let time_in_force = match data.time_in_force.as_deref() {
+        None | Some("gtc") => TimeInForce::Gtc,
+        Some("ioc") => TimeInForce::Ioc,
+        Some("fok") => TimeInForce::Fok,
+        Some("gtd") => TimeInForce::Gtd,
+        Some(other) => return Err(anyhow::anyhow!("Invalid time in force: {}", other)),
+    };
+
+    let post_only_mode = match data.post_only_mode.as_deref() {
+        None | Some("reject") => PostOnlyMode::Reject,
+        Some("slide") => PostOnlyMode::Slide,
+        Some(other) => return Err(anyhow::anyhow!("Invalid post-only mode: {}", other)),
+    };
+
+    let self_trade_prevention = data.self_trade_prevention
+        .as_deref()
+        .map(parse_self_trade_prevention)
+        .transpose()?;
+
+    let expires_at = data.expires_at
+        .map(|ms| Utc.timestamp_millis_opt(ms).single().ok_or_else(|| anyhow::anyhow!("Invalid expiry: {}", ms)))
+        .transpose()?;
 
     // Create order
     let mut order = Order::new(
@@ -225,36 +1138,18 @@ async fn handle_new_order(
         price,
     );
     order.id = order_id;
+    order.trigger_price = trigger_price;
+    order.display_amount = display_amount;
+    order.time_in_force = time_in_force;
+    order.expires_at = expires_at;
+    order.post_only = data.post_only.unwrap_or(false);
+    order.post_only_mode = post_only_mode;
+    order.self_trade_prevention = self_trade_prevention;
 
     // Process order
-    match engine.add_order(order.clone()) {
+    match engine.add_order(order.clone()).await {
         Ok(response) => {
-            // Send trade notifications
-            for trade in &response.trades {
-                if order.is_buy() && trade.buyer_id == user_id {
-                    // Buyer filled
-                    let fill_data = OrderFilledData {
-                        order_id: order.id.to_string(),
-                        filled_amount: trade.amount.to_f64().unwrap_or(0.0),
-                        executed_price: trade.price.to_f64().unwrap_or(0.0),
-                    };
-
-                    let msg = OutgoingMessage::OrderFilled { data: fill_data };
-                    let json = serde_json::to_string(&msg)?;
-                    ws_sender.send(Message::Text(json)).await?;
-                } else if order.is_sell() && trade.seller_id == user_id {
-                    // Seller filled
-                    let fill_data = OrderFilledData {
-                        order_id: order.id.to_string(),
-                        filled_amount: trade.amount.to_f64().unwrap_or(0.0),
-                        executed_price: trade.price.to_f64().unwrap_or(0.0),
-                    };
-
-                    let msg = OutgoingMessage::OrderFilled { data: fill_data };
-                    let json = serde_json::to_string(&msg)?;
-                    ws_sender.send(Message::Text(json)).await?;
-                }
-            }
+            send_execution_reports(&order, &response, ws_sender, state).await?;
 
             info!("Order {} processed successfully with {} trades", order.id, response.trades.len());
         }
@@ -271,6 +1166,196 @@ async fn handle_new_order(
     Ok(())
 }
 
+/// Sends the submitter everything that happened to their order: fills, prevented self-trades,
+/// repricing and a final cancel or reject.
+///
+/// Fills, prevented self-trades and the final cancel or reject are left out when the connection
+/// follows the order's user, since they reach it through that user's private stream.
+async fn send_execution_reports(
+    order: &Order,
+    response: &EngineResponse,
+    ws_sender: &mut futures_util::stream::SplitSink<
+        tokio_tungstenite::WebSocketStream<TcpStream>,
+        Message,
+    >,
+    state: &ConnectionState,
+) -> Result<()> {
+    let protocol = state.protocol;
+
+    // Send trade notifications
+    if !state.follows_user(order.user_id) {
+        for fill in response.fills.iter().filter(|fill| fill.order_id == order.id) {
+            let json = serde_json::to_string(&fill_message(fill, protocol))?;
+            ws_sender.send(Message::Text(json)).await?;
+        }
+    }
+
+    // Report every wash trade the engine refused to create
+    if !state.follows_user(order.user_id) {
+        for prevented in &response.self_trades_prevented {
+            let json = serde_json::to_string(&self_trade_prevented_message(prevented, protocol))?;
+            ws_sender.send(Message::Text(json)).await?;
+        }
+    }
+
+    // Acknowledge a post-only order that was slid behind the opposite best price
+    if let (Some(original_price), Some(price)) = (
+        response.repriced_from,
+        response.updated_order.as_ref().and_then(|o| o.price),
+    ) {
+        let repriced_data = OrderRepricedData {
+            order_id: order.id.to_string(),
+            original_price: protocol.number(original_price),
+            price: protocol.number(price),
+        };
+
+        let msg = OutgoingMessage::OrderRepriced { data: repriced_data };
+        let json = serde_json::to_string(&msg)?;
+        ws_sender.send(Message::Text(json)).await?;
+    }
+
+    // Tell the sender if the order, or its unfilled rest, did not make it onto the book
+    if !state.follows_user(order.user_id) {
+        if let Some(msg) = response.updated_order.as_ref().and_then(closed_order_message) {
+            let json = serde_json::to_string(&msg)?;
+            ws_sender.send(Message::Text(json)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// `order_amended` for an order as it stands after an amend.
+fn order_amended_message(order: &Order, kept_priority: bool, protocol: Protocol) -> OutgoingMessage {
+    OutgoingMessage::OrderAmended {
+        data: OrderAmendedData {
+            order_id: order.id.to_string(),
+            amount: protocol.number(order.amount),
+            price: order.price.map(|p| protocol.number(p)),
+            filled: protocol.number(order.filled),
+            kept_priority,
+        },
+    }
+}
+
+/// `self_trade_prevented` for a trade the engine refused to create.
+fn self_trade_prevented_message(prevented: &SelfTradePrevented, protocol: Protocol) -> OutgoingMessage {
+    OutgoingMessage::SelfTradePrevented {
+        data: SelfTradePreventedData {
+            id: prevented.id.to_string(),
+            pair: prevented.pair.clone(),
+            user_id: prevented.user_id.to_string(),
+            taker_order_id: prevented.taker_order_id.to_string(),
+            maker_order_id: prevented.maker_order_id.to_string(),
+            mode: prevented.mode,
+            prevented_amount: protocol.number(prevented.prevented_amount),
+            timestamp: prevented.timestamp.timestamp_millis(),
+        },
+    }
+}
+
+/// `order_cancelled` or `order_rejected` for an order that ended that way, nothing otherwise.
+fn closed_order_message(order: &Order) -> Option<OutgoingMessage> {
+    let reason = order.cancel_reason.as_ref().map(|r| r.to_string()).unwrap_or_default();
+    match order.status {
+        OrderStatus::Cancelled => Some(OutgoingMessage::OrderCancelled {
+            data: OrderCancelledData { order_id: order.id.to_string(), reason },
+        }),
+        OrderStatus::Rejected => Some(OutgoingMessage::OrderRejected {
+            data: OrderRejectedData { order_id: order.id.to_string(), reason },
+        }),
+        _ => None,
+    }
+}
+
+/// `order_filled` once the order has nothing left, `order_partial` before that.
+fn fill_message(fill: &Fill, protocol: Protocol) -> OutgoingMessage {
+    if fill.remaining.is_zero() {
+        OutgoingMessage::OrderFilled {
+            data: OrderFilledData {
+                order_id: fill.order_id.to_string(),
+                trade_id: fill.trade_id.to_string(),
+                filled_amount: protocol.number(fill.amount),
+                executed_price: protocol.number(fill.price),
+                filled: protocol.number(fill.filled),
+                remaining_amount: protocol.number(Decimal::ZERO),
+                is_maker: fill.is_maker,
+                fee: protocol.number(fill.fee),
+                fee_asset: fill.fee_asset.clone(),
+            },
+        }
+    } else {
+        OutgoingMessage::OrderPartial {
+            data: OrderPartialData {
+                order_id: fill.order_id.to_string(),
+                trade_id: fill.trade_id.to_string(),
+                partial_fill: protocol.number(fill.amount),
+                executed_price: protocol.number(fill.price),
+                filled: protocol.number(fill.filled),
+                remaining_amount: protocol.number(fill.remaining),
+                is_maker: fill.is_maker,
+                fee: protocol.number(fill.fee),
+                fee_asset: fill.fee_asset.clone(),
+            },
+        }
+    }
+}
+
+fn ticker_message(ticker: &Ticker, timestamp: DateTime<Utc>, protocol: Protocol) -> OutgoingMessage {
+    OutgoingMessage::Ticker {
+        data: ticker_data(ticker, timestamp, protocol),
+    }
+}
+
+fn ticker_data(ticker: &Ticker, timestamp: DateTime<Utc>, protocol: Protocol) -> TickerData {
+    TickerData {
+        pair: ticker.pair.clone(),
+        last_price: ticker.last_price.map(|p| protocol.number(p)),
+        best_bid: ticker.best_bid.map(|p| protocol.number(p)),
+        best_bid_size: protocol.number(ticker.best_bid_size),
+        best_ask: ticker.best_ask.map(|p| protocol.number(p)),
+        best_ask_size: protocol.number(ticker.best_ask_size),
+        open: ticker.open.map(|p| protocol.number(p)),
+        high: ticker.high.map(|p| protocol.number(p)),
+        low: ticker.low.map(|p| protocol.number(p)),
+        volume: protocol.number(ticker.volume),
+        quote_volume: protocol.number(ticker.quote_volume),
+        price_change_percent: ticker.price_change_percent.map(|p| protocol.number(p)),
+        weighted_avg_price: ticker.weighted_avg_price.map(|p| protocol.number(p)),
+        trades: ticker.trade_count,
+        timestamp: timestamp.timestamp_millis(),
+    }
+}
+
+fn candle_data(candle: &Candle, protocol: Protocol) -> CandleData {
+    CandleData {
+        pair: candle.pair.clone(),
+        interval: candle.interval,
+        open_time: candle.open_time.timestamp_millis(),
+        close_time: candle.close_time.timestamp_millis(),
+        open: protocol.number(candle.open),
+        high: protocol.number(candle.high),
+        low: protocol.number(candle.low),
+        close: protocol.number(candle.close),
+        volume: protocol.number(candle.volume),
+        quote_volume: protocol.number(candle.quote_volume),
+        trades: candle.trade_count,
+        closed: candle.closed,
+    }
+}
+
+fn parse_self_trade_prevention(mode: &str) -> Result<SelfTradePrevention> {
+    match mode {
+        "cancel_newest" => Ok(SelfTradePrevention::CancelNewest),
+        "cancel_oldest" => Ok(SelfTradePrevention::CancelOldest),
+        "cancel_both" => Ok(SelfTradePrevention::CancelBoth),
+        "decrement_and_cancel" => Ok(SelfTradePrevention::DecrementAndCancel),
+        _ => Err(anyhow::anyhow!("Invalid self-trade prevention mode: {}", mode)),
+    }
+}
+
+/// Cancels an order and confirms it to the sender, unless the confirmation already reaches them
+/// through the owner's private stream.
 async fn handle_cancel_order(
     data: CancelOrderData,
     ws_sender: &mut futures_util::stream::SplitSink<
@@ -278,19 +1363,22 @@ async fn handle_cancel_order(
         Message,
     >,
     engine: &Arc<OrderEngine>,
+    state: &ConnectionState,
 ) -> Result<()> {
     let order_id = Uuid::from_str(&data.order_id)?;
 
-    match engine.cancel_order(&data.pair, order_id) {
-        Ok(Some(_cancelled_order)) => {
-            let cancel_data = OrderCancelledData {
-                order_id: data.order_id,
-                reason: "User requested".to_string(),
-            };
+    match engine.cancel_order(order_id).await {
+        Ok(Some(cancelled_order)) => {
+            if !state.follows_user(cancelled_order.user_id) {
+                let cancel_data = OrderCancelledData {
+                    order_id: data.order_id,
+                    reason: "User requested".to_string(),
+                };
 
-            let msg = OutgoingMessage::OrderCancelled { data: cancel_data };
-            let json = serde_json::to_string(&msg)?;
-            ws_sender.send(Message::Text(json)).await?;
+                let msg = OutgoingMessage::OrderCancelled { data: cancel_data };
+                let json = serde_json::to_string(&msg)?;
+                ws_sender.send(Message::Text(json)).await?;
+            }
 
             info!("Order {} cancelled successfully", order_id);
         }
@@ -313,6 +1401,195 @@ async fn handle_cancel_order(
     Ok(())
 }
 
+async fn handle_amend_order(
+    data: AmendOrderData,
+    ws_sender: &mut futures_util::stream::SplitSink<
+        tokio_tungstenite::WebSocketStream<TcpStream>,
+        Message,
+    >,
+    engine: &Arc<OrderEngine>,
+    state: &ConnectionState,
+) -> Result<()> {
+    let order_id = Uuid::from_str(&data.order_id)?;
+    let protocol = state.protocol;
+
+    // Precision depends on the pair, which only the order itself knows
+    let spec = engine.order_pair(order_id).and_then(|pair| engine.get_market(&pair));
+    let Some(spec) = spec else {
+        let msg = OutgoingMessage::AmendRejected {
+            data: AmendRejectedData {
+                order_id: data.order_id,
+                reason: EngineError::OrderNotFound(order_id).to_string(),
+            },
+        };
+        let json = serde_json::to_string(&msg)?;
+        ws_sender.send(Message::Text(json)).await?;
+        return Ok(());
+    };
+
+    let amount = data.amount
+        .map(|a| protocol.parse(&a, "amount", spec.quantity_precision()))
+        .transpose()?;
+
+    let price = data.price
+        .map(|p| protocol.parse(&p, "price", spec.price_precision))
+        .transpose()?;
+
+    match engine.amend_order(order_id, amount, price).await {
+        Ok(amend) => {
+            let amended_order = amend.result.order.clone();
+
+            // A connection following the user hears of the amend on the private stream
+            if !state.follows_user(amended_order.user_id) {
+                let msg = order_amended_message(&amended_order, amend.kept_priority, protocol);
+                let json = serde_json::to_string(&msg)?;
+                ws_sender.send(Message::Text(json)).await?;
+            }
+
+            // An amend that crossed the book trades like a new order
+            let response = EngineResponse::from(amend.result);
+            send_execution_reports(&amended_order, &response, ws_sender, state).await?;
+
+            info!("Order {} amended successfully", order_id);
+        }
+        Err(e) => {
+            let rejected_data = AmendRejectedData {
+                order_id: data.order_id,
+                reason: e.to_string(),
+            };
+
+            let msg = OutgoingMessage::AmendRejected { data: rejected_data };
+            let json = serde_json::to_string(&msg)?;
+            ws_sender.send(Message::Text(json)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies a deposit or withdrawal and answers with the user's balances. Funds only move on the
+/// word of the custody backend, so anything but a service connection is refused.
+async fn handle_transfer(
+    data: TransferData,
+    transfer: fn(&OrderEngine, Uuid, &str, Decimal) -> Result<Balance, EngineError>,
+    ws_sender: &mut futures_util::stream::SplitSink<
+        tokio_tungstenite::WebSocketStream<TcpStream>,
+        Message,
+    >,
+    engine: &Arc<OrderEngine>,
+    state: &ConnectionState,
+) -> Result<()> {
+    if state.identity != Some(Identity::Service) {
+        return Err(anyhow::anyhow!("Only the custody service may move funds"));
+    }
+
+    let protocol = state.protocol;
+    let user_id = Uuid::from_str(&data.user_id)?;
+    let amount = protocol.parse(&data.amount, "amount", Decimal::MAX_SCALE)?;
+
+    let msg = match transfer(engine, user_id, &data.asset, amount) {
+        Ok(_) => balances_message(engine, user_id, protocol),
+        Err(e) => OutgoingMessage::Error {
+            message: format!("Failed to transfer {}: {}", data.asset, e),
+        },
+    };
+
+    let json = serde_json::to_string(&msg)?;
+    ws_sender.send(Message::Text(json)).await?;
+
+    Ok(())
+}
+
+fn balances_message(engine: &OrderEngine, user_id: Uuid, protocol: Protocol) -> OutgoingMessage {
+    let mut balances: Vec<BalanceData> = engine
+        .get_balances(user_id)
+        .into_iter()
+        .map(|(asset, balance)| BalanceData {
+            asset,
+            available: protocol.number(balance.available),
+            locked: protocol.number(balance.locked),
+        })
+        .collect();
+    balances.sort_by(|a, b| a.asset.cmp(&b.asset));
+
+    OutgoingMessage::Balances {
+        data: BalancesData {
+            user_id: user_id.to_string(),
+            balances,
+        },
+    }
+}
+
+/// The user's fee tier and volume, with the rates the tier pays on every pair.
+fn fee_tier_message(engine: &OrderEngine, user_id: Uuid, protocol: Protocol) -> OutgoingMessage {
+    let now = Utc::now();
+    let tier = engine.get_fee_tier(user_id);
+    let mut rates: Vec<FeeRateData> = engine
+        .get_pairs()
+        .into_iter()
+        .filter_map(|pair| engine.get_market(&pair))
+        .map(|spec| {
+            let rates = spec.fee_schedule().rates(tier);
+            FeeRateData {
+                pair: spec.pair,
+                maker: protocol.number(rates.maker),
+                taker: protocol.number(rates.taker),
+            }
+        })
+        .collect();
+    rates.sort_by(|a, b| a.pair.cmp(&b.pair));
+
+    OutgoingMessage::FeeTier {
+        data: FeeTierData {
+            user_id: user_id.to_string(),
+            tier,
+            name: FeeTierSchedule::tier_name(tier),
+            volume: protocol.number(engine.get_rolling_volume(user_id, now)),
+            next_recalculation: engine.fee_tier_schedule().next_recalculation(now).timestamp_millis(),
+            rates,
+        },
+    }
+}
+
+async fn handle_get_order(
+    data: GetOrderData,
+    ws_sender: &mut futures_util::stream::SplitSink<
+        tokio_tungstenite::WebSocketStream<TcpStream>,
+        Message,
+    >,
+    engine: &Arc<OrderEngine>,
+    protocol: Protocol,
+) -> Result<()> {
+    let order_id = Uuid::from_str(&data.order_id)?;
+
+    let msg = match engine.get_order(order_id).await {
+        Ok(Some(order)) => OutgoingMessage::OrderStatus {
+            data: OrderStatusData {
+                order_id: data.order_id,
+                pair: order.pair.clone(),
+                order_type: order.order_type.clone(),
+                order_kind: order.order_kind.clone(),
+                status: order.status.clone(),
+                amount: protocol.number(order.amount),
+                price: order.price.map(|p| protocol.number(p)),
+                filled: protocol.number(order.filled),
+                remaining_amount: protocol.number(order.remaining_amount()),
+            },
+        },
+        Ok(None) => OutgoingMessage::Error {
+            message: "Order not found".to_string(),
+        },
+        Err(e) => OutgoingMessage::Error {
+            message: format!("Failed to get order: {}", e),
+        },
+    };
+
+    let json = serde_json::to_string(&msg)?;
+    ws_sender.send(Message::Text(json)).await?;
+
+    Ok(())
+}
+
 async fn handle_get_orderbook(
     data: OrderBookRequest,
     ws_sender: &mut futures_util::stream::SplitSink<
@@ -320,19 +1597,21 @@ async fn handle_get_orderbook(
         Message,
     >,
     engine: &Arc<OrderEngine>,
+    protocol: Protocol,
 ) -> Result<()> {
-    if let Some(snapshot) = engine.get_orderbook_snapshot(&data.pair) {
+    if let Some(snapshot) = engine.get_orderbook_snapshot(&data.pair).await? {
         let orderbook_data = OrderBookSnapshotData {
             pair: snapshot.pair,
             bids: snapshot.bids.iter()
-                .map(|(price, amount)| (price.to_f64().unwrap_or(0.0), amount.to_f64().unwrap_or(0.0)))
+                .map(|(price, amount)| (protocol.number(*price), protocol.number(*amount)))
                 .collect(),
             asks: snapshot.asks.iter()
-                .map(|(price, amount)| (price.to_f64().unwrap_or(0.0), amount.to_f64().unwrap_or(0.0)))
+                .map(|(price, amount)| (protocol.number(*price), protocol.number(*amount)))
                 .collect(),
-            best_bid: snapshot.best_bid.and_then(|p| p.to_f64()),
-            best_ask: snapshot.best_ask.and_then(|p| p.to_f64()),
-            spread: snapshot.spread.and_then(|s| s.to_f64()),
+            best_bid: snapshot.best_bid.map(|p| protocol.number(p)),
+            best_ask: snapshot.best_ask.map(|p| protocol.number(p)),
+            spread: snapshot.spread.map(|s| protocol.number(s)),
+            seq: snapshot.seq,
         };
 
         let msg = OutgoingMessage::OrderBookSnapshot { data: orderbook_data };
@@ -348,3 +1627,265 @@ async fn handle_get_orderbook(
 
     Ok(())
 }
+
+async fn handle_get_l3_snapshot(
+    data: OrderBookRequest,
+    ws_sender: &mut futures_util::stream::SplitSink<
+        tokio_tungstenite::WebSocketStream<TcpStream>,
+        Message,
+    >,
+    engine: &Arc<OrderEngine>,
+    protocol: Protocol,
+) -> Result<()> {
+    let msg = match engine.get_l3_snapshot(&data.pair).await? {
+        Some(snapshot) => {
+            let levels = |levels: &[L3Level]| {
+                levels
+                    .iter()
+                    .map(|level| L3LevelData {
+                        price: protocol.number(level.price),
+                        orders: level.orders.iter().map(|(handle, size)| (*handle, protocol.number(*size))).collect(),
+                    })
+                    .collect()
+            };
+
+            OutgoingMessage::L3Snapshot {
+                data: L3SnapshotData {
+                    pair: snapshot.pair.clone(),
+                    bids: levels(&snapshot.bids),
+                    asks: levels(&snapshot.asks),
+                    seq: snapshot.seq,
+                },
+            }
+        }
+        None => OutgoingMessage::Error {
+            message: format!("Order book not found for pair: {}", data.pair),
+        },
+    };
+
+    let json = serde_json::to_string(&msg)?;
+    ws_sender.send(Message::Text(json)).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use tokio::sync::broadcast;
+
+    const PAIR: &str = "BTC/USDT";
+
+    fn limit(user_id: Uuid, order_type: OrderType, price: i64) -> Order {
+        Order::new(user_id, PAIR.to_string(), order_type, OrderKind::Limit, Decimal::ONE, Some(price.into()))
+    }
+
+    /// An engine with one funded buyer and seller, and a receiver for everything it publishes.
+    fn funded_engine() -> (OrderEngine, broadcast::Receiver<EngineEvent>, Uuid, Uuid) {
+        let engine = OrderEngine::new(1);
+        let events = engine.subscribe();
+        let (buyer, seller) = (Uuid::new_v4(), Uuid::new_v4());
+        engine.deposit(buyer, "USDT", Decimal::from(10_000)).unwrap();
+        engine.deposit(seller, "BTC", Decimal::from(10)).unwrap();
+        (engine, events, buyer, seller)
+    }
+
+    /// Every event published so far on `events`, oldest first.
+    fn drain(events: &mut broadcast::Receiver<EngineEvent>) -> Vec<EngineEvent> {
+        std::iter::from_fn(|| events.try_recv().ok()).collect()
+    }
+
+    /// What `state` sends for `events`, depth updates last, as JSON.
+    fn route(state: &mut ConnectionState, events: &[EngineEvent]) -> Vec<Value> {
+        let mut messages: Vec<OutgoingMessage> = events.iter().flat_map(|event| state.route_event(event)).collect();
+        messages.extend(state.take_depth_updates());
+        messages.iter().map(|msg| serde_json::to_value(msg).unwrap()).collect()
+    }
+
+    fn types(messages: &[Value]) -> Vec<&str> {
+        messages.iter().map(|msg| msg["type"].as_str().unwrap()).collect()
+    }
+
+    fn subscribed(channels: impl IntoIterator<Item = Channel>) -> ConnectionState {
+        ConnectionState {
+            protocol: Protocol::Decimal,
+            channels: channels.into_iter().collect(),
+            ..ConnectionState::default()
+        }
+    }
+
+    #[test]
+    fn channels_parse_from_their_wire_names() {
+        let user = Uuid::new_v4();
+        assert_eq!(Channel::from_str("depth:BTC/USDT").unwrap(), Channel::Depth(PAIR.to_string()));
+        assert_eq!(Channel::from_str("trades:BTC/USDT").unwrap(), Channel::Trades(PAIR.to_string()));
+        assert_eq!(Channel::from_str("ticker:BTC/USDT").unwrap(), Channel::Ticker(PAIR.to_string()));
+        assert_eq!(
+            Channel::from_str("candles:BTC/USDT:5m").unwrap(),
+            Channel::Candles(PAIR.to_string(), CandleInterval::FiveMinutes)
+        );
+        assert_eq!(Channel::from_str(&format!("user:{}", user)).unwrap(), Channel::User(user));
+        assert_eq!(Channel::Depth(PAIR.to_string()).pair(), Some(PAIR));
+        assert_eq!(Channel::User(user).pair(), None);
+
+        for invalid in ["depth", "orders:BTC/USDT", "candles:BTC/USDT", "candles:BTC/USDT:2m", "user:nobody"] {
+            assert!(Channel::from_str(invalid).is_err(), "{} should not parse", invalid);
+        }
+    }
+
+    #[test]
+    fn private_streams_are_only_open_to_their_user_and_services() {
+        let user = Uuid::new_v4();
+        let mut state = ConnectionState::default();
+        assert!(state.may_subscribe(&Channel::Trades(PAIR.to_string())));
+        assert!(!state.may_subscribe(&Channel::User(user)));
+
+        state.identity = Some(Identity::User(Uuid::new_v4()));
+        assert!(!state.may_subscribe(&Channel::User(user)));
+        state.identity = Some(Identity::User(user));
+        assert!(state.may_subscribe(&Channel::User(user)));
+        state.identity = Some(Identity::Service);
+        assert!(state.may_subscribe(&Channel::User(user)));
+    }
+
+    #[tokio::test]
+    async fn market_data_reaches_only_subscribed_connections() {
+        let (engine, mut events, buyer, seller) = funded_engine();
+        engine.add_order(limit(seller, OrderType::Sell, 100)).await.unwrap();
+        engine.add_order(limit(buyer, OrderType::Buy, 100)).await.unwrap();
+        let published = drain(&mut events);
+        let messages_for = |state: &mut ConnectionState| route(state, &published);
+
+        let trades = messages_for(&mut subscribed([Channel::Trades(PAIR.to_string())]));
+        assert_eq!(types(&trades), ["trade"]);
+        assert_eq!((trades[0]["data"]["price"].as_str(), trades[0]["data"]["amount"].as_str()), (Some("100"), Some("1")));
+
+        let tickers = messages_for(&mut subscribed([Channel::Ticker(PAIR.to_string())]));
+        assert!(!tickers.is_empty());
+        assert!(types(&tickers).iter().all(|kind| *kind == "ticker"));
+        assert_eq!(tickers.last().unwrap()["data"]["lastPrice"].as_str(), Some("100"));
+
+        // Another pair's channels and an anonymous connection hear nothing
+        assert!(messages_for(&mut subscribed([Channel::Trades("ETH/USDT".to_string())])).is_empty());
+        assert!(messages_for(&mut ConnectionState::default()).is_empty());
+
+        // Unsubscribing stops the stream
+        let mut state = subscribed([Channel::Trades(PAIR.to_string())]);
+        state.channels.remove(&Channel::Trades(PAIR.to_string()));
+        engine.add_order(limit(seller, OrderType::Sell, 100)).await.unwrap();
+        engine.add_order(limit(buyer, OrderType::Buy, 100)).await.unwrap();
+        assert!(route(&mut state, &drain(&mut events)).is_empty());
+    }
+
+    fn decimal(value: &Value) -> Decimal {
+        Decimal::from_str(value.as_str().unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn depth_updates_carry_on_from_the_snapshot_seq() {
+        let (engine, mut events, buyer, seller) = funded_engine();
+        let first = limit(buyer, OrderType::Buy, 99);
+        engine.add_order(first.clone()).await.unwrap();
+        engine.add_order(limit(seller, OrderType::Sell, 101)).await.unwrap();
+        let snapshot = engine.get_orderbook_snapshot(PAIR).await.unwrap().unwrap();
+        drain(&mut events);
+
+        engine.add_order(limit(buyer, OrderType::Buy, 99)).await.unwrap();
+        engine.add_order(limit(buyer, OrderType::Buy, 98)).await.unwrap();
+        engine.cancel_order(first.id).await.unwrap();
+        engine.add_order(limit(buyer, OrderType::Buy, 101)).await.unwrap();
+
+        // Everything since the snapshot arrives as one update covering the next sequence numbers
+        let mut state = subscribed([Channel::Depth(PAIR.to_string())]);
+        let updates = route(&mut state, &drain(&mut events));
+        assert_eq!(types(&updates), ["depth_update"]);
+        let update = &updates[0]["data"];
+        assert_eq!(update["firstSeq"].as_u64(), Some(snapshot.seq + 1));
+
+        // Applying it to the snapshot gives the book as it is now
+        let apply = |mut levels: BTreeMap<Decimal, Decimal>, changes: &Value| {
+            for change in changes.as_array().unwrap() {
+                let (price, amount) = (decimal(&change[0]), decimal(&change[1]));
+                if amount.is_zero() {
+                    levels.remove(&price);
+                } else {
+                    levels.insert(price, amount);
+                }
+            }
+            levels.into_iter().collect::<Vec<_>>()
+        };
+        let current = engine.get_orderbook_snapshot(PAIR).await.unwrap().unwrap();
+        assert_eq!(update["lastSeq"].as_u64(), Some(current.seq));
+        assert_eq!(apply(snapshot.bids.into_iter().collect(), &update["bids"]), current.bids);
+        assert_eq!(apply(snapshot.asks.into_iter().collect(), &update["asks"]), current.asks);
+        assert_eq!(current.bids, vec![(Decimal::from(98), Decimal::ONE), (Decimal::from(99), Decimal::ONE)]);
+        assert!(current.asks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn depth_updates_go_out_per_pair() {
+        let (engine, mut events, buyer, _) = funded_engine();
+        engine.add_order(limit(buyer, OrderType::Buy, 99)).await.unwrap();
+        let mut eth = limit(buyer, OrderType::Buy, 99);
+        eth.pair = "ETH/USDT".to_string();
+        engine.add_order(eth).await.unwrap();
+
+        let mut state = subscribed([Channel::Depth(PAIR.to_string()), Channel::Depth("ETH/USDT".to_string())]);
+        let mut pairs: Vec<String> =
+            route(&mut state, &drain(&mut events)).iter().map(|update| update["data"]["pair"].as_str().unwrap().to_string()).collect();
+        pairs.sort();
+        assert_eq!(pairs, ["BTC/USDT", "ETH/USDT"]);
+        assert!(state.take_depth_updates().is_empty());
+    }
+
+    #[tokio::test]
+    async fn l3_updates_rebuild_the_snapshot_queues() {
+        let (engine, mut events, buyer, seller) = funded_engine();
+        engine.add_order(limit(buyer, OrderType::Buy, 99)).await.unwrap();
+        engine.add_order(limit(seller, OrderType::Sell, 101)).await.unwrap();
+        let snapshot = engine.get_l3_snapshot(PAIR).await.unwrap().unwrap();
+        drain(&mut events);
+
+        let second = limit(buyer, OrderType::Buy, 99);
+        engine.add_order(second.clone()).await.unwrap();
+        engine.add_order(limit(buyer, OrderType::Buy, 99)).await.unwrap();
+        let mut partial = limit(seller, OrderType::Sell, 99);
+        partial.amount = Decimal::new(4, 1);
+        engine.add_order(partial).await.unwrap();
+        engine.cancel_order(second.id).await.unwrap();
+
+        let mut state = subscribed([Channel::L3(PAIR.to_string())]);
+        let updates = route(&mut state, &drain(&mut events));
+        assert!(types(&updates).iter().all(|kind| *kind == "l3_update"));
+        let seqs: Vec<u64> = updates.iter().map(|update| update["data"]["seq"].as_u64().unwrap()).collect();
+        assert_eq!(seqs, (snapshot.seq + 1..=snapshot.seq + seqs.len() as u64).collect::<Vec<_>>());
+
+        // Replaying the updates over the snapshot's queues gives the book's queues now
+        let queues = |levels: &[L3Level]| -> BTreeMap<Decimal, Vec<(u64, Decimal)>> {
+            levels.iter().map(|level| (level.price, level.orders.clone())).collect()
+        };
+        let mut bids = queues(&snapshot.bids);
+        let mut asks = queues(&snapshot.asks);
+        for update in &updates {
+            let data = &update["data"];
+            let side = if data["side"] == serde_json::to_value(OrderType::Buy).unwrap() { &mut bids } else { &mut asks };
+            let (handle, price, size) = (data["handle"].as_u64().unwrap(), decimal(&data["price"]), decimal(&data["size"]));
+            let queue = side.entry(price).or_default();
+            match data["kind"].as_str().unwrap() {
+                "added" => queue.push((handle, size)),
+                "reduced" => queue.iter_mut().find(|(h, _)| *h == handle).unwrap().1 = size,
+                "removed" => queue.retain(|(h, _)| *h != handle),
+                kind => panic!("unexpected change {}", kind),
+            }
+            side.retain(|_, queue| !queue.is_empty());
+        }
+
+        let current = engine.get_l3_snapshot(PAIR).await.unwrap().unwrap();
+        assert_eq!(current.seq, *seqs.last().unwrap());
+        assert_eq!(bids, queues(&current.bids));
+        assert_eq!(asks, queues(&current.asks));
+        let sizes: Vec<Decimal> = current.bids[0].orders.iter().map(|(_, size)| *size).collect();
+        assert_eq!(sizes, vec![Decimal::new(6, 1), Decimal::ONE]);
+    }
+}
@@ -0,0 +1,179 @@
+use anyhow::Result;
+use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
+use rust_decimal::Decimal;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+/// Version of the WebSocket protocol a connection speaks.
+///
+/// Connections start out on [`Protocol::Legacy`] so existing clients keep working, and switch by
+/// sending a `hello` message with the version they want.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// Version 1: amounts and prices are JSON numbers (f64).
+    #[default]
+    Legacy,
+    /// Version 2: amounts and prices are decimal strings, exact to the pair's precision.
+    Decimal,
+}
+
+impl Protocol {
+    pub const LATEST_VERSION: u32 = 2;
+
+    pub fn from_version(version: u32) -> Result<Self> {
+        match version {
+            1 => Ok(Protocol::Legacy),
+            2 => Ok(Protocol::Decimal),
+            _ => Err(anyhow::anyhow!(
+                "Unsupported protocol version: {} (supported: 1..={})",
+                version,
+                Self::LATEST_VERSION
+            )),
+        }
+    }
+
+    pub fn version(self) -> u32 {
+        match self {
+            Protocol::Legacy => 1,
+            Protocol::Decimal => 2,
+        }
+    }
+
+    /// `value` as this protocol puts it on the wire.
+    pub fn number(self, value: Decimal) -> WireDecimal {
+        match self {
+            Protocol::Legacy => WireDecimal::Float(value.to_f64().unwrap_or(0.0)),
+            Protocol::Decimal => WireDecimal::Exact(value),
+        }
+    }
+
+    /// Reads an incoming amount or price, rejecting more than `precision` decimal places.
+    ///
+    /// Version 2 only takes decimal strings. Legacy clients may still send numbers, which are left
+    /// to the market's tick and step checks. They go through `Decimal::from_f64`, which keeps the
+    /// shortest decimal that round-trips, so `0.1` arrives as 0.1. `Decimal::from_f64_retain`
+    /// would keep the full binary expansion and fail those checks.
+    pub fn parse(self, value: &WireDecimal, field: &str, precision: u32) -> Result<Decimal> {
+        match value {
+            WireDecimal::Exact(decimal) => {
+                if decimal.normalize().scale() > precision {
+                    return Err(anyhow::anyhow!(
+                        "Invalid {}: {} has more than {} decimal places",
+                        field,
+                        decimal,
+                        precision
+                    ));
+                }
+                Ok(*decimal)
+            }
+            WireDecimal::Float(float) => match self {
+                Protocol::Legacy => {
+                    Decimal::from_f64(*float).ok_or_else(|| anyhow::anyhow!("Invalid {}: {}", field, float))
+                }
+                Protocol::Decimal => Err(anyhow::anyhow!("Invalid {}: {} must be a decimal string", field, float)),
+            },
+        }
+    }
+}
+
+/// An amount or price on the wire: a decimal string, or a JSON number from a legacy client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WireDecimal {
+    Exact(Decimal),
+    Float(f64),
+}
+
+impl Serialize for WireDecimal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            WireDecimal::Exact(decimal) => serializer.serialize_str(&decimal.to_string()),
+            WireDecimal::Float(float) => serializer.serialize_f64(*float),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for WireDecimal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(WireDecimalVisitor)
+    }
+}
+
+struct WireDecimalVisitor;
+
+impl Visitor<'_> for WireDecimalVisitor {
+    type Value = WireDecimal;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a decimal string or a number")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        Decimal::from_str_exact(value)
+            .map(WireDecimal::Exact)
+            .map_err(|_| E::custom(format!("invalid decimal: {}", value)))
+    }
+
+    fn visit_f64<E: de::Error>(self, value: f64) -> Result<Self::Value, E> {
+        Ok(WireDecimal::Float(value))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Self::Value, E> {
+        Ok(WireDecimal::Float(value as f64))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Self::Value, E> {
+        Ok(WireDecimal::Float(value as f64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn wire(json: &str) -> WireDecimal {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn decimal_strings_parse_exactly() {
+        let value = Protocol::Decimal.parse(&wire("\"0.1\""), "amount", 8).unwrap();
+        assert_eq!(value, Decimal::from_str("0.1").unwrap());
+        assert_eq!(value.to_string(), "0.1");
+
+        // Trailing zeros do not count against the precision
+        assert_eq!(Protocol::Decimal.parse(&wire("\"1.2300\""), "price", 2).unwrap(), Decimal::new(123, 2));
+        assert!(Protocol::Decimal.parse(&wire("\"1.234\""), "price", 2).is_err());
+        assert!(serde_json::from_str::<WireDecimal>("\"1.2.3\"").is_err());
+        assert!(serde_json::from_str::<WireDecimal>("\"1e3\"").is_err());
+    }
+
+    #[test]
+    fn numbers_are_only_taken_from_legacy_clients() {
+        assert!(Protocol::Decimal.parse(&wire("0.1"), "amount", 8).is_err());
+        assert!(Protocol::Decimal.parse(&wire("1"), "amount", 8).is_err());
+
+        // The shortest decimal that round-trips, not the float's binary expansion
+        assert_eq!(Protocol::Legacy.parse(&wire("0.1"), "amount", 8).unwrap().to_string(), "0.1");
+        assert_eq!(Protocol::Legacy.parse(&wire("5"), "amount", 8).unwrap(), Decimal::from(5));
+        assert_eq!(Protocol::Legacy.parse(&wire("\"0.1\""), "amount", 8).unwrap(), Decimal::new(1, 1));
+    }
+
+    #[test]
+    fn outgoing_numbers_follow_the_protocol() {
+        let value = Decimal::from_str("12345678901.123456789").unwrap();
+        assert_eq!(serde_json::to_string(&Protocol::Decimal.number(value)).unwrap(), "\"12345678901.123456789\"");
+        assert_eq!(serde_json::to_string(&Protocol::Legacy.number(Decimal::new(1, 1))).unwrap(), "0.1");
+    }
+
+    #[test]
+    fn versions_map_to_protocols() {
+        assert_eq!(Protocol::default(), Protocol::Legacy);
+        assert_eq!(Protocol::from_version(1).unwrap(), Protocol::Legacy);
+        assert_eq!(Protocol::from_version(Protocol::LATEST_VERSION).unwrap(), Protocol::Decimal);
+        assert_eq!(Protocol::Decimal.version(), Protocol::LATEST_VERSION);
+        assert!(Protocol::from_version(0).is_err());
+        assert!(Protocol::from_version(3).is_err());
+    }
+}